@@ -13,12 +13,13 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with Willow.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::num::NonZeroU32;
+use std::{cell::RefCell, num::NonZeroU32, rc::Rc};
 
 use raqote::DrawTarget;
 use willow_react::{Element, ElementComponent, Hooks};
 use willow_server::{
     glam::{vec2, Vec2},
+    willow_text::FontRegistry,
     Operation,
 };
 use winit::{
@@ -58,6 +59,29 @@ impl ElementComponent for ScalingElement {
     }
 }
 
+/// Loads the built-in fonts, then appends the fonts in the directories listed
+/// in the `WILLOW_FONT_PATH` environment variable to the fallback chain.
+pub fn load_fonts() -> FontRegistry {
+    let mut fonts = FontRegistry::default();
+
+    let Some(paths) = std::env::var_os("WILLOW_FONT_PATH") else {
+        return fonts;
+    };
+
+    for path in std::env::split_paths(&paths) {
+        let first_new = fonts.len();
+        for (path, err) in fonts.load_dir(&path) {
+            log::warn!("failed to load font {}: {}", path.display(), err);
+        }
+
+        for face in first_new..fonts.len() {
+            fonts.add_fallback(face);
+        }
+    }
+
+    fonts
+}
+
 pub fn run_app<T: App>(mut app: T) -> ! {
     let event_loop = EventLoopBuilder::<T::Event>::with_user_event().build();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
@@ -67,7 +91,9 @@ pub fn run_app<T: App>(mut app: T) -> ! {
     let proxy = event_loop.create_proxy();
     app.with_proxy(proxy);
 
-    let mut state = willow_react::State::new();
+    let fonts = Rc::new(RefCell::new(load_fonts()));
+    let mut state = willow_react::State::with_fonts(fonts.clone());
+    let mut atlas = willow_raqote::atlas::GlyphAtlas::new();
 
     event_loop.run(move |event, _, control_flow| match event {
         Event::RedrawRequested(_) => {
@@ -97,7 +123,12 @@ pub fn run_app<T: App>(mut app: T) -> ! {
             let mut buffer = surface.buffer_mut().unwrap();
             buffer.fill(0xff000000);
             let mut dt = DrawTarget::from_backing(width as i32, height as i32, buffer.as_mut());
-            let mut ren = willow_raqote::RaqoteRenderer::new(&mut dt);
+            let mut fonts = fonts.borrow_mut();
+            let resources = state.tree.resources().clone();
+            let resources = resources.borrow();
+            let mut ren = willow_raqote::RaqoteRenderer::new(&mut dt, &mut fonts)
+                .with_atlas(&mut atlas)
+                .with_resources(&resources);
             state.tree.walk(&mut ren, &aabb);
 
             buffer.present().unwrap();
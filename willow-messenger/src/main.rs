@@ -84,8 +84,8 @@ impl willow_desktop::App for MessengerApp {
     }
 
     fn on_window_event(&mut self, event: WindowEvent) {
-        match event {
-            WindowEvent::ReceivedCharacter(char) => match char {
+        if let WindowEvent::ReceivedCharacter(char) = event {
+            match char {
                 '\r' => {
                     let message = format!("BODY {}\n", self.input);
                     let mut stream = self.stream.as_ref();
@@ -106,8 +106,7 @@ impl willow_desktop::App for MessengerApp {
                 char => {
                     self.input.push(char);
                 }
-            },
-            _ => {}
+            }
         }
     }
 }
@@ -15,9 +15,12 @@
 
 use chrono::{DateTime, Utc};
 use glam::{vec2, Vec2, Vec4};
-use willow_react::{stroke_color, Element, ElementComponent, Hooks};
+use willow_react::{stroke_color, Element, ElementComponent, Hooks, Theme};
 use willow_server::*;
 
+/// The size of the text in the messenger UI.
+pub const FONT_SIZE: f32 = 10.0;
+
 #[derive(Debug, Clone)]
 pub struct MessageContent {
     pub text: String,
@@ -34,6 +37,21 @@ impl ElementComponent for Message {
     fn render(&mut self, hooks: &mut Hooks) -> Element {
         let theme = hooks.use_theme();
 
+        let timestamp = self.content.timestamp.format("%d/%m/%Y %H:%M");
+        let sender_font = Font::default().with_weight(FontWeight::BOLD);
+        let timestamp_font =
+            Font::default().with_feature(FontFeature::on(FontFeature::TABULAR_NUMBERS));
+        let line = hooks.measure_text(&Font::default(), "", FONT_SIZE);
+
+        let mut spans = vec![
+            TextSpan::new(timestamp.to_string(), timestamp_font, FONT_SIZE).with_color(theme.muted),
+            TextSpan::new(Message::SEPARATOR, Font::default(), FONT_SIZE),
+            TextSpan::new(self.content.sender.clone(), sender_font, FONT_SIZE),
+            TextSpan::new(Message::SEPARATOR, Font::default(), FONT_SIZE),
+        ];
+
+        spans.extend(body_spans(&self.content.text, &theme));
+
         vec![
             Element::operation(
                 stroke_color(theme.surface),
@@ -45,49 +63,73 @@ impl ElementComponent for Message {
             ),
             Element::operation(
                 Operation::Translate {
-                    offset: vec2(5.0, 15.0),
+                    offset: vec2(Message::PADDING, Message::PADDING + line.ascent),
                 },
-                vec![
-                    Element::operation(
-                        stroke_color(theme.muted),
-                        Shape::Text {
-                            content: self.content.timestamp.format("%d/%m/%Y %H:%M").to_string(),
-                            font: "unused".to_string(),
-                        },
-                    ),
-                    Element::operation(
-                        Operation::Translate {
-                            offset: vec2(100.0, 0.0),
-                        },
-                        vec![
-                            Element::operation(
-                                stroke_color(theme.text),
-                                Shape::Text {
-                                    content: self.content.sender.clone(),
-                                    font: "unused".to_string(),
-                                },
-                            ),
-                            Element::operation(
-                                Operation::Translate {
-                                    offset: vec2(100.0, 0.0),
-                                },
-                                Element::operation(
-                                    stroke_color(theme.text),
-                                    Shape::Text {
-                                        content: self.content.text.clone(),
-                                        font: "unused".to_string(),
-                                    },
-                                ),
-                            ),
-                        ],
-                    ),
-                ],
+                Element::operation(
+                    stroke_color(theme.text),
+                    Shape::RichText {
+                        spans,
+                        paragraph: None,
+                    },
+                ),
             ),
         ]
         .into()
     }
 }
 
+impl Message {
+    /// The space between the edges of a message and its text.
+    pub const PADDING: f32 = 5.0;
+
+    /// The text between the timestamp, sender, and body of a message: an em
+    /// space, which is as wide as the font size.
+    pub const SEPARATOR: &'static str = "\u{2003}";
+}
+
+/// Styles the body of a message, drawing `inline code` on a background and
+/// highlighting @mentions.
+fn body_spans(text: &str, theme: &Theme) -> Vec<TextSpan> {
+    let mut spans = Vec::new();
+
+    for (index, part) in text.split('`').enumerate() {
+        // odd parts are between a pair of backticks
+        if index % 2 == 1 {
+            // drawn in the default family unless a monospace font is loaded
+            let font = Font {
+                family: "Noto Sans Mono".to_string(),
+                ..Font::default()
+            };
+
+            spans.push(TextSpan::new(part, font, FONT_SIZE).with_background(theme.overlay));
+            continue;
+        }
+
+        let mut plain = String::new();
+        for word in part.split_inclusive(' ') {
+            if word.starts_with('@') && word.trim_end().len() > 1 {
+                let mention = word.trim_end();
+                spans.push(TextSpan::new(
+                    std::mem::take(&mut plain),
+                    Font::default(),
+                    FONT_SIZE,
+                ));
+                spans.push(
+                    TextSpan::new(mention, Font::default(), FONT_SIZE).with_color(theme.accent),
+                );
+                plain.push_str(&word[mention.len()..]);
+            } else {
+                plain.push_str(word);
+            }
+        }
+
+        spans.push(TextSpan::new(plain, Font::default(), FONT_SIZE));
+    }
+
+    spans.retain(|span| !span.content.is_empty());
+    spans
+}
+
 pub struct Chat {
     pub messages: Vec<MessageContent>,
     pub width: f32,
@@ -100,8 +142,9 @@ impl ElementComponent for Chat {
         let message_width = self.width - outer_padding.x * 2.0;
         let mut messages = Vec::with_capacity(self.messages.len());
         let mut used_height = inner_padding;
+        let line = hooks.measure_text(&Font::default(), "", FONT_SIZE);
+        let message_height = line.line_height + Message::PADDING * 2.0;
         for content in self.messages.iter().cloned() {
-            let message_height = 25.0;
             let size = Vec2::new(message_width, message_height);
 
             messages.push(Element::operation(
@@ -146,18 +189,21 @@ impl ElementComponent for TextPrompt {
                 },
             ),
             Element::operation(
-                stroke_color(theme.accent),
+                stroke_color(theme.overlay),
                 Shape::Rectangle {
                     min: padding,
                     max: size - padding,
                 },
             ),
             Element::operation(
-                stroke_color(theme.overlay),
-                Shape::Rectangle {
-                    min: padding + border,
-                    max: size - padding - border,
-                },
+                stroke_color(theme.accent),
+                Element::operation(
+                    Operation::Outline(OutlineStyle::new(border)),
+                    Shape::Rectangle {
+                        min: padding + border / 2.0,
+                        max: size - padding - border / 2.0,
+                    },
+                ),
             ),
             Element::operation(
                 Operation::Translate {
@@ -168,7 +214,8 @@ impl ElementComponent for TextPrompt {
                     stroke_color(theme.text),
                     Shape::Text {
                         content: self.content.clone(),
-                        font: "unused".to_string(),
+                        font: Font::default(),
+                        size: FONT_SIZE,
                     },
                 ),
             ),
@@ -190,24 +237,32 @@ pub struct MessengerApp {
 impl ElementComponent for MessengerApp {
     fn render(&mut self, hooks: &mut Hooks) -> Element {
         let theme = hooks.use_theme();
+        let chat_height = self.size.y - TextPrompt::HEIGHT;
 
         vec![
             Element::operation(
                 stroke_color(theme.base),
                 Shape::Rectangle {
                     min: Vec2::ZERO,
-                    max: Vec2::new(self.size.x, self.size.y - TextPrompt::HEIGHT),
+                    max: Vec2::new(self.size.x, chat_height),
                 },
             ),
             Element::operation(
                 Operation::Translate {
-                    offset: Vec2::new(0.0, self.size.y - TextPrompt::HEIGHT),
+                    offset: Vec2::new(0.0, chat_height),
                 },
                 vec![
-                    Element::from(Chat {
-                        messages: self.messages.clone(),
-                        width: self.size.x,
-                    }),
+                    // the chat scrolls up from the top of the prompt
+                    Element::operation(
+                        Operation::Clip(Clip::Rectangle {
+                            min: Vec2::new(0.0, -chat_height),
+                            max: Vec2::new(self.size.x, 0.0),
+                        }),
+                        Chat {
+                            messages: self.messages.clone(),
+                            width: self.size.x,
+                        },
+                    ),
                     Element::from(TextPrompt {
                         content: self.input.clone(),
                         width: self.size.x,
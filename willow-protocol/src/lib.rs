@@ -13,7 +13,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with Willow.  If not, see <https://www.gnu.org/licenses/>.
 
-use glam::{Vec2, Vec3A, Vec4};
+use glam::{Affine2, Mat2, Vec2, Vec3A, Vec4};
 use serde::{Deserialize, Serialize};
 
 pub use glam;
@@ -108,6 +108,109 @@ pub struct NodeUpdateResponse {
     pub new_nodes: Vec<u32>,
 }
 
+/// A message sent to the Willow server to upload a resource that shapes can
+/// refer to by its [ResourceHash], like the picture drawn by a
+/// [Shape::Image]. The server responds with a [ResourceUploadResponse].
+///
+/// Resources are shared by every tree, and each is stored once no matter how
+/// many times it is uploaded. The server frees a resource once no nodes refer
+/// to it anymore, so resources should be uploaded shortly before the nodes
+/// that use them.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResourceUpload {
+    /// The content of the resource.
+    pub data: ResourceData,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResourceUploadResponse {
+    /// The hash of the uploaded resource.
+    pub hash: ResourceHash,
+}
+
+/// A message sent to the Willow server to find which resources it already
+/// has, so that only the rest need to be uploaded. The server responds with
+/// a [ResourceQueryResponse].
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResourceQuery {
+    pub hashes: Vec<ResourceHash>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResourceQueryResponse {
+    /// The queried hashes of the resources that the server doesn't have, in
+    /// the order they were queried.
+    pub missing: Vec<ResourceHash>,
+}
+
+/// The SHA-256 hash of a resource's content, which shapes refer to it by.
+///
+/// Nodes can refer to a resource before it is uploaded. Until then, images
+/// draw nothing, and text is drawn with the font's family instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+pub struct ResourceHash(pub [u8; 32]);
+
+/// The content of a [ResourceUpload].
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ResourceData {
+    /// An uncompressed image with rows of sRGB pixels from top to bottom.
+    ///
+    /// Each pixel is four bytes of red, green, blue, and alpha, and the color
+    /// components are not premultiplied by alpha.
+    Rgba {
+        width: u32,
+        height: u32,
+        pixels: Vec<u8>,
+    },
+
+    /// An image encoded as a PNG file.
+    Png(Vec<u8>),
+
+    /// An image encoded as a JPEG file.
+    Jpeg(Vec<u8>),
+
+    /// A TrueType or OpenType font file, used by text whose [Font] refers to
+    /// it with [Font::resource].
+    Font(Vec<u8>),
+}
+
+impl ResourceData {
+    /// Computes the hash that this resource is referred to by.
+    pub fn hash(&self) -> ResourceHash {
+        use sha2::{Digest, Sha256};
+
+        // the kind of resource is hashed too, so that identical bytes in
+        // different formats have different hashes
+        let mut hasher = Sha256::new();
+        match self {
+            ResourceData::Rgba {
+                width,
+                height,
+                pixels,
+            } => {
+                hasher.update(b"rgba");
+                hasher.update(width.to_le_bytes());
+                hasher.update(height.to_le_bytes());
+                hasher.update(pixels);
+            }
+            ResourceData::Png(data) => {
+                hasher.update(b"png");
+                hasher.update(data);
+            }
+            ResourceData::Jpeg(data) => {
+                hasher.update(b"jpeg");
+                hasher.update(data);
+            }
+            ResourceData::Font(data) => {
+                hasher.update(b"font");
+                hasher.update(data);
+            }
+        }
+
+        ResourceHash(hasher.finalize().into())
+    }
+}
+
 /// The initial contents of a new node in the tree.
 #[derive(Debug, Deserialize, Serialize)]
 pub enum NewNode {
@@ -133,6 +236,38 @@ pub enum Shape {
     /// A circle with a given radius.
     Circle { radius: f32 },
 
+    /// An ellipse centered on the origin, with its radii along the X and Y
+    /// axes.
+    Ellipse { radii: Vec2 },
+
+    /// A part of a circle centered on the origin, between two angles and from
+    /// an inner radius out to an outer one.
+    ///
+    /// With an inner radius of zero, this is a pie slice. Otherwise, it is a
+    /// segment of a ring, like a progress indicator. Angles are in radians,
+    /// with zero pointing along the X axis, and a positive sweep goes
+    /// clockwise.
+    Arc {
+        radius: f32,
+        inner_radius: f32,
+        start_angle: f32,
+        sweep_angle: f32,
+    },
+
+    /// A closed shape with straight edges between a list of points.
+    Polygon {
+        points: Vec<Vec2>,
+
+        /// How overlapping parts of the polygon are filled.
+        fill_rule: FillRule,
+    },
+
+    /// A series of straight lines between a list of points.
+    ///
+    /// Unlike a [Shape::Polygon], a polyline's outline doesn't go back from
+    /// its last point to its first, but its inside is filled the same way.
+    Polyline { points: Vec<Vec2> },
+
     /// A rectangle with minimum and maximum bounds.
     Rectangle { min: Vec2, max: Vec2 },
 
@@ -146,18 +281,525 @@ pub enum Shape {
         /// The content of the text.
         content: String,
 
-        /// Application-specific font information.
-        font: String,
+        /// The font to draw the text with.
+        font: Font,
+
+        /// The size of the font's em square.
+        size: f32,
+    },
+
+    /// A block of text that is broken into lines to fit a maximum width.
+    ///
+    /// Unlike [Shape::Text], which is drawn from the start of its baseline,
+    /// a paragraph is drawn downwards from the top-left corner of its box,
+    /// or from the top-right corner in [WritingMode::Vertical].
+    Paragraph {
+        /// The content of the text. Line breaks start new lines.
+        content: String,
+
+        /// The font to draw the text with.
+        font: Font,
+
+        /// The size of the font's em square.
+        size: f32,
+
+        /// How the text is laid out into lines.
+        style: ParagraphStyle,
+    },
+
+    /// A string of text made of spans with their own styles.
+    ///
+    /// The spans are shaped together, so kerning, ligatures, and line breaking
+    /// all work across span boundaries.
+    RichText {
+        /// The spans of text in logical order.
+        spans: Vec<TextSpan>,
+
+        /// How the text is laid out into lines. If this is `None`, the text
+        /// is drawn as a single line from the start of its baseline, like a
+        /// [Shape::Text]. Otherwise, it is drawn like a [Shape::Paragraph].
+        paragraph: Option<ParagraphStyle>,
+    },
+
+    /// A filled vector path, like an icon or the line of a chart.
+    Path {
+        /// The commands drawing the path's outline. A path that doesn't start
+        /// with [PathCommand::MoveTo] starts at the origin.
+        commands: Vec<PathCommand>,
+
+        /// How overlapping parts of the outline are filled.
+        fill_rule: FillRule,
+    },
+
+    /// An image resource uploaded with [ResourceUpload], drawn into a
+    /// rectangle with minimum and maximum bounds.
+    ///
+    /// The image is clipped to the rectangle. It ignores the current stroke.
+    Image {
+        /// The hash of the image resource.
+        resource: ResourceHash,
+        min: Vec2,
+        max: Vec2,
+
+        /// How the image is sized and placed within the rectangle.
+        fit: ImageFit,
+
+        /// How the image's pixels are sampled when it is scaled.
+        sampling: ImageSampling,
+    },
+}
+
+/// A drawing command in a [Shape::Path].
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum PathCommand {
+    /// Starts a new subpath at a point.
+    MoveTo(Vec2),
+
+    /// Draws a straight line to a point.
+    LineTo(Vec2),
+
+    /// Draws a quadratic Bézier curve to a point.
+    QuadTo { ctrl: Vec2, to: Vec2 },
+
+    /// Draws a cubic Bézier curve to a point.
+    CubicTo { ctrl1: Vec2, ctrl2: Vec2, to: Vec2 },
+
+    /// Draws a straight line to the start of a circular arc, then the arc.
+    ///
+    /// Angles are in radians, with zero pointing along the X axis. A positive
+    /// sweep goes clockwise, because the Y axis points down.
+    Arc {
+        center: Vec2,
+        radius: f32,
+        start_angle: f32,
+        sweep_angle: f32,
+    },
+
+    /// Draws a straight line back to the start of the current subpath. The
+    /// next subpath starts there too, unless it is moved.
+    Close,
+}
+
+/// How a [Shape::Path] decides which areas are inside it when its outline
+/// overlaps itself.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FillRule {
+    /// Areas are inside if the outline winds around them a different number
+    /// of times clockwise than counterclockwise.
+    #[default]
+    NonZero,
+
+    /// Areas are inside if the outline crosses an odd number of times between
+    /// them and the outside.
+    EvenOdd,
+}
+
+/// How a [Shape::Image] is sized and placed within its rectangle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ImageFit {
+    /// The image is stretched to fill the rectangle exactly.
+    #[default]
+    Fill,
+
+    /// The image is scaled to be as large as possible while fitting inside
+    /// the rectangle, keeping its aspect ratio, and centered.
+    Contain,
+
+    /// The image is scaled to be as small as possible while covering the
+    /// rectangle, keeping its aspect ratio, and centered. The parts of the
+    /// image outside the rectangle are cut off.
+    Cover,
+
+    /// The image is centered at its natural size of one unit per pixel.
+    None,
+}
+
+/// How the pixels of a scaled [Shape::Image] are sampled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ImageSampling {
+    /// Pixels are blended smoothly into each other, for photographs.
+    #[default]
+    Linear,
+
+    /// Each pixel is drawn as a sharp square, for pixel art.
+    Nearest,
+}
+
+/// A span of text in a [Shape::RichText].
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TextSpan {
+    /// The content of the span.
+    pub content: String,
+
+    /// The font to draw the span with.
+    pub font: Font,
+
+    /// The size of the font's em square.
+    pub size: f32,
+
+    /// The color of the span's text. Defaults to the current stroke.
+    pub color: Option<Color>,
+
+    /// The color to fill the box behind the span's text with, if any.
+    pub background: Option<Color>,
+
+    /// Whether to draw a line under the span's text.
+    pub underline: bool,
+
+    /// Whether to draw a line through the span's text.
+    pub strikethrough: bool,
+}
+
+impl TextSpan {
+    /// Creates an undecorated span drawn in the current stroke.
+    pub fn new(content: impl Into<String>, font: Font, size: f32) -> Self {
+        Self {
+            content: content.into(),
+            font,
+            size,
+            color: None,
+            background: None,
+            underline: false,
+            strikethrough: false,
+        }
+    }
+
+    /// Returns this span with a different text color.
+    pub fn with_color(self, color: Color) -> Self {
+        Self {
+            color: Some(color),
+            ..self
+        }
+    }
+
+    /// Returns this span with a background color.
+    pub fn with_background(self, background: Color) -> Self {
+        Self {
+            background: Some(background),
+            ..self
+        }
+    }
+
+    /// Returns this span with an underline.
+    pub fn with_underline(self) -> Self {
+        Self {
+            underline: true,
+            ..self
+        }
+    }
+
+    /// Returns this span with a strikethrough line.
+    pub fn with_strikethrough(self) -> Self {
+        Self {
+            strikethrough: true,
+            ..self
+        }
+    }
+}
+
+/// A description of a font, resolved by the renderer against the fonts it
+/// has loaded.
+///
+/// When no loaded font matches exactly, the renderer picks the closest match
+/// in family, then style, then weight.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Font {
+    /// The name of the font family, such as "Noto Sans".
+    pub family: String,
+
+    /// The weight, or thickness, of the font.
+    pub weight: FontWeight,
+
+    /// The slant of the font.
+    pub style: FontStyle,
+
+    /// OpenType features to turn on or off when shaping text, in addition to
+    /// the features that are on by default, like standard ligatures.
+    pub features: Vec<FontFeature>,
+
+    /// A font file uploaded with [ResourceUpload] to draw the text with. The
+    /// closest face in the file is chosen by style and weight, and the
+    /// family is ignored unless the file hasn't been uploaded.
+    pub resource: Option<ResourceHash>,
+}
+
+impl Default for Font {
+    fn default() -> Self {
+        Self {
+            family: Self::DEFAULT_FAMILY.to_string(),
+            weight: FontWeight::NORMAL,
+            style: FontStyle::Normal,
+            features: Vec::new(),
+            resource: None,
+        }
+    }
+}
+
+impl Font {
+    /// The family of the default font.
+    pub const DEFAULT_FAMILY: &'static str = "Noto Sans";
+
+    /// Returns this font with a different weight.
+    pub fn with_weight(self, weight: FontWeight) -> Self {
+        Self { weight, ..self }
+    }
+
+    /// Returns this font with a different style.
+    pub fn with_style(self, style: FontStyle) -> Self {
+        Self { style, ..self }
+    }
+
+    /// Returns this font with a feature turned on or off, replacing any
+    /// previous setting of the same feature.
+    pub fn with_feature(mut self, feature: FontFeature) -> Self {
+        self.features.retain(|other| other.tag != feature.tag);
+        self.features.push(feature);
+        self
+    }
+
+    /// Returns this font drawn from an uploaded font file.
+    pub fn with_resource(self, resource: ResourceHash) -> Self {
+        Self {
+            resource: Some(resource),
+            ..self
+        }
+    }
+}
+
+/// An OpenType feature to turn on or off when shaping text with a [Font].
+///
+/// Features are named by their four-letter OpenType tags, some of which have
+/// constants here. Fonts ignore features that they don't support.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct FontFeature {
+    /// The feature's tag, such as `*b"liga"`.
+    pub tag: [u8; 4],
+
+    /// Whether the feature is turned on.
+    pub enabled: bool,
+}
+
+impl FontFeature {
+    pub const KERNING: [u8; 4] = *b"kern";
+    pub const STANDARD_LIGATURES: [u8; 4] = *b"liga";
+    pub const CONTEXTUAL_LIGATURES: [u8; 4] = *b"clig";
+    pub const DISCRETIONARY_LIGATURES: [u8; 4] = *b"dlig";
+    pub const CONTEXTUAL_ALTERNATES: [u8; 4] = *b"calt";
+    pub const TABULAR_NUMBERS: [u8; 4] = *b"tnum";
+    pub const PROPORTIONAL_NUMBERS: [u8; 4] = *b"pnum";
+    pub const OLDSTYLE_NUMBERS: [u8; 4] = *b"onum";
+    pub const LINING_NUMBERS: [u8; 4] = *b"lnum";
+    pub const SLASHED_ZERO: [u8; 4] = *b"zero";
+    pub const FRACTIONS: [u8; 4] = *b"frac";
+    pub const SMALL_CAPS: [u8; 4] = *b"smcp";
+    pub const CAPITALS_TO_SMALL_CAPS: [u8; 4] = *b"c2sc";
+
+    /// Turns a feature on.
+    pub fn on(tag: [u8; 4]) -> Self {
+        Self { tag, enabled: true }
+    }
+
+    /// Turns a feature off.
+    pub fn off(tag: [u8; 4]) -> Self {
+        Self {
+            tag,
+            enabled: false,
+        }
+    }
+
+    /// The tag of one of the twenty stylistic sets, numbered from 1, or
+    /// `None` if there is no set with that number.
+    pub fn stylistic_set(set: u8) -> Option<[u8; 4]> {
+        (1..=20)
+            .contains(&set)
+            .then(|| [b's', b's', b'0' + set / 10, b'0' + set % 10])
+    }
+
+    /// Tests if this feature's tag is a valid OpenType tag: printable ASCII
+    /// characters padded with trailing spaces. Stylistic set tags must also
+    /// name one of the twenty sets.
+    pub fn is_valid(&self) -> bool {
+        let len = self.tag.iter().position(|c| *c == b' ').unwrap_or(4);
+        let (name, padding) = self.tag.split_at(len);
+
+        if len == 0 || !name.iter().all(|c| c.is_ascii_graphic()) {
+            return false;
+        }
+
+        if padding.iter().any(|c| *c != b' ') {
+            return false;
+        }
+
+        match self.tag {
+            [b's', b's', tens @ b'0'..=b'9', ones @ b'0'..=b'9'] => {
+                Self::stylistic_set((tens - b'0') * 10 + ones - b'0').is_some()
+            }
+            _ => true,
+        }
+    }
+}
+
+/// The weight of a [Font] on the OpenType scale from 1 to 1000.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub struct FontWeight(pub u16);
+
+impl Default for FontWeight {
+    fn default() -> Self {
+        Self::NORMAL
+    }
+}
+
+impl FontWeight {
+    pub const THIN: Self = Self(100);
+    pub const EXTRA_LIGHT: Self = Self(200);
+    pub const LIGHT: Self = Self(300);
+    pub const NORMAL: Self = Self(400);
+    pub const MEDIUM: Self = Self(500);
+    pub const SEMI_BOLD: Self = Self(600);
+    pub const BOLD: Self = Self(700);
+    pub const EXTRA_BOLD: Self = Self(800);
+    pub const BLACK: Self = Self(900);
+}
+
+/// The slant of a [Font].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum FontStyle {
+    /// Upright glyphs.
+    #[default]
+    Normal,
+
+    /// Glyphs from a cursive italic face.
+    Italic,
+
+    /// Upright glyphs that have been slanted.
+    Oblique,
+}
+
+/// The layout of the lines in a [Shape::Paragraph].
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ParagraphStyle {
+    /// The width that lines are broken to fit within. In vertical text, this
+    /// is the height of each column.
+    pub max_width: f32,
+
+    /// Whether lines run across or down the paragraph.
+    pub writing_mode: WritingMode,
+
+    /// How lines are aligned within the maximum width.
+    pub align: TextAlign,
+
+    /// The distance between the baselines of consecutive lines. Defaults to
+    /// the font's recommended line spacing.
+    pub line_height: Option<f32>,
+
+    /// The maximum number of lines to draw, if any.
+    pub max_lines: Option<u32>,
+
+    /// What to do with text that does not fit within the maximum lines.
+    pub overflow: TextOverflow,
+}
+
+impl ParagraphStyle {
+    /// Creates a style that wraps lines to a maximum width and aligns them to
+    /// the start, without limiting the number of lines.
+    pub fn new(max_width: f32) -> Self {
+        Self {
+            max_width,
+            writing_mode: WritingMode::default(),
+            align: TextAlign::default(),
+            line_height: None,
+            max_lines: None,
+            overflow: TextOverflow::default(),
+        }
+    }
+
+    /// Returns this style with a different writing mode.
+    pub fn with_writing_mode(self, writing_mode: WritingMode) -> Self {
+        Self {
+            writing_mode,
+            ..self
+        }
+    }
+
+    /// Returns this style with a different alignment.
+    pub fn with_align(self, align: TextAlign) -> Self {
+        Self { align, ..self }
+    }
+
+    /// Returns this style with a fixed line height.
+    pub fn with_line_height(self, line_height: f32) -> Self {
+        Self {
+            line_height: Some(line_height),
+            ..self
+        }
+    }
+
+    /// Returns this style limited to a number of lines, with the given
+    /// behavior for the text that does not fit.
+    pub fn with_max_lines(self, max_lines: u32, overflow: TextOverflow) -> Self {
+        Self {
+            max_lines: Some(max_lines),
+            overflow,
+            ..self
+        }
     }
 }
 
+/// The direction that the lines of a [Shape::Paragraph] run in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum WritingMode {
+    /// Lines run left to right or right to left, stacked from top to bottom.
+    #[default]
+    Horizontal,
+
+    /// Lines run from top to bottom in columns, stacked from right to left,
+    /// as in traditional Chinese and Japanese text. CJK characters stand
+    /// upright, using the font's vertical alternates, and other text lies
+    /// sideways along the column.
+    ///
+    /// The paragraph's origin is at its top-right corner.
+    Vertical,
+}
+
+/// The alignment of lines in a [Shape::Paragraph] along their direction.
+///
+/// The start and end of a line follow the paragraph's direction, so that
+/// start-aligned right-to-left text is aligned to the right.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum TextAlign {
+    #[default]
+    Start,
+    Center,
+    End,
+
+    /// Stretches the spaces in each line to fill the maximum width. The last
+    /// line and lines ending in a line break are aligned to the start.
+    Justify,
+}
+
+/// The treatment of text past the maximum lines of a [Shape::Paragraph].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum TextOverflow {
+    /// The remaining text is not drawn.
+    #[default]
+    Clip,
+
+    /// The last line is shortened to end in an ellipsis ("…").
+    Ellipsis,
+}
+
 /// A shape tree node with one child that applies a graphical operation to that
 /// child.
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum Operation {
-    /// A stroke to apply to all children.
+    /// The paint that the shapes of all children are filled with, or outlined
+    /// with inside an [Operation::Outline].
     Stroke(Stroke),
 
+    /// Draws the outlines of the shapes of all children instead of filling
+    /// them. Text is still filled.
+    Outline(OutlineStyle),
+
     /// A translation transformation.
     Translate { offset: Vec2 },
 
@@ -167,6 +809,13 @@ pub enum Operation {
     /// A scale transformation.
     Scale { scale: f32 },
 
+    /// A general 2D affine transformation, which can also scale each axis
+    /// differently and skew.
+    ///
+    /// [Operation::rotation_about], [Operation::scale_axes], and
+    /// [Operation::skew] create common transformations.
+    Transform { transform: Affine2 },
+
     /// Places the child in an opacity group with the given opacity.
     ///
     /// Note that this is applied to all children of this operation AFTER they
@@ -176,11 +825,480 @@ pub enum Operation {
     /// Applies a Gaussian or Gaussian-like blur to the result of rendering
     /// all children.
     Blur { radius: f32 },
+
+    /// Limits drawing of the child to the inside of a region, in the local
+    /// space of this operation.
+    Clip(Clip),
+
+    /// Draws the child into a separate layer, then composites that layer onto
+    /// what's beneath it with a blend mode.
+    ///
+    /// Like [Operation::Opacity], this is applied to all children of this
+    /// operation together, and not independently for each child.
+    Blend(BlendMode),
+}
+
+impl Operation {
+    /// Creates a rotation around a pivot point instead of the origin.
+    pub fn rotation_about(angle: f32, pivot: Vec2) -> Self {
+        let transform = Affine2::from_translation(pivot)
+            * Affine2::from_angle(angle)
+            * Affine2::from_translation(-pivot);
+
+        Operation::Transform { transform }
+    }
+
+    /// Creates a scale with a different factor along each axis.
+    pub fn scale_axes(scale: Vec2) -> Self {
+        Operation::Transform {
+            transform: Affine2::from_scale(scale),
+        }
+    }
+
+    /// Creates a skew by an angle in radians along each axis.
+    ///
+    /// Skewing along X by an angle slants vertical lines by that angle, and
+    /// skewing along Y slants horizontal lines.
+    pub fn skew(angles: Vec2) -> Self {
+        let matrix = Mat2::from_cols(
+            Vec2::new(1.0, angles.y.tan()),
+            Vec2::new(angles.x.tan(), 1.0),
+        );
+        Operation::Transform {
+            transform: Affine2::from_mat2(matrix),
+        }
+    }
+}
+
+/// The region that an [Operation::Clip] limits its child to.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Clip {
+    /// A rectangle with minimum and maximum bounds.
+    Rectangle { min: Vec2, max: Vec2 },
+
+    /// A rectangle with rounded corners.
+    ///
+    /// The radii are ordered clockwise from the top-left corner, like those of
+    /// a [Shape::RoundedRectangle].
+    RoundedRectangle { min: Vec2, max: Vec2, radii: Vec4 },
+
+    /// The inside of a path, like that of a [Shape::Path].
+    Path {
+        commands: Vec<PathCommand>,
+        fill_rule: FillRule,
+    },
+}
+
+/// How an [Operation::Blend] composites its layer, which is the source, onto
+/// what's beneath it, which is the destination.
+///
+/// The layer is transparent outside of the child, and is only composited
+/// inside of the child's bounding box and any [Operation::Clip] that the
+/// blend is drawn in. Modes that change the destination where the source is
+/// transparent, like [BlendMode::SourceIn], change all of that region, but
+/// nothing outside of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum BlendMode {
+    /// Clears the destination.
+    Clear,
+
+    /// Replaces the destination with the source.
+    Source,
+
+    /// Keeps the destination and discards the source.
+    Destination,
+
+    /// Draws the source over the destination, like drawing without a layer.
+    #[default]
+    SourceOver,
+
+    /// Draws the source behind the destination.
+    DestinationOver,
+
+    /// Keeps the source where the destination is opaque.
+    SourceIn,
+
+    /// Keeps the destination where the source is opaque.
+    DestinationIn,
+
+    /// Keeps the source where the destination is transparent.
+    SourceOut,
+
+    /// Keeps the destination where the source is transparent.
+    DestinationOut,
+
+    /// Draws the source over the destination, only where the destination is
+    /// opaque.
+    SourceAtop,
+
+    /// Draws the destination over the source, only where the source is
+    /// opaque.
+    DestinationAtop,
+
+    /// Keeps the source and destination where they don't overlap.
+    Xor,
+
+    /// Adds the source to the destination.
+    Add,
+
+    /// Multiplies the source and destination colors, darkening them.
+    Multiply,
+
+    /// Multiplies the inverses of the source and destination colors,
+    /// lightening them.
+    Screen,
+
+    /// Multiplies dark destination colors and screens light ones.
+    Overlay,
+
+    /// Keeps the darker of the source and destination colors.
+    Darken,
+
+    /// Keeps the lighter of the source and destination colors.
+    Lighten,
+
+    /// Brightens the destination to reflect the source.
+    ColorDodge,
+
+    /// Darkens the destination to reflect the source.
+    ColorBurn,
+
+    /// Multiplies dark source colors and screens light ones.
+    HardLight,
+
+    /// A softer [BlendMode::HardLight].
+    SoftLight,
+
+    /// Subtracts the darker of the source and destination colors from the
+    /// lighter one.
+    Difference,
+
+    /// Like [BlendMode::Difference], but with less contrast.
+    Exclusion,
+}
+
+/// The style of the lines drawn by an [Operation::Outline].
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct OutlineStyle {
+    /// The width of the lines, centered on the edges of shapes.
+    pub width: f32,
+
+    /// The shape of the corners between segments.
+    pub join: LineJoin,
+
+    /// The shape of the ends of open lines and dashes.
+    pub cap: LineCap,
+
+    /// The ratio of the length of a [LineJoin::Miter] corner to the width of
+    /// the line past which the corner is beveled instead.
+    pub miter_limit: f32,
+
+    /// The lengths of alternating dashes and gaps along the lines. Lines are
+    /// solid if this is empty.
+    pub dash_array: Vec<f32>,
+
+    /// How far into the dash pattern lines start.
+    pub dash_offset: f32,
+}
+
+impl OutlineStyle {
+    /// Creates a style for solid lines of a width, with mitered corners and
+    /// flat ends.
+    pub fn new(width: f32) -> Self {
+        Self {
+            width,
+            join: LineJoin::default(),
+            cap: LineCap::default(),
+            miter_limit: 4.0,
+            dash_array: Vec::new(),
+            dash_offset: 0.0,
+        }
+    }
+
+    /// Returns this style with a different corner shape.
+    pub fn with_join(self, join: LineJoin) -> Self {
+        Self { join, ..self }
+    }
+
+    /// Returns this style with a different line end shape.
+    pub fn with_cap(self, cap: LineCap) -> Self {
+        Self { cap, ..self }
+    }
+
+    /// Returns this style with a different miter limit.
+    pub fn with_miter_limit(self, miter_limit: f32) -> Self {
+        Self {
+            miter_limit,
+            ..self
+        }
+    }
+
+    /// Returns this style with dashed lines.
+    pub fn with_dashes(self, dash_array: Vec<f32>, dash_offset: f32) -> Self {
+        Self {
+            dash_array,
+            dash_offset,
+            ..self
+        }
+    }
+}
+
+/// The shape of the corners in the lines of an [Operation::Outline].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum LineJoin {
+    /// The outer edges of the lines are extended until they meet.
+    #[default]
+    Miter,
+
+    /// The corner is rounded off.
+    Round,
+
+    /// The corner is cut off straight.
+    Bevel,
+}
+
+/// The shape of the ends of the lines of an [Operation::Outline].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum LineCap {
+    /// Lines end flat at their endpoints.
+    #[default]
+    Butt,
+
+    /// Lines end in a semicircle around their endpoints.
+    Round,
+
+    /// Lines end flat, half their width past their endpoints.
+    Square,
 }
 
 /// A stroke to apply to a [Operation::Stroke] operation.
+///
+/// The geometry of gradients is in the local coordinates of each shape they
+/// paint, after every transform between the stroke and the shape.
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum Stroke {
     /// A solid stroke with a given color.
-    Solid { color: Vec3A },
+    Solid { color: Color },
+
+    /// Colors that change along the line from `start` to `end`.
+    LinearGradient {
+        start: Vec2,
+        end: Vec2,
+        gradient: Gradient,
+    },
+
+    /// Colors that change outwards from `center` to a circle of `radius`.
+    RadialGradient {
+        center: Vec2,
+        radius: f32,
+        gradient: Gradient,
+    },
+
+    /// Colors that change clockwise around `center`, from `start_angle` to
+    /// `end_angle` in radians, with zero pointing along the X axis.
+    SweepGradient {
+        center: Vec2,
+        start_angle: f32,
+        end_angle: f32,
+        gradient: Gradient,
+    },
+}
+
+/// The colors of a gradient [Stroke].
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Gradient {
+    /// The colors at points along the gradient, in order of their offsets.
+    pub stops: Vec<GradientStop>,
+
+    /// How the gradient continues past its first and last stops.
+    pub spread: Spread,
+}
+
+impl Gradient {
+    /// Creates a gradient that blends evenly between colors and pads past
+    /// its ends.
+    pub fn new(colors: impl IntoIterator<Item = Color>) -> Self {
+        let colors: Vec<_> = colors.into_iter().collect();
+        let last = colors.len().saturating_sub(1).max(1) as f32;
+        let stops = colors
+            .into_iter()
+            .enumerate()
+            .map(|(idx, color)| GradientStop {
+                offset: idx as f32 / last,
+                color,
+            })
+            .collect();
+
+        Self {
+            stops,
+            spread: Spread::default(),
+        }
+    }
+
+    /// Returns this gradient with a different spread.
+    pub fn with_spread(self, spread: Spread) -> Self {
+        Self { spread, ..self }
+    }
+}
+
+/// A color at a point along a [Gradient].
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct GradientStop {
+    /// How far along the gradient this color is, from 0 at its start to 1 at
+    /// its end.
+    pub offset: f32,
+    pub color: Color,
+}
+
+/// How a [Gradient] continues past its first and last stops.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Spread {
+    /// The colors of the first and last stops continue forever.
+    #[default]
+    Pad,
+
+    /// The gradient starts over from its first stop.
+    Repeat,
+
+    /// The gradient goes back and forth between its first and last stops.
+    Reflect,
+}
+
+/// A color with components from 0 to 1.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Color {
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32,
+
+    /// The opacity of the color, from 0 for transparent to 1 for opaque. The
+    /// other components are not premultiplied by it.
+    pub alpha: f32,
+
+    /// The color space of the red, green, and blue components.
+    pub space: ColorSpace,
+}
+
+impl Color {
+    pub const TRANSPARENT: Self = Self::rgba(0.0, 0.0, 0.0, 0.0);
+    pub const BLACK: Self = Self::rgb(0.0, 0.0, 0.0);
+    pub const WHITE: Self = Self::rgb(1.0, 1.0, 1.0);
+
+    /// Creates an opaque sRGB color.
+    pub const fn rgb(red: f32, green: f32, blue: f32) -> Self {
+        Self::rgba(red, green, blue, 1.0)
+    }
+
+    /// Creates a translucent sRGB color.
+    pub const fn rgba(red: f32, green: f32, blue: f32, alpha: f32) -> Self {
+        Self {
+            red,
+            green,
+            blue,
+            alpha,
+            space: ColorSpace::Srgb,
+        }
+    }
+
+    /// Returns this color with a different opacity.
+    pub fn with_alpha(self, alpha: f32) -> Self {
+        Self { alpha, ..self }
+    }
+
+    /// Returns this color with its components tagged as being in a different
+    /// color space, without converting them.
+    pub fn with_space(self, space: ColorSpace) -> Self {
+        Self { space, ..self }
+    }
+
+    /// Converts this color's components into sRGB.
+    pub fn to_srgb(self) -> Self {
+        match self.space {
+            ColorSpace::Srgb => self,
+            ColorSpace::LinearSrgb => {
+                let encode = |linear: f32| match linear <= 0.0031308 {
+                    true => linear * 12.92,
+                    false => 1.055 * linear.powf(1.0 / 2.4) - 0.055,
+                };
+
+                Self {
+                    red: encode(self.red),
+                    green: encode(self.green),
+                    blue: encode(self.blue),
+                    alpha: self.alpha,
+                    space: ColorSpace::Srgb,
+                }
+            }
+        }
+    }
+}
+
+impl From<Vec3A> for Color {
+    /// Creates an opaque sRGB color.
+    fn from(rgb: Vec3A) -> Self {
+        Self::rgb(rgb.x, rgb.y, rgb.z)
+    }
+}
+
+impl From<Vec4> for Color {
+    /// Creates a translucent sRGB color.
+    fn from(rgba: Vec4) -> Self {
+        Self::rgba(rgba.x, rgba.y, rgba.z, rgba.w)
+    }
+}
+
+/// The color space of a [Color].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum ColorSpace {
+    /// The standard color space of displays and images, in which components
+    /// are encoded nonlinearly so that even steps look even.
+    #[default]
+    Srgb,
+
+    /// The primaries of sRGB, with components proportional to the intensity
+    /// of light. Colors are converted to sRGB before they are drawn.
+    LinearSrgb,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_colors_are_encoded_to_srgb() {
+        let linear = Color::rgba(0.0, 0.5, 1.0, 0.25).with_space(ColorSpace::LinearSrgb);
+        let srgb = linear.to_srgb();
+        assert_eq!(srgb.space, ColorSpace::Srgb);
+        assert_eq!(srgb.red, 0.0);
+        assert!((srgb.green - 0.7354).abs() < 0.0001);
+        assert!((srgb.blue - 1.0).abs() < 0.0001);
+        assert_eq!(srgb.alpha, 0.25);
+    }
+
+    #[test]
+    fn srgb_colors_are_unchanged() {
+        let color = Color::rgba(0.1, 0.5, 0.9, 0.5);
+        assert_eq!(color.to_srgb(), color);
+    }
+
+    #[test]
+    fn stylistic_sets_are_numbered_1_to_20() {
+        assert_eq!(FontFeature::stylistic_set(1), Some(*b"ss01"));
+        assert_eq!(FontFeature::stylistic_set(20), Some(*b"ss20"));
+        assert_eq!(FontFeature::stylistic_set(0), None);
+        assert_eq!(FontFeature::stylistic_set(21), None);
+        assert_eq!(FontFeature::stylistic_set(255), None);
+    }
+
+    #[test]
+    fn feature_tags_are_validated() {
+        assert!(FontFeature::on(FontFeature::KERNING).is_valid());
+        assert!(FontFeature::on(*b"ss07").is_valid());
+        assert!(FontFeature::on(*b"cv9 ").is_valid());
+        assert!(!FontFeature::on(*b"ss00").is_valid());
+        assert!(!FontFeature::on(*b"ss21").is_valid());
+        assert!(!FontFeature::on(*b"    ").is_valid());
+        assert!(!FontFeature::on(*b"a b ").is_valid());
+        assert!(!FontFeature::on([b'k', b'e', b'r', 0]).is_valid());
+    }
 }
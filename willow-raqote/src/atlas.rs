@@ -0,0 +1,632 @@
+// Copyright (C) 2023 Marceline Cramer
+//
+// Willow is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// Willow is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with Willow.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::num::NonZeroUsize;
+use std::rc::Rc;
+
+use lru::LruCache;
+use raqote::{
+    BlendMode, DrawOptions, DrawTarget, IntPoint, IntRect, Mask, SolidSource, Source, Transform,
+};
+use willow_text::{
+    font::FontData, hinting::autohint, FaceId, FontRegistry, GlyphRotation, PositionedGlyph,
+};
+
+use crate::text::outline_path;
+
+/// The maximum number of glyph masks cached by a [GlyphAtlas].
+pub const ATLAS_CAPACITY: usize = 4096;
+
+/// The largest size in pixels per em that glyphs are cached at. Larger
+/// glyphs are rasterized again each time they're drawn, and only where
+/// they're visible.
+pub const MAX_CACHED_PPEM: f32 = 96.0;
+
+/// The number of steps per pixel that cached glyph sizes are rounded to.
+pub const SIZE_STEPS: f32 = 4.0;
+
+/// The number of horizontal offsets within a pixel that glyphs are cached at.
+pub const SUBPIXEL_STEPS: f32 = 4.0;
+
+/// How glyph outlines are fitted to the pixel grid.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Hinting {
+    /// Outlines are drawn as they are.
+    #[default]
+    None,
+
+    /// Outlines are autohinted vertically with [autohint]: horizontal edges
+    /// and the tops and bottoms of curves are moved onto pixel boundaries, so
+    /// that baselines, x-heights, and horizontal strokes are sharp. Outlines
+    /// aren't changed horizontally.
+    ///
+    /// The autohinter finds edges from the outlines alone, and doesn't run
+    /// the hinting instructions in TrueType fonts.
+    Autohint,
+
+    /// Outlines are hinted with [FontData::hinted_outline], which runs the
+    /// TrueType hinting instructions or CFF hints in the font, and falls
+    /// back to a FreeType-style autohinter for fonts without them. Unlike
+    /// [Hinting::Autohint], this may move outlines horizontally too.
+    TrueType,
+}
+
+/// How a [GlyphAtlas] rasterizes and positions glyphs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TextRendering {
+    /// Whether glyphs are positioned to a quarter of a pixel horizontally,
+    /// instead of being snapped to whole pixels. Text is spaced more evenly
+    /// with subpixel positioning, but the same glyph may be drawn differently
+    /// each time it appears.
+    pub subpixel_positioning: bool,
+
+    pub hinting: Hinting,
+
+    /// The gamma that glyphs' coverage is raised by. Values above 1 make
+    /// text look heavier, and 1 leaves its coverage unchanged.
+    ///
+    /// This only applies to glyphs drawn by a [GlyphAtlas]. Glyphs that it
+    /// can't draw, like color glyphs, sideways glyphs, and glyphs drawn
+    /// with gradients, blending, or rotation, are filled as paths without
+    /// gamma adjustment.
+    pub gamma: f32,
+}
+
+impl Default for TextRendering {
+    fn default() -> Self {
+        Self {
+            subpixel_positioning: true,
+            hinting: Hinting::default(),
+            gamma: 1.0,
+        }
+    }
+}
+
+impl TextRendering {
+    pub fn with_subpixel_positioning(mut self, subpixel_positioning: bool) -> Self {
+        self.subpixel_positioning = subpixel_positioning;
+        self
+    }
+
+    pub fn with_hinting(mut self, hinting: Hinting) -> Self {
+        self.hinting = hinting;
+        self
+    }
+
+    pub fn with_gamma(mut self, gamma: f32) -> Self {
+        self.gamma = gamma;
+        self
+    }
+
+    /// Maps each coverage value to its value after gamma adjustment.
+    fn gamma_table(&self) -> [u8; 256] {
+        let exponent = self.gamma.recip();
+        std::array::from_fn(|coverage| {
+            ((coverage as f32 / 255.0).powf(exponent) * 255.0).round() as u8
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct MaskKey {
+    face: FaceId,
+    index: u16,
+
+    /// The size in pixels per em, in multiples of [SIZE_STEPS].
+    size: u32,
+
+    /// The horizontal offset of the origin, in multiples of [SUBPIXEL_STEPS].
+    subpixel: u8,
+}
+
+/// A glyph's coverage, rasterized at one size and subpixel offset.
+struct GlyphMask {
+    /// The offset of the mask's top-left pixel from the pixel containing the
+    /// glyph's origin.
+    left: i32,
+    top: i32,
+
+    mask: Mask,
+}
+
+/// A cache of rasterized glyphs, blitted in the color of the text instead of
+/// filling their outlines every time they're drawn.
+///
+/// Glyphs are rasterized once for each size, rounded to a quarter of a pixel
+/// per em, and, with [subpixel positioning][TextRendering::subpixel_positioning],
+/// each of four horizontal offsets within a pixel. Baselines are snapped to
+/// whole pixels.
+pub struct GlyphAtlas {
+    masks: LruCache<MaskKey, Option<Rc<GlyphMask>>>,
+    rendering: TextRendering,
+    gamma_table: [u8; 256],
+    clip: Option<IntRect>,
+}
+
+impl Default for GlyphAtlas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GlyphAtlas {
+    pub fn new() -> Self {
+        let rendering = TextRendering::default();
+        Self {
+            masks: LruCache::new(NonZeroUsize::new(ATLAS_CAPACITY).unwrap()),
+            rendering,
+            gamma_table: rendering.gamma_table(),
+            clip: None,
+        }
+    }
+
+    pub fn with_rendering(mut self, rendering: TextRendering) -> Self {
+        self.set_rendering(rendering);
+        self
+    }
+
+    pub fn rendering(&self) -> &TextRendering {
+        &self.rendering
+    }
+
+    /// Changes how glyphs are rasterized, removing every cached glyph if the
+    /// settings are different.
+    pub fn set_rendering(&mut self, rendering: TextRendering) {
+        if rendering != self.rendering {
+            self.rendering = rendering;
+            self.gamma_table = rendering.gamma_table();
+            self.clear();
+        }
+    }
+
+    /// Limits the pixels that glyphs are drawn into to a rectangle, or lifts
+    /// the limit if `clip` is `None`.
+    ///
+    /// Masks don't use the clips of the target that they're drawn into, so
+    /// the renderer sets this to match them.
+    pub fn set_clip(&mut self, clip: Option<IntRect>) {
+        self.clip = clip;
+    }
+
+    /// Removes every cached glyph.
+    pub fn clear(&mut self) {
+        self.masks.clear();
+    }
+
+    /// Draws a glyph from the cache, rasterizing it first if it's missing.
+    ///
+    /// Glyphs larger than [MAX_CACHED_PPEM] are rasterized without being
+    /// cached, so that they're drawn with the same hinting and gamma.
+    ///
+    /// Returns false without drawing anything if the glyph can't be masked:
+    /// if it's a color glyph or turned sideways, or if it's drawn with a
+    /// source that isn't solid, with blending options, or with a transform
+    /// other than a translation and a uniform scale. These glyphs should be
+    /// filled as paths instead.
+    ///
+    /// Masks are blended straight into the pixels of `dt`, so they ignore its
+    /// layers and clips, and are only limited by [GlyphAtlas::set_clip].
+    pub fn draw<Backing>(
+        &mut self,
+        dt: &mut DrawTarget<Backing>,
+        fonts: &mut FontRegistry,
+        glyph: &PositionedGlyph,
+        source: &Source,
+        options: &DrawOptions,
+    ) -> bool
+    where
+        Backing: AsRef<[u32]> + AsMut<[u32]>,
+    {
+        // masks are always composited over the target at full opacity
+        if options.alpha != 1.0 || options.blend_mode != BlendMode::SrcOver {
+            return false;
+        }
+
+        let Source::Solid(color) = source else {
+            return false;
+        };
+
+        if glyph.rotation != GlyphRotation::Upright {
+            return false;
+        }
+
+        let transform = *dt.get_transform();
+        let scale = transform.m11;
+        if transform.m12 != 0.0 || transform.m21 != 0.0 || transform.m22 != scale || scale <= 0.0 {
+            return false;
+        }
+
+        let Some(face) = fonts.get_mut(glyph.face) else {
+            return false;
+        };
+
+        let data = &mut face.data;
+        let ppem = glyph.scale * data.metrics().units_per_em * scale;
+        if data.color_glyph(glyph.index, ppem).is_some() {
+            return false;
+        }
+
+        let origin = transform.transform_point(euclid::point2(glyph.position.x, glyph.position.y));
+        let steps = match self.rendering.subpixel_positioning {
+            true => SUBPIXEL_STEPS,
+            false => 1.0,
+        };
+
+        let mut x = origin.x.floor() as i32;
+        let mut subpixel = ((origin.x - origin.x.floor()) * steps).round() as u8;
+        if subpixel as f32 >= steps {
+            subpixel = 0;
+            x += 1;
+        }
+
+        let y = origin.y.round() as i32;
+
+        let key = MaskKey {
+            face: glyph.face,
+            index: glyph.index,
+            size: (ppem * SIZE_STEPS).round() as u32,
+            subpixel,
+        };
+
+        let mask = if ppem > MAX_CACHED_PPEM {
+            // only the part of the glyph inside the target is rasterized
+            let size = IntPoint::new(dt.width(), dt.height());
+            let visible = self.clip.unwrap_or(IntRect::new(IntPoint::zero(), size));
+            let visible = visible.translate(-IntPoint::new(x, y).to_vector());
+            self.rasterize(data, &key, Some(visible)).map(Rc::new)
+        } else if let Some(mask) = self.masks.get(&key) {
+            mask.clone()
+        } else {
+            let mask = self.rasterize(data, &key, None).map(Rc::new);
+            self.masks.put(key, mask.clone());
+            mask
+        };
+
+        if let Some(mask) = mask {
+            let origin = IntPoint::new(x + mask.left, y + mask.top);
+            blit(dt, *color, origin, &mask.mask, self.clip);
+        }
+
+        true
+    }
+
+    /// Fills a glyph's outline into a new coverage mask, or returns `None` if
+    /// the glyph has no outline.
+    ///
+    /// If `visible` is set, the mask only covers the pixels of the glyph
+    /// inside it, relative to the pixel containing the glyph's origin, and
+    /// `None` is returned if none of them are.
+    fn rasterize(
+        &self,
+        data: &mut FontData,
+        key: &MaskKey,
+        visible: Option<IntRect>,
+    ) -> Option<GlyphMask> {
+        let outline = data.glyph_outline(key.index);
+        if outline.is_empty() {
+            return None;
+        }
+
+        let ppem = key.size as f32 / SIZE_STEPS;
+        let scale = ppem / data.metrics().units_per_em;
+        let outline = match self.rendering.hinting {
+            Hinting::None => outline,
+            Hinting::Autohint => Rc::new(autohint(&outline, scale)),
+            Hinting::TrueType => match data.hinted_outline(key.index, ppem) {
+                Some(hinted) => Rc::new(hinted),
+                None => outline,
+            },
+        };
+
+        let offset = key.subpixel as f32 / SUBPIXEL_STEPS;
+
+        // pixel bounds of the outline with the Y axis flipped to point down
+        let mut bounds = IntRect::new(
+            IntPoint::new(
+                (outline.min.x * scale + offset).floor() as i32,
+                (-outline.max.y * scale).floor() as i32,
+            ),
+            IntPoint::new(
+                (outline.max.x * scale + offset).ceil() as i32,
+                (-outline.min.y * scale).ceil() as i32,
+            ),
+        );
+
+        if let Some(visible) = visible {
+            bounds = bounds.intersection(&visible)?;
+        }
+
+        let (left, top) = (bounds.min.x, bounds.min.y);
+        let width = bounds.width().max(1);
+        let height = bounds.height().max(1);
+
+        let transform = Transform::new(scale, 0.0, 0.0, -scale, offset - left as f32, -top as f32);
+
+        let path = outline_path(&outline).transform(&transform);
+        let white = Source::Solid(SolidSource::from_unpremultiplied_argb(255, 255, 255, 255));
+        let mut target = DrawTarget::new(width, height);
+        target.fill(&path, &white, &DrawOptions::new());
+
+        let coverage = target
+            .get_data()
+            .iter()
+            .map(|pixel| self.gamma_table[(pixel >> 24) as usize])
+            .collect();
+
+        Some(GlyphMask {
+            left,
+            top,
+            mask: Mask {
+                width,
+                height,
+                data: coverage,
+            },
+        })
+    }
+}
+
+/// Blends a solid color through a coverage mask into the pixels of `dt`, with
+/// the mask's top-left corner at `origin`, and only within `clip` if it's set.
+///
+/// [DrawTarget::mask] can't be used instead because it misplaces masks that
+/// aren't drawn at the origin.
+fn blit<Backing>(
+    dt: &mut DrawTarget<Backing>,
+    color: SolidSource,
+    origin: IntPoint,
+    mask: &Mask,
+    clip: Option<IntRect>,
+) where
+    Backing: AsRef<[u32]> + AsMut<[u32]>,
+{
+    let width = dt.width();
+    let height = dt.height();
+    let clip = clip.unwrap_or(IntRect::new(IntPoint::zero(), IntPoint::new(width, height)));
+    let (x, y) = (origin.x, origin.y);
+    let columns = x.max(clip.min.x).max(0)..(x + mask.width).min(clip.max.x).min(width);
+    let rows = y.max(clip.min.y).max(0)..(y + mask.height).min(clip.max.y).min(height);
+    let color = [color.a, color.r, color.g, color.b].map(u32::from);
+    let pixels = dt.get_data_mut();
+
+    for row in rows {
+        for column in columns.clone() {
+            let coverage = mask.data[((row - y) * mask.width + column - x) as usize] as u32;
+            if coverage == 0 {
+                continue;
+            }
+
+            let src = color.map(|channel| (channel * coverage + 127) / 255);
+            let inverse = 255 - src[0];
+            let pixel = &mut pixels[(row * width + column) as usize];
+            let dst = pixel.to_be_bytes().map(u32::from);
+            let blended: [u8; 4] =
+                std::array::from_fn(|i| (src[i] + (dst[i] * inverse + 127) / 255) as u8);
+            *pixel = u32::from_be_bytes(blended);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    use willow_server::{glam::Vec2, Font, FontWeight};
+
+    use crate::text::draw_glyphs;
+
+    const SIZE: i32 = 128;
+
+    /// Draws white text on a transparent target, through `atlas` if it's set.
+    fn draw_text(text: &str, size: f32, atlas: Option<&mut GlyphAtlas>) -> DrawTarget {
+        let mut fonts = FontRegistry::default();
+        let layout = fonts.layout(&Font::default(), text, size);
+        let white = Source::Solid(SolidSource::from_unpremultiplied_argb(255, 255, 255, 255));
+        let mut dt = DrawTarget::new(SIZE, SIZE);
+        dt.set_transform(&Transform::translation(4.0, SIZE as f32 - 8.0));
+        draw_glyphs(
+            &mut dt,
+            &mut fonts,
+            atlas,
+            &layout.glyphs,
+            &white,
+            &DrawOptions::new(),
+        );
+        dt
+    }
+
+    /// Draws one glyph through `atlas` on a transparent target, with the
+    /// glyph's origin `x` pixels to the right of the target's translation.
+    fn draw_glyph(
+        atlas: &mut GlyphAtlas,
+        fonts: &mut FontRegistry,
+        glyph: &PositionedGlyph,
+        x: f32,
+    ) -> DrawTarget {
+        let glyph = PositionedGlyph {
+            position: Vec2::new(x, 0.0),
+            ..*glyph
+        };
+
+        let white = Source::Solid(SolidSource::from_unpremultiplied_argb(255, 255, 255, 255));
+        let mut dt = DrawTarget::new(SIZE, SIZE);
+        dt.set_transform(&Transform::translation(4.0, SIZE as f32 - 8.0));
+        assert!(atlas.draw(&mut dt, fonts, &glyph, &white, &DrawOptions::new()));
+        dt
+    }
+
+    /// Lays out the first glyph of some text.
+    fn glyph(fonts: &mut FontRegistry, font: &Font, text: &str, size: f32) -> PositionedGlyph {
+        fonts.layout(font, text, size).glyphs[0]
+    }
+
+    fn keys(atlas: &GlyphAtlas) -> HashSet<MaskKey> {
+        atlas.masks.iter().map(|(key, _)| *key).collect()
+    }
+
+    /// Sums the coverage of every pixel in a target.
+    fn coverage(dt: &DrawTarget) -> u32 {
+        dt.get_data().iter().map(|pixel| pixel >> 24).sum()
+    }
+
+    #[test]
+    fn large_glyphs_are_gamma_adjusted() {
+        let size = MAX_CACHED_PPEM * 1.2;
+        let mut atlas = GlyphAtlas::new();
+        let linear = draw_text("H", size, Some(&mut atlas));
+
+        let rendering = TextRendering::default().with_gamma(2.2);
+        let mut atlas = GlyphAtlas::new().with_rendering(rendering);
+        let adjusted = draw_text("H", size, Some(&mut atlas));
+
+        assert!(coverage(&linear) > 0);
+        assert!(coverage(&adjusted) > coverage(&linear));
+        assert!(atlas.masks.is_empty());
+    }
+
+    #[test]
+    fn masks_are_cached_by_face_glyph_size_and_subpixel_offset() {
+        let mut fonts = FontRegistry::default();
+        let mut atlas = GlyphAtlas::new();
+        let regular = Font::default();
+        let bold = Font::default().with_weight(FontWeight::BOLD);
+        let h = glyph(&mut fonts, &regular, "H", 16.0);
+        let i = glyph(&mut fonts, &regular, "I", 16.0);
+        let bold_h = glyph(&mut fonts, &bold, "H", 16.0);
+        let large_h = glyph(&mut fonts, &regular, "H", 24.0);
+        let nearly_h = glyph(&mut fonts, &regular, "H", 16.1);
+        assert_ne!(h.face, bold_h.face);
+
+        let key = |glyph: &PositionedGlyph, size, subpixel| MaskKey {
+            face: glyph.face,
+            index: glyph.index,
+            size,
+            subpixel,
+        };
+
+        draw_glyph(&mut atlas, &mut fonts, &h, 0.0);
+        draw_glyph(&mut atlas, &mut fonts, &h, 0.0);
+        draw_glyph(&mut atlas, &mut fonts, &h, 3.0);
+        assert_eq!(keys(&atlas), HashSet::from([key(&h, 64, 0)]));
+
+        draw_glyph(&mut atlas, &mut fonts, &h, 0.25);
+        draw_glyph(&mut atlas, &mut fonts, &i, 0.0);
+        draw_glyph(&mut atlas, &mut fonts, &bold_h, 0.0);
+        draw_glyph(&mut atlas, &mut fonts, &large_h, 0.0);
+
+        // sizes are rounded to a quarter of a pixel per em
+        draw_glyph(&mut atlas, &mut fonts, &nearly_h, 0.0);
+
+        let expected = HashSet::from([
+            key(&h, 64, 0),
+            key(&h, 64, 1),
+            key(&i, 64, 0),
+            key(&bold_h, 64, 0),
+            key(&h, 96, 0),
+        ]);
+
+        assert_eq!(keys(&atlas), expected);
+    }
+
+    #[test]
+    fn subpixel_offsets_are_rounded_to_quarter_pixels() {
+        let mut fonts = FontRegistry::default();
+        let mut atlas = GlyphAtlas::new();
+        let h = glyph(&mut fonts, &Font::default(), "H", 16.0);
+        let mut draw = |x| draw_glyph(&mut atlas, &mut fonts, &h, x).into_vec();
+
+        assert_eq!(draw(0.1), draw(0.0));
+        assert_eq!(draw(0.4), draw(0.5));
+        assert_ne!(draw(0.25), draw(0.0));
+
+        // offsets close to the next pixel round up to it
+        assert_eq!(draw(0.9), draw(1.0));
+        assert_eq!(keys(&atlas).len(), 3);
+    }
+
+    #[test]
+    fn glyphs_snap_to_whole_pixels_without_subpixel_positioning() {
+        let mut fonts = FontRegistry::default();
+        let rendering = TextRendering::default().with_subpixel_positioning(false);
+        let mut atlas = GlyphAtlas::new().with_rendering(rendering);
+        let h = glyph(&mut fonts, &Font::default(), "H", 16.0);
+        let mut draw = |x| draw_glyph(&mut atlas, &mut fonts, &h, x).into_vec();
+
+        assert_eq!(draw(0.3), draw(0.0));
+        assert_eq!(draw(0.6), draw(1.0));
+        assert_eq!(keys(&atlas).len(), 1);
+    }
+
+    #[test]
+    fn blit_blends_over_pixels_within_the_clip() {
+        let mut dt = DrawTarget::new(4, 4);
+        let red = SolidSource::from_unpremultiplied_argb(255, 255, 0, 0);
+        let mask = Mask {
+            width: 2,
+            height: 2,
+            data: vec![255, 128, 64, 0],
+        };
+
+        blit(&mut dt, red, IntPoint::new(1, 1), &mask, None);
+        assert_eq!(dt.get_data()[5], 0xffff0000);
+        assert_eq!(dt.get_data()[6], 0x80800000);
+        assert_eq!(dt.get_data()[9], 0x40400000);
+        assert_eq!(dt.get_data()[10], 0);
+
+        // only the top-right pixel of the mask is inside the clip
+        let clip = IntRect::new(IntPoint::new(2, 0), IntPoint::new(4, 2));
+        blit(&mut dt, red, IntPoint::new(1, 1), &mask, Some(clip));
+        assert_eq!(dt.get_data()[5], 0xffff0000);
+        assert_eq!(dt.get_data()[6], 0xc0c00000);
+        assert_eq!(dt.get_data()[9], 0x40400000);
+
+        // masks hanging off the target's edges are cropped to it
+        blit(&mut dt, red, IntPoint::new(-1, 3), &mask, None);
+        assert_eq!(dt.get_data()[12], 0x80800000);
+        blit(&mut dt, red, IntPoint::new(3, -1), &mask, None);
+        assert_eq!(dt.get_data()[3], 0x40400000);
+    }
+
+    #[test]
+    fn masks_match_filled_outlines() {
+        // at whole-pixel positions, masks are filled exactly like paths
+        let size = 32.0;
+        let mut atlas = GlyphAtlas::new();
+        let masked = draw_text("H", size, Some(&mut atlas));
+        let filled = draw_text("H", size, None);
+        assert!(!atlas.masks.is_empty());
+
+        let difference = |a: &DrawTarget, b: &DrawTarget| {
+            let pairs = a.get_data().iter().zip(b.get_data());
+            pairs
+                .map(|(a, b)| ((a >> 24) as i32 - (b >> 24) as i32).unsigned_abs())
+                .max()
+                .unwrap()
+        };
+
+        assert!(coverage(&filled) > 0);
+        assert!(difference(&masked, &filled) <= 1);
+
+        // elsewhere, glyphs are only moved and resized by up to an eighth
+        // of a pixel
+        let text = "Hamburgefonstiv";
+        let mut atlas = GlyphAtlas::new();
+        let masked = draw_text(text, 13.3, Some(&mut atlas));
+        let filled = draw_text(text, 13.3, None);
+        let (masked, filled) = (coverage(&masked) as f32, coverage(&filled) as f32);
+        assert!((masked / filled - 1.0).abs() < 0.02);
+    }
+}
@@ -18,38 +18,94 @@ use std::f32::consts::TAU;
 use euclid::{Angle, Size2D};
 use raqote::*;
 use stackblur_iter::imgref::ImgRefMut;
-use willow_server::{glam::Vec2, Aabb, Operation, Shape, WalkTree};
+use willow_server::{
+    glam::Vec2,
+    path,
+    resource::{self, Resources},
+    Aabb, FillRule, ImageSampling, Operation, OutlineStyle, PathCommand, Shape, TextGlyphs,
+    WalkTree,
+};
+use willow_text::FontRegistry;
 
-mod text;
+pub mod atlas;
+pub mod text;
+
+use atlas::GlyphAtlas;
+
+/// The region of an [Operation::Clip] in device space.
+enum DeviceClip {
+    /// A rectangle of whole pixels, which is clipped to without a mask.
+    Rect(IntRect),
+
+    /// Any other region, which is rasterized into a mask for every shape
+    /// drawn inside of it.
+    Path(Path),
+}
+
+/// An offscreen target that an operation's children are drawn into.
+struct Layer {
+    /// A target the size of `bounds`.
+    target: DrawTarget,
+
+    /// The pixels that the operation's node covers inside of what's visible,
+    /// which are the only ones the layer is drawn into and composited onto.
+    /// Layers with empty bounds draw nothing.
+    bounds: IntRect,
+}
 
 pub struct RaqoteRenderer<'a, Backing> {
     dt: &'a mut DrawTarget<Backing>,
-    blur_stack: Vec<DrawTarget>,
+    /// Offscreen targets that shapes are drawn into instead of `dt`, for
+    /// operations that are applied to their children together.
+    layer_stack: Vec<Layer>,
     stroke_stack: Vec<Source<'static>>,
+    outline_stack: Vec<StrokeStyle>,
     transform_stack: Vec<Transform>,
-    default_font: text::FontData,
+    clip_stack: Vec<DeviceClip>,
+    fonts: &'a mut FontRegistry,
+    atlas: Option<&'a mut GlyphAtlas>,
+    resources: Option<&'a Resources>,
 }
 
 impl<'a, Backing> WalkTree for RaqoteRenderer<'a, Backing>
 where
     Backing: AsRef<[u32]> + AsMut<[u32]>,
 {
-    fn on_shape(&mut self, shape: &Shape) {
+    fn on_shape(&mut self, shape: &Shape, text: Option<&TextGlyphs>) {
         let source = self.stroke_stack.last().unwrap();
+        let outline = self.outline_stack.last();
         let options = DrawOptions::new();
 
-        let width = self.dt.width();
-        let height = self.dt.height();
+        if self
+            .layer_stack
+            .last()
+            .is_some_and(|layer| layer.bounds.is_empty())
+        {
+            return;
+        }
 
-        let backing = match self.blur_stack.last_mut() {
-            Some(blurred) => blurred.get_data_mut(),
-            None => self.dt.get_data_mut(),
-        };
+        let (width, height, origin, backing) = current_target(self.dt, &mut self.layer_stack);
 
+        // clips are in device space, so they're pushed before the transform
+        // is set
         let mut dt = DrawTarget::from_backing(width, height, backing);
+        let Some((clip_rect, masked)) = push_clips(&mut dt, &self.clip_stack, origin) else {
+            return;
+        };
 
-        let current_transform = self.transform_stack.last().unwrap().clone();
-        dt.set_transform(&current_transform);
+        // the atlas can only clip its glyphs to rectangles
+        let mut atlas = match masked {
+            true => None,
+            false => self.atlas.as_deref_mut(),
+        };
+
+        if let Some(atlas) = atlas.as_deref_mut() {
+            atlas.set_clip(clip_rect);
+        }
+
+        let current_transform = *self.transform_stack.last().unwrap();
+        let offset = euclid::vec2(-origin.x as f32, -origin.y as f32);
+        dt.set_transform(&current_transform.then_translate(offset));
 
         use Shape::*;
         match shape {
@@ -61,98 +117,178 @@ where
 
                 let path = pb.finish();
 
-                dt.fill(&path, &source, &options);
+                draw_path(&mut dt, &path, source, outline, &options);
+            }
+            Ellipse { radii } => {
+                let mut pb = PathBuilder::new();
+                pb.arc(0., 0., 1., 0., TAU);
+                pb.close();
+
+                let path = pb.finish().transform(&Transform::scale(radii.x, radii.y));
+                draw_path(&mut dt, &path, source, outline, &options);
+            }
+            Arc {
+                radius,
+                inner_radius,
+                start_angle,
+                sweep_angle,
+            } => {
+                let commands =
+                    path::arc_commands(*radius, *inner_radius, *start_angle, *sweep_angle);
+                let path = build_path(&commands, FillRule::NonZero);
+                draw_path(&mut dt, &path, source, outline, &options);
+            }
+            Polygon { points, fill_rule } => {
+                let path = build_path(&path::polygon_commands(points, true), *fill_rule);
+                draw_path(&mut dt, &path, source, outline, &options);
+            }
+            Polyline { points } => {
+                let commands = path::polygon_commands(points, false);
+                let path = build_path(&commands, FillRule::NonZero);
+                draw_path(&mut dt, &path, source, outline, &options);
             }
             Rectangle { min, max } => {
                 let size = *max - *min;
-                dt.fill_rect(min.x, min.y, size.x, size.y, &source, &options);
+                match outline {
+                    None => dt.fill_rect(min.x, min.y, size.x, size.y, source, &options),
+                    Some(style) => {
+                        let mut pb = PathBuilder::new();
+                        pb.rect(min.x, min.y, size.x, size.y);
+                        dt.stroke(&pb.finish(), source, style, &options);
+                    }
+                }
             }
             RoundedRectangle { min, max, radii } => {
-                let aabb = Aabb {
-                    min: *min,
-                    max: *max,
+                let commands = path::rounded_rectangle_commands(*min, *max, *radii);
+                let path = build_path(&commands, FillRule::NonZero);
+                draw_path(&mut dt, &path, source, outline, &options);
+            }
+            // text is drawn as it was laid out for its node's bounds
+            Text { .. } | Paragraph { .. } => {
+                let Some(text) = text else {
+                    return;
                 };
 
-                let get_offsets = |corner_idx| match corner_idx {
-                    0 => (Vec2::Y, Vec2::X),
-                    1 => (-Vec2::X, Vec2::Y),
-                    2 => (-Vec2::Y, -Vec2::X),
-                    3 => (Vec2::X, -Vec2::Y),
-                    _ => unreachable!(),
+                text::draw_glyphs(&mut dt, self.fonts, atlas, &text.glyphs, source, &options);
+            }
+            RichText { spans, .. } => {
+                let Some(text) = text else {
+                    return;
                 };
 
-                let mut pb = PathBuilder::new();
+                let sources: Vec<_> = spans
+                    .iter()
+                    .map(|span| match span.color {
+                        Some(color) => solid_source(color),
+                        None => source.clone(),
+                    })
+                    .collect();
 
-                let first_corner = *min + get_offsets(3).1 * radii.x;
-                pb.move_to(first_corner.x, first_corner.y);
+                let backgrounds: Vec<_> = spans
+                    .iter()
+                    .map(|span| span.background.map(solid_source))
+                    .collect();
 
-                // approximate quarter circle control point offset
-                let control_offset = 0.446;
+                text::draw_rich(
+                    &mut dt,
+                    self.fonts,
+                    atlas,
+                    &text.glyphs,
+                    &text.decorations,
+                    &sources,
+                    &backgrounds,
+                    &options,
+                );
+            }
+            Path {
+                commands,
+                fill_rule,
+            } => {
+                let path = build_path(commands, *fill_rule);
+                draw_path(&mut dt, &path, source, outline, &options);
+            }
+            Image {
+                resource,
+                min,
+                max,
+                fit,
+                sampling,
+            } => {
+                let Some(image) = self.resources.and_then(|res| res.get_image(resource)) else {
+                    return;
+                };
 
-                let corners = aabb.corners();
-                for (idx, corner) in corners.iter().copied().enumerate() {
-                    let (loff, roff) = get_offsets(idx);
+                if image.width == 0 || image.height == 0 {
+                    return;
+                }
 
-                    let radius = match idx {
-                        0 => radii.x,
-                        1 => radii.y,
-                        2 => radii.z,
-                        3 => radii.w,
-                        _ => unreachable!(),
-                    };
+                let size = image.size();
+                let image = raqote::Image {
+                    width: image.width as i32,
+                    height: image.height as i32,
+                    data: &image.pixels,
+                };
 
-                    let control_offset = control_offset * radius;
-                    let start = corner + loff * radius;
-                    let c1 = corner + loff * control_offset;
-                    let c2 = corner + roff * control_offset;
-                    let pt = corner + roff * radius;
+                let placed = resource::fit_image(*fit, size, *min, *max);
+                let placed_size = placed.max - placed.min;
+                let to_image = Transform::translation(-placed.min.x, -placed.min.y)
+                    .then_scale(size.x / placed_size.x, size.y / placed_size.y);
 
-                    pb.line_to(start.x, start.y);
-                    pb.cubic_to(c1.x, c1.y, c2.x, c2.y, pt.x, pt.y);
-                }
+                let filter = match sampling {
+                    ImageSampling::Linear => FilterMode::Bilinear,
+                    ImageSampling::Nearest => FilterMode::Nearest,
+                };
 
-                pb.close();
+                let source = Source::Image(image, ExtendMode::Pad, filter, to_image);
 
-                let path = pb.finish();
-                dt.fill(&path, &source, &options);
-            }
-            Text { content, .. } => {
-                self.default_font.draw(&mut dt, content, &source, &options);
+                // clip the image to its rectangle
+                let min = min.max(placed.min);
+                let max = max.min(placed.max);
+                let size = max - min;
+                if size.x > 0.0 && size.y > 0.0 {
+                    dt.fill_rect(min.x, min.y, size.x, size.y, &source, &options);
+                }
             }
         }
     }
 
-    fn push_operation(&mut self, operation: &Operation) {
-        let current_transform = self.transform_stack.last().unwrap().clone();
+    fn push_operation(&mut self, operation: &Operation, aabb: &Aabb) {
+        let current_transform = *self.transform_stack.last().unwrap();
 
         use Operation::*;
         match operation {
-            Stroke(stroke) => match stroke {
-                willow_server::Stroke::Solid { color } => {
-                    let color = (*color * 255.0).as_uvec3();
-                    let (r, g, b) = (color.x as u8, color.y as u8, color.z as u8);
-                    let a = 255;
-                    let source = SolidSource { r, g, b, a };
-                    self.stroke_stack.push(Source::Solid(source));
-                }
-            },
+            Stroke(stroke) => self.stroke_stack.push(stroke_source(stroke)),
+            Outline(style) => self.outline_stack.push(stroke_style(style)),
             Translate { offset } => {
-                let translate = Transform::translation(offset.x, offset.y);
+                let translate = raqote::Transform::translation(offset.x, offset.y);
                 self.transform_stack
                     .push(translate.then(&current_transform));
             }
             Rotation { angle } => {
-                let rotation = Transform::rotation(Angle { radians: *angle });
+                let rotation = raqote::Transform::rotation(Angle { radians: *angle });
                 self.transform_stack.push(rotation.then(&current_transform));
             }
             Scale { scale } => {
-                let scale = Transform::scale(*scale, *scale);
+                let scale = raqote::Transform::scale(*scale, *scale);
                 self.transform_stack.push(scale.then(&current_transform));
             }
+            Transform { transform } => {
+                let x = transform.matrix2.x_axis;
+                let y = transform.matrix2.y_axis;
+                let offset = transform.translation;
+                let transform = raqote::Transform::new(x.x, x.y, y.x, y.y, offset.x, offset.y);
+                self.transform_stack
+                    .push(transform.then(&current_transform));
+            }
             Opacity { opacity } => self.dt.push_layer(*opacity),
-            Blur { .. } => self
-                .blur_stack
-                .push(DrawTarget::new(self.dt.width(), self.dt.height())),
+            Blur { .. } | Blend(_) => {
+                let bounds = self.layer_bounds(aabb);
+                self.layer_stack.push(Layer {
+                    target: DrawTarget::new(bounds.width(), bounds.height()),
+                    bounds,
+                });
+            }
+            Clip(clip) => self.clip_stack.push(device_clip(clip, &current_transform)),
         }
     }
 
@@ -163,22 +299,28 @@ where
             Stroke(_) => {
                 self.stroke_stack.pop();
             }
-            Translate { .. } | Rotation { .. } | Scale { .. } => {
+            Outline(_) => {
+                self.outline_stack.pop();
+            }
+            Translate { .. } | Rotation { .. } | Scale { .. } | Transform { .. } => {
                 self.transform_stack.pop();
             }
             Opacity { .. } => self.dt.pop_layer(),
             Blur { radius } => {
-                let mut blur_target = self.blur_stack.pop().unwrap();
-                let width = blur_target.width();
-                let height = blur_target.height();
-                let buffer = blur_target.get_data_mut();
+                let mut layer = self.layer_stack.pop().unwrap();
+                let width = layer.target.width();
+                let height = layer.target.height();
+                let buffer = layer.target.get_data_mut();
                 let mut img = ImgRefMut::new(buffer, width as usize, height as usize);
                 stackblur_iter::blur_srgb(&mut img, *radius as usize);
-                let size = Size2D::new(width, height);
-                let src_rect = IntRect::from_size(size);
-                let dst = IntPoint::zero();
-                let blend = raqote::BlendMode::SrcOver;
-                self.dt.blend_surface(&blur_target, src_rect, dst, blend);
+                self.composite_layer(&layer, raqote::BlendMode::SrcOver);
+            }
+            Blend(mode) => {
+                let layer = self.layer_stack.pop().unwrap();
+                self.composite_layer(&layer, blend_mode(*mode));
+            }
+            Clip(_) => {
+                self.clip_stack.pop();
             }
         }
     }
@@ -207,14 +349,356 @@ where
         pb.rect(aabb.min.x, aabb.min.y, size.x, size.y);
         let path = pb.finish();
 
-        let current_transform = self.transform_stack.last().unwrap().clone();
+        let current_transform = *self.transform_stack.last().unwrap();
         self.dt.set_transform(&current_transform);
         self.dt.stroke(&path, &source, &style, &options);
     }
 }
 
+/// Fills a path, or draws its outline if the renderer is inside an
+/// [Operation::Outline].
+fn draw_path<Backing>(
+    dt: &mut DrawTarget<Backing>,
+    path: &Path,
+    source: &Source,
+    outline: Option<&StrokeStyle>,
+    options: &DrawOptions,
+) where
+    Backing: AsRef<[u32]> + AsMut<[u32]>,
+{
+    match outline {
+        Some(style) => dt.stroke(path, source, style, options),
+        None => dt.fill(path, source, options),
+    }
+}
+
+/// Converts the style of an [Operation::Outline] into a raqote [StrokeStyle].
+fn stroke_style(style: &OutlineStyle) -> StrokeStyle {
+    StrokeStyle {
+        width: style.width,
+        cap: match style.cap {
+            willow_server::LineCap::Butt => LineCap::Butt,
+            willow_server::LineCap::Round => LineCap::Round,
+            willow_server::LineCap::Square => LineCap::Square,
+        },
+        join: match style.join {
+            willow_server::LineJoin::Miter => LineJoin::Miter,
+            willow_server::LineJoin::Round => LineJoin::Round,
+            willow_server::LineJoin::Bevel => LineJoin::Bevel,
+        },
+        miter_limit: style.miter_limit,
+        dash_array: style.dash_array.clone(),
+        dash_offset: style.dash_offset,
+    }
+}
+
+/// Converts a [willow_server::BlendMode] into raqote's blend mode.
+fn blend_mode(mode: willow_server::BlendMode) -> raqote::BlendMode {
+    use willow_server::BlendMode::*;
+    match mode {
+        Clear => raqote::BlendMode::Clear,
+        Source => raqote::BlendMode::Src,
+        Destination => raqote::BlendMode::Dst,
+        SourceOver => raqote::BlendMode::SrcOver,
+        DestinationOver => raqote::BlendMode::DstOver,
+        SourceIn => raqote::BlendMode::SrcIn,
+        DestinationIn => raqote::BlendMode::DstIn,
+        SourceOut => raqote::BlendMode::SrcOut,
+        DestinationOut => raqote::BlendMode::DstOut,
+        SourceAtop => raqote::BlendMode::SrcAtop,
+        DestinationAtop => raqote::BlendMode::DstAtop,
+        Xor => raqote::BlendMode::Xor,
+        Add => raqote::BlendMode::Add,
+        Multiply => raqote::BlendMode::Multiply,
+        Screen => raqote::BlendMode::Screen,
+        Overlay => raqote::BlendMode::Overlay,
+        Darken => raqote::BlendMode::Darken,
+        Lighten => raqote::BlendMode::Lighten,
+        ColorDodge => raqote::BlendMode::ColorDodge,
+        ColorBurn => raqote::BlendMode::ColorBurn,
+        HardLight => raqote::BlendMode::HardLight,
+        SoftLight => raqote::BlendMode::SoftLight,
+        Difference => raqote::BlendMode::Difference,
+        Exclusion => raqote::BlendMode::Exclusion,
+    }
+}
+
+/// Gets the pixels that shapes are currently drawn into, which are those of
+/// the top layer, or of `dt` if there are no layers, along with their size
+/// and the device position of their top-left corner.
+fn current_target<'a, Backing>(
+    dt: &'a mut DrawTarget<Backing>,
+    layers: &'a mut [Layer],
+) -> (i32, i32, IntPoint, &'a mut [u32])
+where
+    Backing: AsRef<[u32]> + AsMut<[u32]>,
+{
+    match layers.last_mut() {
+        Some(layer) => (
+            layer.target.width(),
+            layer.target.height(),
+            layer.bounds.min,
+            layer.target.get_data_mut(),
+        ),
+        None => (dt.width(), dt.height(), IntPoint::zero(), dt.get_data_mut()),
+    }
+}
+
+/// Finds the intersection of the rectangular clips in device space.
+fn clip_rect(clips: &[DeviceClip]) -> Option<IntRect> {
+    clips
+        .iter()
+        .filter_map(|clip| match clip {
+            DeviceClip::Rect(rect) => Some(*rect),
+            DeviceClip::Path(_) => None,
+        })
+        .reduce(|a, b| a.intersection_unchecked(&b))
+}
+
+/// Pushes device-space clips onto a target whose top-left corner is at
+/// `origin`, returning the intersection of the rectangular clips in the
+/// target's space and whether any clip was masked, or nothing if the clips
+/// are empty.
+fn push_clips<Backing>(
+    dt: &mut DrawTarget<Backing>,
+    clips: &[DeviceClip],
+    origin: IntPoint,
+) -> Option<(Option<IntRect>, bool)>
+where
+    Backing: AsRef<[u32]> + AsMut<[u32]>,
+{
+    let clip_rect = clip_rect(clips).map(|rect| rect.translate(-origin.to_vector()));
+    if clip_rect.is_some_and(|rect| rect.is_empty()) {
+        return None;
+    }
+
+    // rectangles are pushed first, because raqote drops the masks of earlier
+    // clips when pushing a rectangle
+    if let Some(rect) = clip_rect {
+        dt.push_clip_rect(rect);
+    }
+
+    let offset = Transform::translation(-origin.x as f32, -origin.y as f32);
+    let mut masked = false;
+    for clip in clips.iter() {
+        if let DeviceClip::Path(path) = clip {
+            match origin == IntPoint::zero() {
+                true => dt.push_clip(path),
+                false => dt.push_clip(&path.clone().transform(&offset)),
+            }
+
+            masked = true;
+        }
+    }
+
+    Some((clip_rect, masked))
+}
+
+/// Converts the region of an [Operation::Clip] into device space.
+fn device_clip(clip: &willow_server::Clip, transform: &Transform) -> DeviceClip {
+    use willow_server::Clip;
+
+    let fill_rule = match clip {
+        Clip::Rectangle { min, max } if transform.m12 == 0.0 && transform.m21 == 0.0 => {
+            let min = transform.transform_point(Point::new(min.x, min.y));
+            let max = transform.transform_point(Point::new(max.x, max.y));
+            let rect = euclid::default::Box2D::from_points([min, max]);
+            return DeviceClip::Rect(rect.round().to_i32());
+        }
+        Clip::Path { fill_rule, .. } => *fill_rule,
+        _ => FillRule::NonZero,
+    };
+
+    let path = build_path(&path::clip_commands(clip), fill_rule);
+    DeviceClip::Path(path.transform(transform))
+}
+
+/// Converts the commands of a [Shape::Path] into a raqote [Path].
+fn build_path(commands: &[PathCommand], fill_rule: FillRule) -> Path {
+    let mut pb = PathBuilder::new();
+    let mut start = Vec2::ZERO;
+
+    // raqote has no current point before the first move or after closing
+    let mut moved = false;
+
+    for command in commands.iter() {
+        if !moved && !matches!(command, PathCommand::MoveTo(_)) {
+            pb.move_to(start.x, start.y);
+            moved = true;
+        }
+
+        match *command {
+            PathCommand::MoveTo(to) => {
+                pb.move_to(to.x, to.y);
+                start = to;
+                moved = true;
+            }
+            PathCommand::LineTo(to) => pb.line_to(to.x, to.y),
+            PathCommand::QuadTo { ctrl, to } => pb.quad_to(ctrl.x, ctrl.y, to.x, to.y),
+            PathCommand::CubicTo { ctrl1, ctrl2, to } => {
+                pb.cubic_to(ctrl1.x, ctrl1.y, ctrl2.x, ctrl2.y, to.x, to.y)
+            }
+            PathCommand::Arc {
+                center,
+                radius,
+                start_angle,
+                sweep_angle,
+            } => pb.arc(center.x, center.y, radius, start_angle, sweep_angle),
+            PathCommand::Close => {
+                pb.close();
+                moved = false;
+            }
+        }
+    }
+
+    let mut path = pb.finish();
+    path.winding = match fill_rule {
+        FillRule::NonZero => Winding::NonZero,
+        FillRule::EvenOdd => Winding::EvenOdd,
+    };
+
+    path
+}
+
+/// Creates a solid source from a color.
+fn solid_source(color: willow_server::Color) -> Source<'static> {
+    let [a, r, g, b] = argb_bytes(color);
+    Source::Solid(SolidSource::from_unpremultiplied_argb(a, r, g, b))
+}
+
+/// Converts a color to sRGB bytes with straight alpha.
+fn argb_bytes(color: willow_server::Color) -> [u8; 4] {
+    let color = color.to_srgb();
+    [color.alpha, color.red, color.green, color.blue]
+        .map(|component| (component.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+/// Creates the source that a [willow_server::Stroke] paints shapes with.
+///
+/// Gradient sources are positioned in the user space of the draw target when
+/// they're drawn with, which is the local space of the shape being drawn.
+///
+/// raqote panics on gradients with no length, radius, or sweep, so those are
+/// drawn with the color of their last stop instead.
+fn stroke_source(stroke: &willow_server::Stroke) -> Source<'static> {
+    use willow_server::Stroke::*;
+    match stroke {
+        Solid { color } => solid_source(*color),
+        LinearGradient {
+            start,
+            end,
+            gradient,
+        } if start == end || !start.is_finite() || !end.is_finite() => last_stop_source(gradient),
+        RadialGradient {
+            center,
+            radius,
+            gradient,
+        } if !(radius.is_finite() && *radius > 0.0 && center.is_finite()) => {
+            last_stop_source(gradient)
+        }
+        SweepGradient {
+            center,
+            start_angle,
+            end_angle,
+            gradient,
+        } if start_angle == end_angle
+            || !start_angle.is_finite()
+            || !end_angle.is_finite()
+            || !center.is_finite() =>
+        {
+            last_stop_source(gradient)
+        }
+        LinearGradient {
+            start,
+            end,
+            gradient,
+        } => {
+            let (gradient, spread) = convert_gradient(gradient);
+            let start = Point::new(start.x, start.y);
+            let end = Point::new(end.x, end.y);
+            Source::new_linear_gradient(gradient, start, end, spread)
+        }
+        RadialGradient {
+            center,
+            radius,
+            gradient,
+        } => {
+            let (gradient, spread) = convert_gradient(gradient);
+            let center = Point::new(center.x, center.y);
+            Source::new_radial_gradient(gradient, center, *radius, spread)
+        }
+        SweepGradient {
+            center,
+            start_angle,
+            end_angle,
+            gradient,
+        } => {
+            let (gradient, spread) = convert_gradient(gradient);
+            let center = Point::new(center.x, center.y);
+
+            // raqote measures sweeps in degrees
+            let start_angle = start_angle.to_degrees();
+            let end_angle = end_angle.to_degrees();
+            Source::new_sweep_gradient(gradient, center, start_angle, end_angle, spread)
+        }
+    }
+}
+
+/// Creates a solid source with the color of a gradient's last stop, or a
+/// transparent one if it has no stops.
+fn last_stop_source(gradient: &willow_server::Gradient) -> Source<'static> {
+    let last = gradient
+        .stops
+        .iter()
+        .max_by(|a, b| a.offset.total_cmp(&b.offset));
+
+    match last {
+        Some(stop) => solid_source(stop.color),
+        None => Source::Solid(SolidSource::from_unpremultiplied_argb(0, 0, 0, 0)),
+    }
+}
+
+/// Converts a [willow_server::Gradient] into raqote's gradient and spread.
+fn convert_gradient(gradient: &willow_server::Gradient) -> (Gradient, Spread) {
+    let mut stops: Vec<_> = gradient
+        .stops
+        .iter()
+        .map(|stop| {
+            // raqote interpolates between premultiplied colors
+            let [a, r, g, b] = argb_bytes(stop.color);
+            let color = SolidSource::from_unpremultiplied_argb(a, r, g, b);
+            GradientStop {
+                position: stop.offset,
+                color: Color::new(color.a, color.r, color.g, color.b),
+            }
+        })
+        .collect();
+
+    stops.sort_by(|a, b| a.position.total_cmp(&b.position));
+
+    // raqote panics on gradients without stops, so make them transparent
+    if stops.is_empty() {
+        stops.push(GradientStop {
+            position: 0.0,
+            color: Color::new(0, 0, 0, 0),
+        });
+    }
+
+    let spread = match gradient.spread {
+        willow_server::Spread::Pad => Spread::Pad,
+        willow_server::Spread::Repeat => Spread::Repeat,
+        willow_server::Spread::Reflect => Spread::Reflect,
+    };
+
+    (Gradient { stops }, spread)
+}
+
 impl<'a, Backing> RaqoteRenderer<'a, Backing> {
-    pub fn new(dt: &'a mut DrawTarget<Backing>) -> Self {
+    /// Creates a renderer drawing into `dt`.
+    ///
+    /// Text is drawn with the fonts in `fonts`, which are borrowed so that
+    /// their glyph and shaping caches outlive individual frames.
+    pub fn new(dt: &'a mut DrawTarget<Backing>, fonts: &'a mut FontRegistry) -> Self {
         let default_stroke = Source::Solid(SolidSource {
             r: 0xff,
             g: 0x00,
@@ -224,15 +708,406 @@ impl<'a, Backing> RaqoteRenderer<'a, Backing> {
 
         Self {
             dt,
-            blur_stack: Vec::new(),
+            layer_stack: Vec::new(),
             stroke_stack: vec![default_stroke],
+            outline_stack: Vec::new(),
             transform_stack: vec![Transform::identity()],
-            default_font: text::FontData::load(
-                allsorts::tag::LATN,
-                allsorts::glyph_position::TextDirection::LeftToRight,
-                false,
-                notosans::REGULAR_TTF.to_vec(),
-            ),
+            clip_stack: Vec::new(),
+            fonts,
+            atlas: None,
+            resources: None,
+        }
+    }
+
+    /// Finds the bounds of a layer for an operation node with the given
+    /// bounds in device space.
+    ///
+    /// This is the part of the node that's inside of the target, the layer
+    /// beneath it, and the rectangular clips, or an empty rectangle if none
+    /// of it is.
+    fn layer_bounds(&self, aabb: &Aabb) -> IntRect
+    where
+        Backing: AsRef<[u32]> + AsMut<[u32]>,
+    {
+        let size = Size2D::new(self.dt.width(), self.dt.height());
+        let mut visible = IntRect::from_size(size);
+
+        if let Some(below) = self.layer_stack.last() {
+            visible = visible.intersection_unchecked(&below.bounds);
+        }
+
+        if let Some(clip) = clip_rect(&self.clip_stack) {
+            visible = visible.intersection_unchecked(&clip);
+        }
+
+        // the node is clamped in floating point, because it may be far
+        // outside of the range of pixel coordinates
+        let min = Point::new(aabb.min.x, aabb.min.y);
+        let max = Point::new(aabb.max.x, aabb.max.y);
+        let bounds = euclid::default::Box2D::new(min, max)
+            .intersection_unchecked(&visible.to_f32())
+            .round_out();
+
+        match bounds.try_cast() {
+            Some(bounds) if !bounds.is_empty() => bounds,
+            _ => IntRect::zero(),
+        }
+    }
+
+    /// Composites a popped layer onto the layer beneath it, or onto the
+    /// target if it was the last one.
+    ///
+    /// Only the layer's bounds inside of the current clips are composited,
+    /// so that blend modes that change the destination where the layer is
+    /// transparent don't reach past its node or the clips it's drawn in.
+    fn composite_layer(&mut self, layer: &Layer, blend: raqote::BlendMode)
+    where
+        Backing: AsRef<[u32]> + AsMut<[u32]>,
+    {
+        if layer.bounds.is_empty() {
+            return;
+        }
+
+        let (width, height, origin, backing) = current_target(self.dt, &mut self.layer_stack);
+        let mut dt = DrawTarget::from_backing(width, height, backing);
+        if push_clips(&mut dt, &self.clip_stack, origin).is_none() {
+            return;
+        }
+
+        // the layer's bounds are already inside of the rectangular clips
+        let bounds = layer.bounds.translate(-origin.to_vector());
+
+        let image = Image {
+            width: layer.target.width(),
+            height: layer.target.height(),
+            data: layer.target.get_data(),
+        };
+
+        let to_layer = Transform::translation(-bounds.min.x as f32, -bounds.min.y as f32);
+        let source = Source::Image(image, ExtendMode::Pad, FilterMode::Nearest, to_layer);
+
+        let options = DrawOptions {
+            blend_mode: blend,
+            ..Default::default()
+        };
+
+        // fill_rect() blends whole rows of the target when it isn't clipped,
+        // so the bounds are filled as a path instead
+        let min = bounds.min.to_f32();
+        let size = bounds.size().to_f32();
+        let mut pb = PathBuilder::new();
+        pb.rect(min.x, min.y, size.width, size.height);
+        dt.fill(&pb.finish(), &source, &options);
+    }
+
+    /// Draws small glyphs from `atlas`, which is borrowed so that it can be
+    /// kept between frames.
+    pub fn with_atlas(mut self, atlas: &'a mut GlyphAtlas) -> Self {
+        self.atlas = Some(atlas);
+        self
+    }
+
+    /// Draws image shapes from `resources`. Without resources, image shapes
+    /// draw nothing.
+    pub fn with_resources(mut self, resources: &'a Resources) -> Self {
+        self.resources = Some(resources);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use willow_server::{glam::vec2, Clip, Color, ColorSpace, NewNode, NodeUpdate, Stroke, Tree};
+
+    const SIZE: i32 = 16;
+    const WHITE: u32 = 0xffffffff;
+    const RED: u32 = 0xffff0000;
+
+    /// Renders nodes over a white background.
+    fn render(nodes: Vec<NewNode>) -> DrawTarget {
+        let mut tree = Tree::new();
+        let background = Shape::Rectangle {
+            min: Vec2::ZERO,
+            max: Vec2::splat(SIZE as f32),
+        };
+
+        let mut children = vec![fill(Color::WHITE, background)];
+        children.extend(nodes);
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: children.into(),
+        })
+        .unwrap();
+
+        let mut dt = DrawTarget::new(SIZE, SIZE);
+        let mut fonts = FontRegistry::default();
+        let mut renderer = RaqoteRenderer::new(&mut dt, &mut fonts);
+        let aabb = Aabb {
+            min: Vec2::ZERO,
+            max: Vec2::splat(SIZE as f32),
+        };
+
+        tree.walk(&mut renderer, &aabb);
+        dt
+    }
+
+    fn pixel(dt: &DrawTarget, x: i32, y: i32) -> u32 {
+        dt.get_data()[(y * SIZE + x) as usize]
+    }
+
+    fn fill(color: Color, shape: Shape) -> NewNode {
+        NewNode::Operation {
+            operation: Operation::Stroke(Stroke::Solid { color }),
+            child: NewNode::Shape(shape).into(),
+        }
+    }
+
+    fn blend(mode: willow_server::BlendMode, child: NewNode) -> NewNode {
+        NewNode::Operation {
+            operation: Operation::Blend(mode),
+            child: child.into(),
+        }
+    }
+
+    fn gradient(offsets: &[f32]) -> willow_server::Gradient {
+        let stops = offsets
+            .iter()
+            .map(|offset| willow_server::GradientStop {
+                offset: *offset,
+                color: Color::rgb(*offset, 0.0, 0.0),
+            })
+            .collect();
+
+        willow_server::Gradient {
+            stops,
+            spread: willow_server::Spread::Pad,
         }
     }
+
+    /// A red circle filling the square from 4 to 12, whose corners are
+    /// transparent.
+    fn red_circle() -> NewNode {
+        NewNode::Operation {
+            operation: Operation::Translate {
+                offset: Vec2::splat(8.0),
+            },
+            child: fill(Color::rgb(1.0, 0.0, 0.0), Shape::Circle { radius: 4.0 }).into(),
+        }
+    }
+
+    #[test]
+    fn multiply_blends_inside_node_bounds() {
+        let dt = render(vec![blend(
+            willow_server::BlendMode::Multiply,
+            red_circle(),
+        )]);
+        assert_eq!(pixel(&dt, 8, 8), RED);
+        assert_eq!(pixel(&dt, 4, 4), WHITE);
+        assert_eq!(pixel(&dt, 1, 1), WHITE);
+    }
+
+    #[test]
+    fn source_in_only_replaces_node_bounds() {
+        let dt = render(vec![blend(
+            willow_server::BlendMode::SourceIn,
+            red_circle(),
+        )]);
+        assert_eq!(pixel(&dt, 8, 8), RED);
+
+        // the transparent corners of the circle's bounds are cleared, but
+        // nothing outside of them is
+        assert_eq!(pixel(&dt, 4, 4), 0);
+        assert_eq!(pixel(&dt, 1, 1), WHITE);
+        assert_eq!(pixel(&dt, 14, 8), WHITE);
+    }
+
+    #[test]
+    fn source_in_stays_inside_clips() {
+        let clip = NewNode::Operation {
+            operation: Operation::Clip(Clip::Rectangle {
+                min: Vec2::ZERO,
+                max: vec2(8.0, SIZE as f32),
+            }),
+            child: blend(willow_server::BlendMode::SourceIn, red_circle()).into(),
+        };
+
+        let dt = render(vec![clip]);
+        assert_eq!(pixel(&dt, 6, 8), RED);
+        assert_eq!(pixel(&dt, 4, 4), 0);
+        assert_eq!(pixel(&dt, 10, 8), WHITE);
+        assert_eq!(pixel(&dt, 11, 4), WHITE);
+    }
+
+    #[test]
+    fn huge_layers_are_clamped_to_the_target() {
+        // raqote can't rasterize edges across the target this far out, so
+        // the node is made huge by dots far above and below it
+        let dot = |offset| NewNode::Operation {
+            operation: Operation::Translate { offset },
+            child: NewNode::Shape(Shape::Circle { radius: 1.0 }).into(),
+        };
+
+        let red = Shape::Rectangle {
+            min: Vec2::ZERO,
+            max: Vec2::splat(SIZE as f32),
+        };
+
+        let group = NewNode::Group {
+            children: vec![
+                dot(Vec2::splat(-1e20)),
+                fill(Color::rgb(1.0, 0.0, 0.0), red),
+                dot(Vec2::splat(1e20)),
+            ],
+        };
+
+        let dt = render(vec![blend(willow_server::BlendMode::SourceIn, group)]);
+        assert!(dt.get_data().iter().all(|pixel| *pixel == RED));
+    }
+
+    #[test]
+    fn nested_layers_keep_their_positions() {
+        let blue = Shape::Rectangle {
+            min: vec2(10.0, 2.0),
+            max: vec2(14.0, 6.0),
+        };
+
+        let group = NewNode::Group {
+            children: vec![
+                red_circle(),
+                blend(
+                    willow_server::BlendMode::SourceOver,
+                    fill(Color::rgb(0.0, 0.0, 1.0), blue),
+                ),
+            ],
+        };
+
+        let dt = render(vec![blend(willow_server::BlendMode::SourceOver, group)]);
+        assert_eq!(pixel(&dt, 8, 8), RED);
+        assert_eq!(pixel(&dt, 12, 4), 0xff0000ff);
+        assert_eq!(pixel(&dt, 12, 8), WHITE);
+        assert_eq!(pixel(&dt, 14, 4), WHITE);
+    }
+
+    #[test]
+    fn gradient_stops_are_sorted() {
+        let (gradient, _) = convert_gradient(&gradient(&[1.0, 0.0, 0.5]));
+        let positions: Vec<_> = gradient.stops.iter().map(|stop| stop.position).collect();
+        assert_eq!(positions, vec![0.0, 0.5, 1.0]);
+        assert_eq!(gradient.stops[0].color, raqote::Color::new(255, 0, 0, 0));
+        assert_eq!(gradient.stops[2].color, raqote::Color::new(255, 255, 0, 0));
+    }
+
+    #[test]
+    fn empty_gradients_are_transparent() {
+        let (gradient, _) = convert_gradient(&gradient(&[]));
+        assert_eq!(gradient.stops.len(), 1);
+        assert_eq!(gradient.stops[0].color, raqote::Color::new(0, 0, 0, 0));
+    }
+
+    #[test]
+    fn gradient_spreads_are_converted() {
+        let spread = |spread| convert_gradient(&gradient(&[0.0]).with_spread(spread)).1;
+        assert!(matches!(spread(willow_server::Spread::Pad), Spread::Pad));
+        assert!(matches!(
+            spread(willow_server::Spread::Repeat),
+            Spread::Repeat
+        ));
+        assert!(matches!(
+            spread(willow_server::Spread::Reflect),
+            Spread::Reflect
+        ));
+    }
+
+    #[test]
+    fn degenerate_gradients_draw_their_last_stop() {
+        use willow_server::Stroke::*;
+
+        let gradient = gradient(&[1.0, 0.0]);
+        let strokes = [
+            LinearGradient {
+                start: Vec2::ONE,
+                end: Vec2::ONE,
+                gradient: gradient.clone(),
+            },
+            RadialGradient {
+                center: Vec2::ZERO,
+                radius: 0.0,
+                gradient: gradient.clone(),
+            },
+            RadialGradient {
+                center: Vec2::ZERO,
+                radius: -1.0,
+                gradient: gradient.clone(),
+            },
+            RadialGradient {
+                center: Vec2::ZERO,
+                radius: f32::NAN,
+                gradient: gradient.clone(),
+            },
+            SweepGradient {
+                center: Vec2::ZERO,
+                start_angle: 1.0,
+                end_angle: 1.0,
+                gradient: gradient.clone(),
+            },
+        ];
+
+        let red = SolidSource::from_unpremultiplied_argb(255, 255, 0, 0);
+        for stroke in strokes.iter() {
+            let Source::Solid(solid) = stroke_source(stroke) else {
+                panic!("{:?} isn't solid", stroke);
+            };
+
+            assert_eq!(solid, red);
+
+            // drawing with it doesn't panic either
+            render(vec![NewNode::Operation {
+                operation: Operation::Stroke(stroke.clone()),
+                child: NewNode::Shape(Shape::Circle { radius: 4.0 }).into(),
+            }]);
+        }
+
+        let empty = RadialGradient {
+            center: Vec2::ZERO,
+            radius: 0.0,
+            gradient: willow_server::Gradient::default(),
+        };
+
+        let Source::Solid(solid) = stroke_source(&empty) else {
+            panic!("empty gradients aren't solid");
+        };
+
+        assert_eq!(solid.a, 0);
+    }
+
+    #[test]
+    fn colors_are_converted_to_srgb_bytes() {
+        let linear = Color::rgba(0.0, 0.5, 1.0, 0.5).with_space(ColorSpace::LinearSrgb);
+        assert_eq!(argb_bytes(linear), [128, 0, 188, 255]);
+        assert_eq!(
+            argb_bytes(Color::rgba(0.0, 0.5, 1.0, 0.5)),
+            [128, 0, 128, 255]
+        );
+
+        // components outside of the range of bytes are clamped
+        assert_eq!(
+            argb_bytes(Color::rgba(-1.0, 2.0, 0.0, 2.0)),
+            [255, 0, 255, 0]
+        );
+    }
+
+    #[test]
+    fn solid_sources_are_premultiplied() {
+        let Source::Solid(solid) = solid_source(Color::rgba(1.0, 0.5, 0.0, 0.5)) else {
+            panic!("solid colors make solid sources");
+        };
+
+        assert_eq!(solid.a, 128);
+        assert_eq!(solid.r, 128);
+        assert_eq!(solid.g, 64);
+        assert_eq!(solid.b, 0);
+    }
 }
@@ -13,208 +13,191 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with Willow.  If not, see <https://www.gnu.org/licenses/>.
 
-use allsorts::binary::read::ReadScope;
-use allsorts::font::{GlyphTableFlags, MatchingPresentation};
-use allsorts::font_data::{DynamicFontTableProvider, FontData as AllsortsFontData};
-use allsorts::glyph_position::{GlyphLayout, TextDirection};
-use allsorts::outline::OutlineBuilder;
-use allsorts::Font as AllsortsFont;
-use euclid::default::Transform2D;
-use raqote::{DrawOptions, DrawTarget, Path, PathBuilder, Source};
-
-#[repr(C)]
-#[derive(Copy, Clone, Debug)]
-pub struct GlyphPosition {
-    pub index: u16,
-    pub hori_advance: i32,
-    pub vert_advance: i32,
-    pub xoff: i32,
-    pub yoff: i32,
-}
-
-#[ouroboros::self_referencing]
-pub struct FontData {
-    file_buffer: Vec<u8>,
-    script: u32,
-    direction: TextDirection,
-    vertical: bool,
-
-    #[borrows(file_buffer)]
-    #[covariant]
-    read_scope: ReadScope<'this>,
-
-    #[borrows(read_scope)]
-    #[not_covariant]
-    font_data: AllsortsFontData<'this>,
-
-    #[borrows(font_data)]
-    #[covariant]
-    inner: AllsortsFont<DynamicFontTableProvider<'this>>,
-}
-
-impl FontData {
-    pub fn load(
-        script: u32,
-        direction: TextDirection,
-        vertical: bool,
-        file_buffer: Vec<u8>,
-    ) -> Self {
-        FontDataBuilder {
-            file_buffer,
-            script,
-            direction,
-            vertical,
-            read_scope_builder: |buffer| ReadScope::new(buffer),
-            font_data_builder: |scope| scope.read::<AllsortsFontData<'_>>().unwrap(),
-            inner_builder: |font_data| {
-                AllsortsFont::new(font_data.table_provider(0).unwrap())
-                    .unwrap()
-                    .unwrap()
-            },
-        }
-        .build()
-    }
-
-    pub fn shape(&mut self, text: &str) -> Vec<GlyphPosition> {
-        let presentation = MatchingPresentation::Required;
-        let script = *self.borrow_script();
-        let lang_tag = None;
-        let features = allsorts::gsub::Features::default();
-        let kerning = true;
-        let direction = *self.borrow_direction();
-        let vertical = *self.borrow_vertical();
-
-        self.with_inner_mut(|font| {
-            let mapped = font.map_glyphs(text, script, presentation);
-            let infos = font
-                .shape(mapped, script, lang_tag, &features, kerning)
-                .unwrap();
-            let mut layout = GlyphLayout::new(font, &infos, direction, vertical);
-            let positions = layout.glyph_positions().unwrap();
-            let mut glyphs = Vec::with_capacity(positions.len());
-            for (glyph, position) in infos.iter().zip(&positions) {
-                glyphs.push(GlyphPosition {
-                    index: glyph.glyph.glyph_index,
-                    hori_advance: position.hori_advance,
-                    vert_advance: position.vert_advance,
-                    xoff: position.x_offset,
-                    yoff: position.y_offset,
-                });
+use raqote::{DrawOptions, DrawTarget, Image, Path, PathBuilder, SolidSource, Source, Transform};
+use willow_server::glam::Vec2;
+use willow_text::{
+    font::{Outline, OutlineCommand},
+    Bounds, ColorGlyph, Decoration, DecorationKind, FontRegistry, GlyphBitmap, PositionedGlyph,
+};
+
+use crate::atlas::GlyphAtlas;
+
+/// Fills a list of glyphs, such as those of a [TextLayout][willow_text::TextLayout],
+/// positioned relative to the origin of `dt`'s current transform.
+///
+/// Color glyphs are drawn in their own colors, except for the layers that
+/// take the color of the text from `source`. Other glyphs are blitted from
+/// `atlas` when they can be, and filled as paths otherwise.
+pub fn draw_glyphs<Backing>(
+    dt: &mut DrawTarget<Backing>,
+    fonts: &mut FontRegistry,
+    mut atlas: Option<&mut GlyphAtlas>,
+    glyphs: &[PositionedGlyph],
+    source: &Source,
+    options: &DrawOptions,
+) where
+    Backing: AsRef<[u32]> + AsMut<[u32]>,
+{
+    for glyph in glyphs.iter() {
+        if let Some(atlas) = atlas.as_deref_mut() {
+            if atlas.draw(dt, fonts, glyph, source, options) {
+                continue;
             }
-
-            glyphs
-        })
-    }
-
-    pub fn draw<Backing>(
-        &mut self,
-        dt: &mut DrawTarget<Backing>,
-        text: &str,
-        source: &Source,
-        options: &DrawOptions,
-    ) where
-        Backing: AsRef<[u32]> + AsMut<[u32]>,
-    {
-        let units_per_em =
-            self.with_inner_mut(|font| font.head_table().unwrap().unwrap().units_per_em as f32);
-        let px_per_unit = 10.0 / units_per_em;
-
-        let mut xcur = 0;
-        let mut ycur = 0;
-        for position in self.shape(text) {
-            let xpos = xcur + position.xoff;
-            let ypos = ycur + position.yoff;
-            xcur += position.hori_advance;
-            ycur += position.vert_advance;
-
-            let path = self.glyph_path(position.index);
-            let translate = Transform2D::translation(xpos as f32, ypos as f32);
-            let scale = Transform2D::scale(px_per_unit, -px_per_unit);
-            let transform = translate.then(&scale);
-            let path = path.transform(&transform);
-            dt.fill(&path, source, options);
         }
-    }
 
-    pub fn glyph_path(&mut self, index: u16) -> Path {
-        use allsorts::cff::CFF;
-        use allsorts::tables::{glyf::GlyfTable, loca::LocaTable, FontTableProvider, SfntVersion};
-        use allsorts::tag;
-
-        self.with_inner_mut(|font| {
-            if font.glyph_table_flags.contains(GlyphTableFlags::CFF)
-                && font.font_table_provider.sfnt_version() == tag::OTTO
-            {
-                let cff_data = font.font_table_provider.read_table_data(tag::CFF).unwrap();
-                let mut cff = ReadScope::new(&cff_data).read::<CFF<'_>>().unwrap();
-                GlyphPathBuilder::build(&mut cff, index)
-            } else if font.glyph_table_flags.contains(GlyphTableFlags::GLYF) {
-                let loca_data = font.font_table_provider.read_table_data(tag::LOCA).unwrap();
-                let loca = ReadScope::new(&loca_data)
-                    .read_dep::<LocaTable<'_>>((
-                        usize::from(font.maxp_table.num_glyphs),
-                        font.head_table().unwrap().unwrap().index_to_loc_format,
-                    ))
-                    .unwrap();
-                let glyf_data = font.font_table_provider.read_table_data(tag::GLYF).unwrap();
-                let mut glyf = ReadScope::new(&glyf_data)
-                    .read_dep::<GlyfTable<'_>>(&loca)
-                    .unwrap();
-
-                GlyphPathBuilder::build(&mut glyf, index)
-            } else {
-                panic!("no glyf or CFF table");
+        let Some(face) = fonts.get_mut(glyph.face) else {
+            continue;
+        };
+
+        let data = &mut face.data;
+        let transform = glyph_transform(glyph);
+        let ppem = glyph.scale * data.metrics().units_per_em;
+        match data.color_glyph(glyph.index, ppem) {
+            Some(ColorGlyph::Layers(layers)) => {
+                for layer in layers {
+                    let outline = data.glyph_outline(layer.index);
+                    let path = outline_path(&outline).transform(&transform);
+                    match layer.color {
+                        Some([r, g, b, a]) => {
+                            let color = SolidSource::from_unpremultiplied_argb(a, r, g, b);
+                            dt.fill(&path, &Source::Solid(color), options);
+                        }
+                        None => dt.fill(&path, source, options),
+                    }
+                }
             }
-        })
+            Some(ColorGlyph::Bitmap(bitmap)) => {
+                draw_bitmap(dt, &bitmap, &transform, options);
+            }
+            None => {
+                let outline = data.glyph_outline(glyph.index);
+                if !outline.is_empty() {
+                    let path = outline_path(&outline).transform(&transform);
+                    dt.fill(&path, source, options);
+                }
+            }
+        }
     }
 }
 
-pub struct GlyphPathBuilder {
-    pb: PathBuilder,
+/// Draws a color glyph's image, given the transform from its font units to
+/// the user space of `dt`.
+fn draw_bitmap<Backing>(
+    dt: &mut DrawTarget<Backing>,
+    bitmap: &GlyphBitmap,
+    transform: &Transform,
+    options: &DrawOptions,
+) where
+    Backing: AsRef<[u32]> + AsMut<[u32]>,
+{
+    let image = Image {
+        width: bitmap.width as i32,
+        height: bitmap.height as i32,
+        data: &bitmap.pixels,
+    };
+
+    // map the image's pixels onto its corners in font units, top row first
+    let pixel_size = (bitmap.max - bitmap.min) / Vec2::new(image.width as f32, image.height as f32);
+    let to_font = Transform::new(
+        pixel_size.x,
+        0.0,
+        0.0,
+        -pixel_size.y,
+        bitmap.min.x,
+        bitmap.max.y,
+    );
+
+    let user = *dt.get_transform();
+    dt.set_transform(&to_font.then(transform).then(&user));
+    dt.draw_image_at(0.0, 0.0, &image, options);
+    dt.set_transform(&user);
 }
 
-impl allsorts::outline::OutlineSink for GlyphPathBuilder {
-    fn move_to(&mut self, to: allsorts::pathfinder_geometry::vector::Vector2F) {
-        self.pb.move_to(to.x(), to.y());
+/// Draws rich text: first the backgrounds in `decorations`, then the glyphs,
+/// then the lines under and through them.
+///
+/// Glyphs and lines are filled with the source of their span in `sources`,
+/// and backgrounds with the source of their span in `backgrounds`.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_rich<Backing>(
+    dt: &mut DrawTarget<Backing>,
+    fonts: &mut FontRegistry,
+    mut atlas: Option<&mut GlyphAtlas>,
+    glyphs: &[PositionedGlyph],
+    decorations: &[Decoration],
+    sources: &[Source],
+    backgrounds: &[Option<Source>],
+    options: &DrawOptions,
+) where
+    Backing: AsRef<[u32]> + AsMut<[u32]>,
+{
+    let (behind, lines): (Vec<&Decoration>, Vec<_>) = decorations
+        .iter()
+        .partition(|decoration| decoration.kind == DecorationKind::Background);
+
+    for decoration in behind {
+        if let Some(Some(source)) = backgrounds.get(decoration.span) {
+            fill_bounds(dt, &decoration.bounds, source, options);
+        }
     }
 
-    fn line_to(&mut self, to: allsorts::pathfinder_geometry::vector::Vector2F) {
-        self.pb.line_to(to.x(), to.y());
+    for group in glyphs.chunk_by(|a, b| a.span == b.span) {
+        if let Some(source) = sources.get(group[0].span) {
+            draw_glyphs(dt, fonts, atlas.as_deref_mut(), group, source, options);
+        }
     }
 
-    fn quadratic_curve_to(
-        &mut self,
-        ctrl: allsorts::pathfinder_geometry::vector::Vector2F,
-        to: allsorts::pathfinder_geometry::vector::Vector2F,
-    ) {
-        self.pb.quad_to(ctrl.x(), ctrl.y(), to.x(), to.y());
+    for decoration in lines {
+        if let Some(source) = sources.get(decoration.span) {
+            fill_bounds(dt, &decoration.bounds, source, options);
+        }
     }
+}
 
-    fn cubic_curve_to(
-        &mut self,
-        ctrl: allsorts::pathfinder_geometry::line_segment::LineSegment2F,
-        to: allsorts::pathfinder_geometry::vector::Vector2F,
-    ) {
-        self.pb.cubic_to(
-            ctrl.from_x(),
-            ctrl.from_y(),
-            ctrl.to_x(),
-            ctrl.to_y(),
-            to.x(),
-            to.y(),
-        );
-    }
+/// Converts a glyph's transform from font units to pixels into a raqote
+/// [Transform].
+fn glyph_transform(glyph: &PositionedGlyph) -> Transform {
+    let transform = glyph.transform();
+    let x_axis = transform.matrix2.x_axis;
+    let y_axis = transform.matrix2.y_axis;
+    let translation = transform.translation;
+    Transform::new(
+        x_axis.x,
+        x_axis.y,
+        y_axis.x,
+        y_axis.y,
+        translation.x,
+        translation.y,
+    )
+}
 
-    fn close(&mut self) {
-        self.pb.close();
-    }
+fn fill_bounds<Backing>(
+    dt: &mut DrawTarget<Backing>,
+    bounds: &Bounds,
+    source: &Source,
+    options: &DrawOptions,
+) where
+    Backing: AsRef<[u32]> + AsMut<[u32]>,
+{
+    let size = bounds.max - bounds.min;
+    dt.fill_rect(bounds.min.x, bounds.min.y, size.x, size.y, source, options);
 }
 
-impl GlyphPathBuilder {
-    pub fn build(builder: &mut impl OutlineBuilder, index: u16) -> Path {
-        let pb = PathBuilder::new();
-        let mut sink = Self { pb };
-        builder.visit(index, &mut sink).unwrap();
-        sink.pb.finish()
+/// Converts a glyph [Outline] into a raqote [Path] in font units.
+pub fn outline_path(outline: &Outline) -> Path {
+    let mut pb = PathBuilder::new();
+
+    for command in outline.commands.iter() {
+        match *command {
+            OutlineCommand::MoveTo(to) => pb.move_to(to.x, to.y),
+            OutlineCommand::LineTo(to) => pb.line_to(to.x, to.y),
+            OutlineCommand::QuadTo(ctrl, to) => pb.quad_to(ctrl.x, ctrl.y, to.x, to.y),
+            OutlineCommand::CubicTo(ctrl1, ctrl2, to) => {
+                pb.cubic_to(ctrl1.x, ctrl1.y, ctrl2.x, ctrl2.y, to.x, to.y)
+            }
+            OutlineCommand::Close => pb.close(),
+        }
     }
+
+    pb.finish()
 }
@@ -13,25 +13,52 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with Willow.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
+
+use willow_server::resource::{ResourceResult, SharedResources};
 use willow_server::*;
-use glam::Vec3A;
+use willow_text::{SharedFonts, TextMetrics};
 
 pub use willow_server;
 
 pub struct State {
     pub tree: Tree,
+
+    /// The hashes of the resources that components have uploaded, by the
+    /// keys they uploaded them with.
+    uploads: HashMap<String, ResourceHash>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl State {
     pub fn new() -> Self {
-        Self { tree: Tree::new() }
+        Self::with_fonts(Default::default())
+    }
+
+    /// Creates a state whose trees lay text out with a shared set of fonts.
+    pub fn with_fonts(fonts: SharedFonts) -> Self {
+        Self {
+            tree: Tree::with_fonts(fonts),
+            uploads: HashMap::new(),
+        }
     }
 
     pub fn set_root(&mut self, mut component: Box<dyn ElementComponent>) {
-        let mut hooks = Hooks {};
+        let mut hooks = Hooks {
+            fonts: self.tree.fonts().clone(),
+            resources: self.tree.resources().clone(),
+            uploads: std::mem::take(&mut self.uploads),
+        };
         let rendered = component.render(&mut hooks).render_whole(&mut hooks);
 
-        let mut tree = Tree::new();
+        let fonts = self.tree.fonts().clone();
+        let resources = self.tree.resources().clone();
+        let mut tree = Tree::with_shared(fonts, resources);
         tree.update_node(NodeUpdate {
             target: 0,
             content: NodeContent::Group {
@@ -40,7 +67,14 @@ impl State {
         })
         .unwrap();
 
+        // the previous tree releases its resources when it's replaced, so
+        // this is where the frame ends
         self.tree = tree;
+        self.tree.evict_unclaimed_resources();
+
+        let resources = self.tree.resources().borrow();
+        self.uploads = hooks.uploads;
+        self.uploads.retain(|_, hash| resources.contains(hash));
     }
 }
 
@@ -109,8 +143,6 @@ impl Element {
     }
 }
 
-pub type Color = Vec3A;
-
 pub fn stroke_color(color: Color) -> Operation {
     Operation::Stroke(Stroke::Solid { color })
 }
@@ -124,15 +156,48 @@ pub struct Theme {
     pub accent: Color,
 }
 
-pub struct Hooks {}
+pub struct Hooks {
+    fonts: SharedFonts,
+    resources: SharedResources,
+    uploads: HashMap<String, ResourceHash>,
+}
 
 impl Hooks {
+    /// Measures a string of text as the tree will lay it out, so that
+    /// components can position content around it.
+    pub fn measure_text(&mut self, font: &Font, text: &str, size: f32) -> TextMetrics {
+        self.fonts.borrow_mut().measure(font, text, size)
+    }
+
+    /// Uploads a resource for shapes to refer to by its hash.
+    ///
+    /// Resources are kept between renders for as long as the rendered tree
+    /// uses them. `key` names the resource between renders, so that while
+    /// it's stored, `upload` isn't called and its data isn't hashed again.
+    pub fn upload_resource(
+        &mut self,
+        key: &str,
+        upload: impl FnOnce() -> ResourceUpload,
+    ) -> ResourceResult<ResourceHash> {
+        let mut resources = self.resources.borrow_mut();
+        if let Some(hash) = self.uploads.get(key) {
+            if resources.contains(hash) {
+                return Ok(*hash);
+            }
+        }
+
+        let mut fonts = self.fonts.borrow_mut();
+        let hash = resources.upload(upload(), &mut fonts)?.hash;
+        self.uploads.insert(key.to_owned(), hash);
+        Ok(hash)
+    }
+
     pub fn use_theme(&mut self) -> Theme {
         fn rgb(rgb: u32) -> Color {
             let r = (rgb >> 16) as f32;
             let g = ((rgb >> 8) & 0xff) as f32;
             let b = (rgb & 0xff) as f32;
-            Color::new(r, g, b) / 255.0
+            Color::rgb(r / 255.0, g / 255.0, b / 255.0)
         }
 
         Theme {
@@ -158,3 +223,69 @@ where
         self(hooks)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use willow_server::glam::Vec2;
+
+    /// Renders a text shape after measuring it with [Hooks::measure_text],
+    /// and checks the measurements against the bounds the tree lays it out
+    /// with.
+    fn assert_measured_like_tree(font: impl Fn(&mut Hooks) -> Font + 'static, content: &str) {
+        let metrics = Rc::new(RefCell::new(None));
+        let mut state = State::new();
+        let content = content.to_string();
+        let size = 18.0;
+
+        state.set_root(Box::new({
+            let metrics = metrics.clone();
+            move |hooks: &mut Hooks| {
+                let font = font(hooks);
+                *metrics.borrow_mut() = Some(hooks.measure_text(&font, &content, size));
+                Element::from(Shape::Text {
+                    content: content.clone(),
+                    font,
+                    size,
+                })
+            }
+        }));
+
+        let NodeKind::Group(children) = state.tree.get_node(0).unwrap().get_kind() else {
+            panic!("root is not a group");
+        };
+
+        let node = state.tree.get_node(children[0]).unwrap();
+        let bounds = &node.get_text_bounds().unwrap().advance;
+        let metrics = metrics.borrow().unwrap();
+        assert!(metrics.advance > 0.0);
+        assert_eq!(bounds.min, Vec2::new(0.0, -metrics.ascent));
+        assert_eq!(bounds.max, Vec2::new(metrics.advance, metrics.descent));
+    }
+
+    #[test]
+    fn measured_text_matches_the_tree() {
+        assert_measured_like_tree(|_| Font::default(), "Willow");
+
+        let bold = |_: &mut Hooks| Font::default().with_weight(FontWeight::BOLD);
+        assert_measured_like_tree(bold, "Hello, мир! ﬁ");
+    }
+
+    #[test]
+    fn text_measured_with_uploaded_fonts_matches_the_tree() {
+        let uploaded = |hooks: &mut Hooks| {
+            let upload = || ResourceUpload {
+                data: ResourceData::Font(notosans::BOLD_ITALIC_TTF.to_vec()),
+            };
+
+            let hash = hooks.upload_resource("font", upload).unwrap();
+            Font::default().with_resource(hash)
+        };
+
+        assert_measured_like_tree(uploaded, "Willow");
+    }
+}
@@ -20,6 +20,15 @@ use slab::Slab;
 
 use willow_protocol::glam::{vec2, Mat2, Mat3};
 pub use willow_protocol::*;
+pub use willow_text;
+use willow_text::{
+    Bounds, Decoration, ParagraphLayout, PositionedGlyph, SharedFonts, StyledRange, TextLayout,
+};
+
+pub mod path;
+pub mod resource;
+
+use resource::{Resource, ResourceResult, SharedResources};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum NodeUpdateError {
@@ -34,6 +43,12 @@ pub enum NodeUpdateError {
 
     /// Two instances of [ChildUpdate::KeepIndex] refer to the same index.
     DuplicateKeepIndex(u32),
+
+    /// An arc in this update had an infinite or NaN angle.
+    NonFiniteAngle,
+
+    /// A font in this update had a feature with an invalid OpenType tag.
+    InvalidFontFeature([u8; 4]),
 }
 
 impl std::fmt::Display for NodeUpdateError {
@@ -44,6 +59,10 @@ impl std::fmt::Display for NodeUpdateError {
             InvalidKeepIndex(idx) => write!(fmt, "invalid kept index: {}", idx),
             UnownedKeepIndex(idx) => write!(fmt, "unowned kept index: {}", idx),
             DuplicateKeepIndex(idx) => write!(fmt, "attempt to keep an index twice: {}", idx),
+            NonFiniteAngle => write!(fmt, "non-finite arc angle"),
+            InvalidFontFeature(tag) => {
+                write!(fmt, "invalid font feature tag: \"{}\"", tag.escape_ascii())
+            }
         }
     }
 }
@@ -76,6 +95,32 @@ impl Aabb {
             && self.max.y > other.min.y
     }
 
+    /// Finds the overlap of two boxes, or [Aabb::INVALID] if they don't
+    /// overlap.
+    pub fn intersection(&self, other: &Self) -> Self {
+        let min = self.min.max(other.min);
+        let max = self.max.min(other.max);
+        if min.x < max.x && min.y < max.y {
+            Self { min, max }
+        } else {
+            Self::INVALID
+        }
+    }
+
+    /// Finds the bounds of this box's corners after transforming them.
+    pub fn transform(&self, f: impl Fn(Vec2) -> Vec2) -> Self {
+        let mut min = Vec2::INFINITY;
+        let mut max = Vec2::NEG_INFINITY;
+
+        for corner in self.corners() {
+            let corner = f(corner);
+            min = min.min(corner);
+            max = max.max(corner);
+        }
+
+        Self { min, max }
+    }
+
     pub fn corners(&self) -> [Vec2; 4] {
         [
             self.min,
@@ -86,6 +131,83 @@ impl Aabb {
     }
 }
 
+impl From<Bounds> for Aabb {
+    fn from(bounds: Bounds) -> Self {
+        if bounds.is_empty() {
+            Self::INVALID
+        } else {
+            Self {
+                min: bounds.min,
+                max: bounds.max,
+            }
+        }
+    }
+}
+
+/// The measured extents of a line of text, relative to the start of its
+/// baseline, or of a paragraph, relative to its top-left corner.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextBounds {
+    /// The bounds of every glyph outline drawn by the text.
+    pub ink: Aabb,
+
+    /// The box the text occupies. For a single line, this spans the advance
+    /// horizontally and the ascent and descent of its fonts vertically. For a
+    /// paragraph, this spans the maximum width and the height of every line.
+    pub advance: Aabb,
+}
+
+impl From<&TextLayout> for TextBounds {
+    fn from(layout: &TextLayout) -> Self {
+        Self {
+            ink: layout.ink.into(),
+            advance: layout.advance_bounds().into(),
+        }
+    }
+}
+
+impl From<&ParagraphLayout> for TextBounds {
+    fn from(layout: &ParagraphLayout) -> Self {
+        Self {
+            ink: layout.ink.into(),
+            advance: layout.box_bounds().into(),
+        }
+    }
+}
+
+/// The glyphs and decorations of a laid-out text shape, positioned like its
+/// [TextBounds].
+///
+/// Text is laid out once when its node is created, and walkers draw this
+/// layout instead of laying the text out again, so that drawn text always
+/// matches its node's bounds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextGlyphs {
+    pub glyphs: Vec<PositionedGlyph>,
+
+    /// The rectangles to fill for the decorations of rich text, with
+    /// backgrounds first.
+    pub decorations: Vec<Decoration>,
+}
+
+impl From<TextLayout> for TextGlyphs {
+    fn from(layout: TextLayout) -> Self {
+        Self {
+            glyphs: layout.glyphs,
+            decorations: layout.decorations,
+        }
+    }
+}
+
+impl From<ParagraphLayout> for TextGlyphs {
+    fn from(layout: ParagraphLayout) -> Self {
+        Self {
+            glyphs: layout.glyphs,
+            decorations: layout.decorations,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum NodeKind {
     Shape(Shape),
@@ -106,6 +228,13 @@ pub struct Node {
 
     /// The bounding box of this node and its children.
     aabb: Aabb,
+
+    /// The shaped extents of this node, if it is a [Shape::Text],
+    /// [Shape::Paragraph], or [Shape::RichText].
+    text_bounds: Option<TextBounds>,
+
+    /// The laid-out glyphs of this node, for the same shapes as `text_bounds`.
+    text_glyphs: Option<TextGlyphs>,
 }
 
 impl Node {
@@ -115,27 +244,131 @@ impl Node {
             owned: false,
             reused: false,
             aabb,
+            text_bounds: None,
+            text_glyphs: None,
         }
     }
 
     pub fn get_kind(&self) -> &NodeKind {
         &self.kind
     }
+
+    pub fn get_aabb(&self) -> &Aabb {
+        &self.aabb
+    }
+
+    pub fn get_text_bounds(&self) -> Option<&TextBounds> {
+        self.text_bounds.as_ref()
+    }
+
+    pub fn get_text_glyphs(&self) -> Option<&TextGlyphs> {
+        self.text_glyphs.as_ref()
+    }
 }
 
 /// A Willow shape tree.
 pub struct Tree {
     nodes: Slab<Node>,
+
+    /// The fonts that text shapes are laid out with.
+    fonts: SharedFonts,
+
+    /// The resources that image shapes draw.
+    resources: SharedResources,
+}
+
+impl Default for Tree {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Tree {
     /// Creates a new tree. The initial node (at index 0) is a [Shape::Empty].
+    ///
+    /// Text is laid out with a new default [FontRegistry][willow_text::FontRegistry].
     pub fn new() -> Self {
+        Self::with_fonts(Default::default())
+    }
+
+    /// Creates a new tree that lays text out with a shared set of fonts.
+    ///
+    /// Renderers should draw text with the same fonts so that the bounds
+    /// computed here match what is drawn.
+    pub fn with_fonts(fonts: SharedFonts) -> Self {
+        Self::with_shared(fonts, Default::default())
+    }
+
+    /// Creates a new tree with shared fonts and resources.
+    ///
+    /// Trees that replace each other should share their resources, so that
+    /// they don't need to be uploaded again.
+    pub fn with_shared(fonts: SharedFonts, resources: SharedResources) -> Self {
         let mut nodes = Slab::new();
         let empty = NodeKind::Shape(Shape::Empty);
         nodes.insert(Node::new(empty, Aabb::default()));
 
-        Self { nodes }
+        Self {
+            nodes,
+            fonts,
+            resources,
+        }
+    }
+
+    /// Gets the fonts that this tree lays text out with.
+    pub fn fonts(&self) -> &SharedFonts {
+        &self.fonts
+    }
+
+    /// Gets the resources that this tree's shapes draw.
+    pub fn resources(&self) -> &SharedResources {
+        &self.resources
+    }
+
+    /// Stores an uploaded resource, loading font files into this tree's
+    /// fonts.
+    ///
+    /// Text in this tree that was laid out before a font it uses was uploaded
+    /// is laid out again with it.
+    pub fn upload_resource(
+        &mut self,
+        upload: ResourceUpload,
+    ) -> ResourceResult<ResourceUploadResponse> {
+        let hash = upload.data.hash();
+        let stored = {
+            let mut fonts = self.fonts.borrow_mut();
+            self.resources
+                .borrow_mut()
+                .store(hash, upload.data, &mut fonts)?
+        };
+
+        let is_font = matches!(self.resources.borrow().get(&hash), Some(Resource::Font(_)));
+        if stored && is_font {
+            self.relayout_resource(hash);
+        }
+
+        Ok(ResourceUploadResponse { hash })
+    }
+
+    /// Evicts the uploaded resources that no node in any tree sharing them
+    /// has referred to since they were uploaded.
+    ///
+    /// This should be called at the end of each frame, once every tree that
+    /// shares these resources has been updated. See
+    /// [Resources::evict_unclaimed][resource::Resources::evict_unclaimed].
+    pub fn evict_unclaimed_resources(&self) {
+        let mut fonts = self.fonts.borrow_mut();
+        self.resources.borrow_mut().evict_unclaimed(&mut fonts);
+    }
+
+    /// Finds which of the queried resources haven't been uploaded.
+    pub fn query_resources(&self, query: &ResourceQuery) -> ResourceQueryResponse {
+        self.resources.borrow().query(query)
+    }
+
+    /// Gets a node by its index.
+    pub fn get_node(&self, index: usize) -> Option<&Node> {
+        self.nodes.get(index)
     }
 
     /// Creates a new tree with an initial content.
@@ -146,9 +379,10 @@ impl Tree {
     }
 
     pub fn update_node(&mut self, update: NodeUpdate) -> NodeUpdateResult<NodeUpdateResponse> {
+        validate_content(&update.content)?;
         let original_children = self.begin_children_update(update.target as usize)?;
         let update_result = self.update_node_inner(update);
-        let remove_unused = !update_result.is_err();
+        let remove_unused = update_result.is_ok();
         self.end_children_update(original_children, remove_unused); // always clean up update
         update_result
     }
@@ -178,7 +412,8 @@ impl Tree {
 
         let new_node = self.create_new_node(node_kind);
         let node = self.nodes.get_mut(update.target as usize).unwrap();
-        let _ = std::mem::replace(node, new_node);
+        let old_node = std::mem::replace(node, new_node);
+        self.release_resources(&old_node);
 
         Ok(NodeUpdateResponse { new_nodes })
     }
@@ -197,8 +432,6 @@ impl Tree {
             NodeKind::Group(children) => children,
         };
 
-        drop(node);
-
         for child in children.iter() {
             self.nodes.get_mut(*child).unwrap().owned = true;
         }
@@ -219,11 +452,45 @@ impl Tree {
             }
 
             if remove_unused {
-                self.nodes.remove(child);
+                self.remove_node(child);
+            }
+        }
+    }
+
+    /// Frees a node and all of its descendants.
+    fn remove_node(&mut self, index: usize) {
+        let node = self.nodes.remove(index);
+        self.release_resources(&node);
+
+        match node.kind {
+            NodeKind::Shape(_) => {}
+            NodeKind::Operation { child, .. } => self.remove_node(child),
+            NodeKind::Group(children) => {
+                for child in children {
+                    self.remove_node(child);
+                }
             }
         }
     }
 
+    /// Removes a node's references to the resources its shape uses.
+    fn release_resources(&self, node: &Node) {
+        let NodeKind::Shape(shape) = &node.kind else {
+            return;
+        };
+
+        let hashes = resource::shape_resources(shape);
+        if hashes.is_empty() {
+            return;
+        }
+
+        let mut fonts = self.fonts.borrow_mut();
+        let mut resources = self.resources.borrow_mut();
+        for hash in hashes {
+            resources.release(hash, &mut fonts);
+        }
+    }
+
     /// Consumes a [ChildUpdate] during a node update.
     fn update_child(
         &mut self,
@@ -276,22 +543,101 @@ impl Tree {
         index
     }
 
-    /// Creates a [Node] of the given kind.
+    /// Creates a [Node] of the given kind, counting its references to
+    /// resources.
     pub fn create_new_node(&mut self, kind: NodeKind) -> Node {
-        let aabb = match &kind {
+        if let NodeKind::Shape(shape) = &kind {
+            let mut resources = self.resources.borrow_mut();
+            for hash in resource::shape_resources(shape) {
+                resources.acquire(hash);
+            }
+        }
+
+        let (aabb, text_bounds, text_glyphs) = self.lay_out_node(&kind);
+        let mut node = Node::new(kind, aabb);
+        node.text_bounds = text_bounds;
+        node.text_glyphs = text_glyphs;
+        node
+    }
+
+    /// Computes the bounding box of a node from its shape or its children,
+    /// and lays its text out if it has any.
+    fn lay_out_node(&self, kind: &NodeKind) -> (Aabb, Option<TextBounds>, Option<TextGlyphs>) {
+        let mut text_bounds = None;
+        let mut text_glyphs = None;
+
+        let aabb = match kind {
             NodeKind::Shape(shape) => match shape.clone() {
                 Shape::Empty => Aabb::INVALID,
                 Shape::Circle { radius } => Aabb {
                     min: -Vec2::splat(radius),
                     max: Vec2::splat(radius),
                 },
+                Shape::Ellipse { radii } => Aabb {
+                    min: -radii.abs(),
+                    max: radii.abs(),
+                },
+                Shape::Arc {
+                    radius,
+                    inner_radius,
+                    start_angle,
+                    sweep_angle,
+                } => path::path_aabb(&path::arc_commands(
+                    radius,
+                    inner_radius,
+                    start_angle,
+                    sweep_angle,
+                )),
+                Shape::Polygon { points, .. } => {
+                    path::path_aabb(&path::polygon_commands(&points, true))
+                }
+                Shape::Polyline { points } => {
+                    path::path_aabb(&path::polygon_commands(&points, false))
+                }
                 Shape::Rectangle { min, max } => Aabb { min, max },
                 Shape::RoundedRectangle { min, max, .. } => Aabb { min, max },
-                Shape::Text { content, .. } => Aabb {
-                    // TODO server-side shaping
-                    min: Vec2::new(-5.0, -10.0),
-                    max: Vec2::new(content.len() as f32 * 10.0, 5.0),
-                },
+                Shape::Text {
+                    content,
+                    font,
+                    size,
+                } => {
+                    let layout = self.fonts.borrow_mut().layout(&font, &content, size);
+                    let bounds = text_bounds.insert(TextBounds::from(&layout));
+                    text_glyphs = Some(TextGlyphs::from(layout));
+                    bounds.ink.clone()
+                }
+                Shape::Paragraph {
+                    content,
+                    font,
+                    size,
+                    style,
+                } => {
+                    let mut fonts = self.fonts.borrow_mut();
+                    let layout = fonts.layout_paragraph(&font, &content, size, &style);
+                    let bounds = text_bounds.insert(TextBounds::from(&layout));
+                    text_glyphs = Some(TextGlyphs::from(layout));
+                    bounds.ink.union(&bounds.advance)
+                }
+                Shape::RichText { spans, paragraph } => {
+                    let (text, styles) = StyledRange::from_spans(&spans);
+                    let mut fonts = self.fonts.borrow_mut();
+                    match paragraph {
+                        None => {
+                            let layout = fonts.layout_styled(&text, &styles);
+                            let bounds = text_bounds.insert(TextBounds::from(&layout));
+                            text_glyphs = Some(TextGlyphs::from(layout));
+                            bounds.ink.clone()
+                        }
+                        Some(style) => {
+                            let layout = fonts.layout_paragraph_styled(&text, &styles, &style);
+                            let bounds = text_bounds.insert(TextBounds::from(&layout));
+                            text_glyphs = Some(TextGlyphs::from(layout));
+                            bounds.ink.union(&bounds.advance)
+                        }
+                    }
+                }
+                Shape::Path { commands, .. } => path::path_aabb(&commands),
+                Shape::Image { min, max, .. } => Aabb { min, max },
             },
             NodeKind::Operation { operation, child } => {
                 let child_aabb = self.nodes[*child].aabb.clone();
@@ -302,19 +648,11 @@ impl Tree {
                         max: child_aabb.max + *offset,
                     },
                     Operation::Rotation { angle } => {
-                        let corners = child_aabb.corners();
-
                         let mat = Mat2::from_angle(*angle);
-                        let mut min = Vec2::INFINITY;
-                        let mut max = Vec2::NEG_INFINITY;
-
-                        for corner in corners {
-                            let corner = mat * corner;
-                            min = min.min(corner);
-                            max = max.max(corner);
-                        }
-
-                        Aabb { min, max }
+                        child_aabb.transform(|corner| mat * corner)
+                    }
+                    Operation::Transform { transform } => {
+                        child_aabb.transform(|corner| transform.transform_point2(corner))
                     }
                     Operation::Scale { scale } => Aabb {
                         min: child_aabb.min * *scale,
@@ -324,6 +662,14 @@ impl Tree {
                         min: child_aabb.min - *radius,
                         max: child_aabb.max + *radius,
                     },
+                    Operation::Outline(style) => {
+                        let extent = path::outline_extent(style);
+                        Aabb {
+                            min: child_aabb.min - extent,
+                            max: child_aabb.max + extent,
+                        }
+                    }
+                    Operation::Clip(clip) => child_aabb.intersection(&path::clip_aabb(clip)),
                     _ => child_aabb,
                 }
             }
@@ -338,33 +684,72 @@ impl Tree {
             }
         };
 
-        Node::new(kind, aabb)
+        (aabb, text_bounds, text_glyphs)
+    }
+
+    /// Lays out the text that uses a font resource again, along with the
+    /// bounding boxes of the nodes containing it.
+    ///
+    /// Text is laid out with fallback fonts until the font resources it uses
+    /// are uploaded. [Tree::upload_resource] calls this for this tree, and
+    /// other trees that share the same resources should call it too once a
+    /// font is uploaded through [Resources::upload][resource::Resources::upload].
+    pub fn relayout_resource(&mut self, hash: ResourceHash) {
+        self.relayout_node(0, hash);
+    }
+
+    /// Lays a node out again if it or any of its descendants uses a resource,
+    /// returning whether it was.
+    fn relayout_node(&mut self, index: usize, hash: ResourceHash) -> bool {
+        let children = match &self.nodes[index].kind {
+            NodeKind::Shape(shape) => {
+                if !resource::shape_resources(shape).contains(&hash) {
+                    return false;
+                }
+
+                Vec::new()
+            }
+            NodeKind::Operation { child, .. } => vec![*child],
+            NodeKind::Group(children) => children.clone(),
+        };
+
+        let mut changed = children.is_empty();
+        for child in children {
+            changed |= self.relayout_node(child, hash);
+        }
+
+        if changed {
+            let (aabb, text_bounds, text_glyphs) = self.lay_out_node(&self.nodes[index].kind);
+            let node = &mut self.nodes[index];
+            node.aabb = aabb;
+            node.text_bounds = text_bounds;
+            node.text_glyphs = text_glyphs;
+        }
+
+        changed
     }
 
     /// Walks the entire tree using a type implementing [WalkTree].
+    ///
+    /// Nodes outside of `aabb`, or outside the regions of the
+    /// [Operation::Clip] nodes they're in, are skipped.
     pub fn walk(&mut self, walker: &mut impl WalkTree, aabb: &Aabb) {
         let mut stack = Vec::new();
         let mut transforms = vec![Mat3::default()];
+        let mut clips = vec![aabb.clone()];
         stack.push((0, true));
 
         while let Some((index, ascending)) = stack.pop() {
             let node = self.nodes.get(index).unwrap();
-            let current_transform = transforms.last().unwrap().clone();
+            let current_transform = *transforms.last().unwrap();
+            let current_clip = clips.last().unwrap();
 
             if ascending {
-                let corners = node.aabb.corners();
+                let child_aabb = node
+                    .aabb
+                    .transform(|corner| current_transform.transform_point2(corner));
 
-                let mut min = Vec2::INFINITY;
-                let mut max = Vec2::NEG_INFINITY;
-
-                for corner in corners {
-                    let corner = current_transform.transform_point2(corner);
-                    min = min.min(corner);
-                    max = max.max(corner);
-                }
-
-                let child_aabb = Aabb { min, max };
-                if !aabb.is_intersecting(&child_aabb) {
+                if !current_clip.is_intersecting(&child_aabb) {
                     continue;
                 }
 
@@ -372,10 +757,15 @@ impl Tree {
             }
 
             match &node.kind {
-                NodeKind::Shape(shape) if ascending => walker.on_shape(shape),
+                NodeKind::Shape(shape) if ascending => {
+                    walker.on_shape(shape, node.text_glyphs.as_ref())
+                }
                 NodeKind::Operation { operation, child } => {
                     if ascending {
-                        walker.push_operation(operation);
+                        let aabb = node
+                            .aabb
+                            .transform(|corner| current_transform.transform_point2(corner));
+                        walker.push_operation(operation, &aabb);
                         stack.push((index, false));
                         stack.push((*child, true));
 
@@ -387,21 +777,32 @@ impl Tree {
                             Operation::Scale { scale } => {
                                 Some(Mat3::from_scale(Vec2::splat(*scale)))
                             }
+                            Operation::Transform { transform } => Some(Mat3::from(*transform)),
                             _ => None,
                         };
 
                         if let Some(new_transform) = new_transform {
                             transforms.push(current_transform * new_transform);
                         }
+
+                        if let Operation::Clip(clip) = operation {
+                            let clip_aabb = path::clip_aabb(clip)
+                                .transform(|corner| current_transform.transform_point2(corner));
+                            clips.push(current_clip.intersection(&clip_aabb));
+                        }
                     } else {
                         walker.pop_operation(operation);
 
                         match operation {
                             Operation::Translate { .. }
                             | Operation::Rotation { .. }
-                            | Operation::Scale { .. } => {
+                            | Operation::Scale { .. }
+                            | Operation::Transform { .. } => {
                                 transforms.pop();
                             }
+                            Operation::Clip(_) => {
+                                clips.pop();
+                            }
                             _ => {}
                         }
                     }
@@ -420,10 +821,109 @@ impl Tree {
     }
 }
 
+/// Checks that the new nodes of an update can be bounded.
+///
+/// Arcs are bounded by the quarter turns that they sweep through, so their
+/// angles need to be finite.
+fn validate_content(content: &NodeContent) -> NodeUpdateResult<()> {
+    match content {
+        NodeContent::Shape(shape) => validate_shape(shape),
+        NodeContent::Operation { operation, child } => {
+            validate_operation(operation)?;
+            validate_child(child)
+        }
+        NodeContent::Group { new_children } => {
+            new_children.iter().flatten().try_for_each(validate_child)
+        }
+    }
+}
+
+fn validate_child(child: &ChildUpdate) -> NodeUpdateResult<()> {
+    match child {
+        ChildUpdate::KeepIndex(_) => Ok(()),
+        ChildUpdate::NewNode(node) => validate_new_node(node),
+    }
+}
+
+fn validate_new_node(node: &NewNode) -> NodeUpdateResult<()> {
+    match node {
+        NewNode::Shape(shape) => validate_shape(shape),
+        NewNode::Operation { operation, child } => {
+            validate_operation(operation)?;
+            validate_new_node(child)
+        }
+        NewNode::Group { children } => children.iter().try_for_each(validate_new_node),
+    }
+}
+
+fn validate_shape(shape: &Shape) -> NodeUpdateResult<()> {
+    match shape {
+        Shape::Arc {
+            start_angle,
+            sweep_angle,
+            ..
+        } => validate_angles(&[*start_angle, *sweep_angle]),
+        Shape::Path { commands, .. } => validate_commands(commands),
+        Shape::Text { font, .. } | Shape::Paragraph { font, .. } => validate_font(font),
+        Shape::RichText { spans, .. } => {
+            spans.iter().try_for_each(|span| validate_font(&span.font))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_font(font: &Font) -> NodeUpdateResult<()> {
+    match font.features.iter().find(|feature| !feature.is_valid()) {
+        Some(feature) => Err(NodeUpdateError::InvalidFontFeature(feature.tag)),
+        None => Ok(()),
+    }
+}
+
+fn validate_operation(operation: &Operation) -> NodeUpdateResult<()> {
+    match operation {
+        Operation::Clip(Clip::Path { commands, .. }) => validate_commands(commands),
+        _ => Ok(()),
+    }
+}
+
+fn validate_commands(commands: &[PathCommand]) -> NodeUpdateResult<()> {
+    for command in commands.iter() {
+        if let PathCommand::Arc {
+            start_angle,
+            sweep_angle,
+            ..
+        } = command
+        {
+            validate_angles(&[*start_angle, *sweep_angle])?;
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_angles(angles: &[f32]) -> NodeUpdateResult<()> {
+    match angles.iter().all(|angle| angle.is_finite()) {
+        true => Ok(()),
+        false => Err(NodeUpdateError::NonFiniteAngle),
+    }
+}
+
+impl Drop for Tree {
+    fn drop(&mut self) {
+        for (_, node) in self.nodes.iter() {
+            self.release_resources(node);
+        }
+    }
+}
+
 pub trait WalkTree {
-    fn on_shape(&mut self, shape: &Shape);
+    /// Visits a shape. `text` is the layout of text shapes, which should be
+    /// drawn instead of laying the text out again.
+    fn on_shape(&mut self, shape: &Shape, text: Option<&TextGlyphs>);
 
-    fn push_operation(&mut self, operation: &Operation);
+    /// Visits an operation before its child. `aabb` bounds the operation's
+    /// node in the space of the tree's root.
+    fn push_operation(&mut self, operation: &Operation, aabb: &Aabb);
 
     fn pop_operation(&mut self, operation: &Operation);
 
@@ -434,6 +934,8 @@ pub trait WalkTree {
 mod tests {
     use super::*;
 
+    use resource::ResourceError;
+
     use glam::Vec2;
 
     #[test]
@@ -559,6 +1061,742 @@ mod tests {
         assert_eq!(result, Err(NodeUpdateError::UnownedKeepIndex(0)));
     }
 
+    #[test]
+    fn text_bounds_fit_glyphs() {
+        let mut tree = Tree::new();
+        let shape = Shape::Text {
+            content: "Hello".to_string(),
+            font: Font::default(),
+            size: 10.0,
+        };
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(shape),
+        })
+        .unwrap();
+
+        let node = tree.get_node(0).unwrap();
+        let bounds = node.get_text_bounds().unwrap();
+        assert_eq!(node.aabb, bounds.ink);
+
+        // the ink sits above the baseline and inside the advance box
+        assert!(bounds.ink.min.y < 0.0 && bounds.ink.max.y <= 0.5);
+        assert!(bounds.ink.min.x >= 0.0 && bounds.ink.max.x <= bounds.advance.max.x);
+        assert!(bounds.advance.min.y <= bounds.ink.min.y);
+        assert!(bounds.advance.max.x > 20.0 && bounds.advance.max.x < 40.0);
+    }
+
+    /// Collects the text layouts that a [Tree::walk] passes to its walker.
+    #[derive(Default)]
+    struct TextCollector(Vec<TextGlyphs>);
+
+    impl WalkTree for TextCollector {
+        fn on_shape(&mut self, _shape: &Shape, text: Option<&TextGlyphs>) {
+            self.0.extend(text.cloned());
+        }
+
+        fn push_operation(&mut self, _operation: &Operation, _aabb: &Aabb) {}
+
+        fn pop_operation(&mut self, _operation: &Operation) {}
+
+        fn on_aabb(&mut self, _aabb: &Aabb) {}
+    }
+
+    #[test]
+    fn walks_draw_measured_text() {
+        let mut tree = Tree::new();
+        let shape = Shape::Text {
+            content: "Hello".to_string(),
+            font: Font::default(),
+            size: 10.0,
+        };
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(shape),
+        })
+        .unwrap();
+
+        let glyphs = tree.get_node(0).unwrap().get_text_glyphs().unwrap().clone();
+        assert_eq!(glyphs.glyphs.len(), 5);
+
+        let viewport = Aabb {
+            min: vec2(-100.0, -100.0),
+            max: vec2(100.0, 100.0),
+        };
+
+        let mut collector = TextCollector::default();
+        tree.walk(&mut collector, &viewport);
+        assert_eq!(collector.0, [glyphs]);
+    }
+
+    #[test]
+    fn empty_text_has_no_ink() {
+        let mut tree = Tree::new();
+        let shape = Shape::Text {
+            content: " ".to_string(),
+            font: Font::default(),
+            size: 10.0,
+        };
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(shape),
+        })
+        .unwrap();
+
+        let node = tree.get_node(0).unwrap();
+        assert_eq!(node.aabb, Aabb::INVALID);
+        assert!(node.get_text_bounds().unwrap().advance.max.x > 0.0);
+    }
+
+    #[test]
+    fn paragraph_bounds_cover_wrapped_lines() {
+        let mut tree = Tree::new();
+        let shape = Shape::Paragraph {
+            content: "the quick brown fox jumps over the lazy dog".to_string(),
+            font: Font::default(),
+            size: 10.0,
+            style: ParagraphStyle::new(60.0),
+        };
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(shape),
+        })
+        .unwrap();
+
+        let node = tree.get_node(0).unwrap();
+        let bounds = node.get_text_bounds().unwrap();
+        assert_eq!(bounds.advance.min, Vec2::ZERO);
+        assert_eq!(bounds.advance.max.x, 60.0);
+        assert!(bounds.advance.max.y > 20.0);
+        assert!(node.aabb.max.y >= bounds.ink.max.y);
+    }
+
+    #[test]
+    fn rich_text_bounds_cover_backgrounds() {
+        let mut tree = Tree::new();
+        let spans = vec![
+            TextSpan::new("plain ", Font::default(), 10.0),
+            TextSpan::new("code", Font::default(), 10.0).with_background(Color::WHITE),
+        ];
+
+        let shape = Shape::RichText {
+            spans,
+            paragraph: None,
+        };
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(shape),
+        })
+        .unwrap();
+
+        let node = tree.get_node(0).unwrap();
+        let bounds = node.get_text_bounds().unwrap();
+        assert_eq!(node.aabb, bounds.ink);
+
+        // the background spans the whole line height of its span
+        assert_eq!(bounds.ink.min.y, bounds.advance.min.y);
+        assert_eq!(bounds.ink.max.y, bounds.advance.max.y);
+        assert_eq!(bounds.ink.max.x, bounds.advance.max.x);
+    }
+
+    fn path_bounds(commands: Vec<PathCommand>) -> Aabb {
+        let mut tree = Tree::new();
+        let shape = Shape::Path {
+            commands,
+            fill_rule: FillRule::NonZero,
+        };
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(shape),
+        })
+        .unwrap();
+
+        tree.get_node(0).unwrap().aabb.clone()
+    }
+
+    #[test]
+    fn path_bounds_hug_curves() {
+        let aabb = path_bounds(vec![
+            PathCommand::MoveTo(Vec2::ZERO),
+            PathCommand::CubicTo {
+                ctrl1: vec2(0.0, 10.0),
+                ctrl2: vec2(10.0, 10.0),
+                to: vec2(10.0, 0.0),
+            },
+            PathCommand::Close,
+        ]);
+
+        // the curve peaks below its control points
+        assert_eq!(aabb.min, Vec2::ZERO);
+        assert_eq!(aabb.max, vec2(10.0, 7.5));
+    }
+
+    #[test]
+    fn path_bounds_include_arc_extremes() {
+        let aabb = path_bounds(vec![
+            PathCommand::MoveTo(vec2(5.0, 0.0)),
+            PathCommand::Arc {
+                center: Vec2::ZERO,
+                radius: 5.0,
+                start_angle: 0.0,
+                sweep_angle: std::f32::consts::PI,
+            },
+        ]);
+
+        assert!(aabb.min.abs_diff_eq(vec2(-5.0, 0.0), 0.001));
+        assert!(aabb.max.abs_diff_eq(vec2(5.0, 5.0), 0.001));
+    }
+
+    #[test]
+    fn huge_arc_angles_are_bounded() {
+        let aabb = path_bounds(vec![
+            PathCommand::MoveTo(Vec2::ZERO),
+            PathCommand::Arc {
+                center: Vec2::ZERO,
+                radius: 5.0,
+                start_angle: 1e10,
+                sweep_angle: 1e10,
+            },
+        ]);
+
+        assert!(aabb.min.abs_diff_eq(Vec2::splat(-5.0), 0.001));
+        assert!(aabb.max.abs_diff_eq(Vec2::splat(5.0), 0.001));
+    }
+
+    #[test]
+    fn non_finite_arc_angles_are_rejected() {
+        let mut tree = Tree::new();
+        for angle in [f32::INFINITY, f32::NEG_INFINITY, f32::NAN] {
+            let arc = PathCommand::Arc {
+                center: Vec2::ZERO,
+                radius: 5.0,
+                start_angle: angle,
+                sweep_angle: 1.0,
+            };
+
+            let path = Shape::Path {
+                commands: vec![PathCommand::MoveTo(Vec2::ZERO), arc],
+                fill_rule: FillRule::NonZero,
+            };
+
+            let clip = NewNode::Operation {
+                operation: Operation::Clip(Clip::Path {
+                    commands: vec![arc],
+                    fill_rule: FillRule::NonZero,
+                }),
+                child: NewNode::Shape(Shape::Empty).into(),
+            };
+
+            let result = tree.update_node(NodeUpdate {
+                target: 0,
+                content: NodeContent::Shape(path),
+            });
+
+            assert_eq!(result, Err(NodeUpdateError::NonFiniteAngle));
+
+            let result = tree.update_node(NodeUpdate {
+                target: 0,
+                content: vec![clip].into(),
+            });
+
+            assert_eq!(result, Err(NodeUpdateError::NonFiniteAngle));
+        }
+    }
+
+    #[test]
+    fn pie_bounds_include_center() {
+        let mut tree = Tree::new();
+        let shape = Shape::Arc {
+            radius: 10.0,
+            inner_radius: 0.0,
+            start_angle: 0.0,
+            sweep_angle: std::f32::consts::FRAC_PI_2,
+        };
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(shape),
+        })
+        .unwrap();
+
+        // a quarter of a circle below and right of the origin
+        let aabb = &tree.get_node(0).unwrap().aabb;
+        assert!(aabb.min.abs_diff_eq(Vec2::ZERO, 0.001));
+        assert!(aabb.max.abs_diff_eq(Vec2::splat(10.0), 0.001));
+    }
+
+    #[test]
+    fn arc_shape_angles_are_validated() {
+        let arc = |start_angle, sweep_angle| Shape::Arc {
+            radius: 10.0,
+            inner_radius: 5.0,
+            start_angle,
+            sweep_angle,
+        };
+
+        let mut tree = Tree::new();
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(arc(1e10, 1e10)),
+        })
+        .unwrap();
+
+        let aabb = &tree.get_node(0).unwrap().aabb;
+        assert!(aabb.min.abs_diff_eq(Vec2::splat(-10.0), 0.001));
+        assert!(aabb.max.abs_diff_eq(Vec2::splat(10.0), 0.001));
+
+        for (start, sweep) in [(f32::INFINITY, 1.0), (0.0, f32::NAN)] {
+            let result = tree.update_node(NodeUpdate {
+                target: 0,
+                content: NodeContent::Shape(arc(start, sweep)),
+            });
+
+            assert_eq!(result, Err(NodeUpdateError::NonFiniteAngle));
+        }
+    }
+
+    #[test]
+    fn font_features_are_validated() {
+        let valid = Font::default().with_feature(FontFeature::on(*b"ss20"));
+        let invalid = Font::default().with_feature(FontFeature::on(*b"ss21"));
+
+        let mut tree = Tree::new();
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(Shape::Text {
+                content: "Hello".to_string(),
+                font: valid.clone(),
+                size: 16.0,
+            }),
+        })
+        .unwrap();
+
+        let shapes = [
+            Shape::Text {
+                content: "Hello".to_string(),
+                font: invalid.clone(),
+                size: 16.0,
+            },
+            Shape::RichText {
+                spans: vec![
+                    TextSpan::new("Hello, ", valid, 16.0),
+                    TextSpan::new("world!", invalid, 16.0),
+                ],
+                paragraph: None,
+            },
+        ];
+
+        for shape in shapes {
+            let result = tree.update_node(NodeUpdate {
+                target: 0,
+                content: NodeContent::Shape(shape),
+            });
+
+            assert_eq!(result, Err(NodeUpdateError::InvalidFontFeature(*b"ss21")));
+        }
+    }
+
+    #[test]
+    fn outlines_inflate_bounds() {
+        let mut tree = Tree::new();
+        let style = OutlineStyle::new(4.0).with_join(LineJoin::Round);
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Operation {
+                operation: Operation::Outline(style),
+                child: NewNode::Shape(Shape::Rectangle {
+                    min: Vec2::ZERO,
+                    max: Vec2::splat(10.0),
+                })
+                .into(),
+            },
+        })
+        .unwrap();
+
+        let aabb = &tree.get_node(0).unwrap().aabb;
+        assert_eq!(aabb.min, Vec2::splat(-2.0));
+        assert_eq!(aabb.max, Vec2::splat(12.0));
+    }
+
+    fn pixel(rgba: [u8; 4]) -> ResourceUpload {
+        ResourceUpload {
+            data: ResourceData::Rgba {
+                width: 1,
+                height: 1,
+                pixels: rgba.to_vec(),
+            },
+        }
+    }
+
+    fn image(resource: ResourceHash) -> Shape {
+        Shape::Image {
+            resource,
+            min: Vec2::ZERO,
+            max: Vec2::ONE,
+            fit: ImageFit::Fill,
+            sampling: ImageSampling::Linear,
+        }
+    }
+
+    #[test]
+    fn uploaded_pixels_are_premultiplied() {
+        let mut tree = Tree::new();
+        let hash = tree
+            .upload_resource(pixel([255, 128, 0, 128]))
+            .unwrap()
+            .hash;
+        let resources = tree.resources().borrow();
+        let image = resources.get_image(&hash).unwrap();
+        assert_eq!(image.pixels, vec![0x80804000]);
+    }
+
+    #[test]
+    fn short_pixels_are_rejected() {
+        let mut tree = Tree::new();
+        let result = tree.upload_resource(ResourceUpload {
+            data: ResourceData::Rgba {
+                width: 2,
+                height: 2,
+                pixels: vec![0; 12],
+            },
+        });
+
+        assert!(matches!(
+            result,
+            Err(ResourceError::InvalidPixelCount {
+                expected: 16,
+                found: 12
+            })
+        ));
+    }
+
+    #[test]
+    fn queries_find_missing_resources() {
+        let mut tree = Tree::new();
+        let red = tree.upload_resource(pixel([255, 0, 0, 255])).unwrap().hash;
+        assert_eq!(
+            tree.upload_resource(pixel([255, 0, 0, 255])).unwrap().hash,
+            red
+        );
+
+        let green = pixel([0, 255, 0, 255]).data.hash();
+        let response = tree.query_resources(&ResourceQuery {
+            hashes: vec![red, green],
+        });
+
+        assert_eq!(response.missing, vec![green]);
+    }
+
+    #[test]
+    fn unused_resources_are_evicted() {
+        let mut tree = Tree::new();
+        let hash = tree.upload_resource(pixel([0; 4])).unwrap().hash;
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: vec![NewNode::Shape(image(hash)), NewNode::Shape(image(hash))].into(),
+        })
+        .unwrap();
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: vec![ChildUpdate::KeepIndex(1)].into(),
+        })
+        .unwrap();
+
+        assert!(tree.resources().borrow().contains(&hash));
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(Shape::Empty),
+        })
+        .unwrap();
+
+        assert!(!tree.resources().borrow().contains(&hash));
+    }
+
+    #[test]
+    fn resources_can_be_uploaded_after_use() {
+        let mut tree = Tree::new();
+        let upload = pixel([0; 4]);
+        let hash = upload.data.hash();
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(image(hash)),
+        })
+        .unwrap();
+
+        tree.upload_resource(upload).unwrap();
+        let resources = tree.resources().clone();
+        assert!(resources.borrow().contains(&hash));
+
+        drop(tree);
+        assert!(!resources.borrow().contains(&hash));
+    }
+
+    #[test]
+    fn resources_can_be_uploaded_before_use() {
+        let mut tree = Tree::new();
+        let hash = tree.upload_resource(pixel([0; 4])).unwrap().hash;
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(image(hash)),
+        })
+        .unwrap();
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(image(hash)),
+        })
+        .unwrap();
+
+        assert!(tree.resources().borrow().contains(&hash));
+    }
+
+    #[test]
+    fn unclaimed_resources_are_evicted_at_the_end_of_a_frame() {
+        let mut tree = Tree::new();
+        let hash = tree.upload_resource(pixel([0; 4])).unwrap().hash;
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Shape(Shape::Empty),
+        })
+        .unwrap();
+
+        assert!(tree.resources().borrow().contains(&hash));
+        tree.evict_unclaimed_resources();
+        assert!(!tree.resources().borrow().contains(&hash));
+    }
+
+    #[test]
+    fn updates_keep_resources_uploaded_for_other_trees() {
+        let mut first = Tree::new();
+        let fonts = first.fonts().clone();
+        let resources = first.resources().clone();
+        let mut second = Tree::with_shared(fonts, resources);
+        let hash = second.upload_resource(pixel([0; 4])).unwrap().hash;
+
+        first
+            .update_node(NodeUpdate {
+                target: 0,
+                content: NodeContent::Shape(Shape::Empty),
+            })
+            .unwrap();
+
+        second
+            .update_node(NodeUpdate {
+                target: 0,
+                content: NodeContent::Shape(image(hash)),
+            })
+            .unwrap();
+
+        first.evict_unclaimed_resources();
+        assert!(first.resources().borrow().contains(&hash));
+    }
+
+    #[test]
+    fn text_is_laid_out_again_when_its_font_is_uploaded() {
+        let upload = ResourceUpload {
+            data: ResourceData::Font(notosans::BOLD_TTF.to_vec()),
+        };
+
+        let text = Shape::Text {
+            content: "Willow".to_string(),
+            font: Font {
+                resource: Some(upload.data.hash()),
+                ..Default::default()
+            },
+            size: 16.0,
+        };
+
+        let translated = NewNode::Operation {
+            operation: Operation::Translate { offset: Vec2::ONE },
+            child: NewNode::Shape(text.clone()).into(),
+        };
+
+        let mut tree = Tree::new();
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: vec![translated].into(),
+        })
+        .unwrap();
+
+        let fallback = tree.get_node(0).unwrap().get_aabb().clone();
+        tree.upload_resource(upload).unwrap();
+        let root = tree.get_node(0).unwrap().get_aabb().clone();
+        assert_ne!(root, fallback);
+
+        // the bounds match the text laid out with the font from the start
+        let mut expected = Tree::with_shared(tree.fonts().clone(), tree.resources().clone());
+        expected
+            .update_node(NodeUpdate {
+                target: 0,
+                content: NodeContent::Shape(text),
+            })
+            .unwrap();
+
+        let text = expected.get_node(0).unwrap().get_aabb();
+        assert_eq!(root.min, text.min + Vec2::ONE);
+        assert_eq!(root.max, text.max + Vec2::ONE);
+    }
+
+    #[test]
+    fn removed_subtrees_release_resources() {
+        let mut tree = Tree::new();
+        let hash = tree.upload_resource(pixel([0; 4])).unwrap().hash;
+
+        let translated = NewNode::Operation {
+            operation: Operation::Translate { offset: Vec2::ONE },
+            child: NewNode::Shape(image(hash)).into(),
+        };
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: vec![translated].into(),
+        })
+        .unwrap();
+
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: Vec::<ChildUpdate>::new().into(),
+        })
+        .unwrap();
+
+        assert!(!tree.resources().borrow().contains(&hash));
+        assert_eq!(tree.nodes.len(), 1);
+    }
+
+    #[test]
+    fn images_fit_their_rectangles() {
+        let size = vec2(20.0, 10.0);
+        let min = Vec2::ZERO;
+        let max = vec2(10.0, 10.0);
+
+        let contain = resource::fit_image(ImageFit::Contain, size, min, max);
+        assert_eq!(contain.min, vec2(0.0, 2.5));
+        assert_eq!(contain.max, vec2(10.0, 7.5));
+
+        let cover = resource::fit_image(ImageFit::Cover, size, min, max);
+        assert_eq!(cover.min, vec2(-5.0, 0.0));
+        assert_eq!(cover.max, vec2(15.0, 10.0));
+
+        let none = resource::fit_image(ImageFit::None, size, min, max);
+        assert_eq!(none.min, vec2(-5.0, 0.0));
+        assert_eq!(none.max, vec2(15.0, 10.0));
+    }
+
+    fn transformed_bounds(operation: Operation, min: Vec2, max: Vec2) -> Aabb {
+        let mut tree = Tree::new();
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Operation {
+                operation,
+                child: NewNode::Shape(Shape::Rectangle { min, max }).into(),
+            },
+        })
+        .unwrap();
+
+        tree.get_node(0).unwrap().aabb.clone()
+    }
+
+    #[test]
+    fn transforms_map_bounds() {
+        let scale = Operation::scale_axes(vec2(2.0, 3.0));
+        let aabb = transformed_bounds(scale, Vec2::ONE, vec2(2.0, 2.0));
+        assert_eq!(aabb.min, vec2(2.0, 3.0));
+        assert_eq!(aabb.max, vec2(4.0, 6.0));
+
+        let rotation = Operation::rotation_about(std::f32::consts::FRAC_PI_2, Vec2::splat(5.0));
+        let aabb = transformed_bounds(rotation, Vec2::ZERO, vec2(10.0, 2.0));
+        assert!(aabb.min.abs_diff_eq(vec2(8.0, 0.0), 1e-5));
+        assert!(aabb.max.abs_diff_eq(vec2(10.0, 10.0), 1e-5));
+
+        let skew = Operation::skew(vec2(std::f32::consts::FRAC_PI_4, 0.0));
+        let aabb = transformed_bounds(skew, Vec2::ZERO, vec2(10.0, 2.0));
+        assert!(aabb.min.abs_diff_eq(Vec2::ZERO, 1e-5));
+        assert!(aabb.max.abs_diff_eq(vec2(12.0, 2.0), 1e-5));
+    }
+
+    #[test]
+    fn clips_limit_bounds() {
+        let clip = Operation::Clip(Clip::Rectangle {
+            min: Vec2::ZERO,
+            max: vec2(5.0, 5.0),
+        });
+
+        let aabb = transformed_bounds(clip.clone(), vec2(2.0, -2.0), vec2(10.0, 4.0));
+        assert_eq!(aabb.min, vec2(2.0, 0.0));
+        assert_eq!(aabb.max, vec2(5.0, 4.0));
+
+        let aabb = transformed_bounds(clip, vec2(6.0, 6.0), vec2(8.0, 8.0));
+        assert_eq!(aabb, Aabb::INVALID);
+    }
+
+    /// Counts the shapes that a [Tree::walk] draws.
+    #[derive(Default)]
+    struct ShapeCounter(usize);
+
+    impl WalkTree for ShapeCounter {
+        fn on_shape(&mut self, _shape: &Shape, _text: Option<&TextGlyphs>) {
+            self.0 += 1;
+        }
+
+        fn push_operation(&mut self, _operation: &Operation, _aabb: &Aabb) {}
+
+        fn pop_operation(&mut self, _operation: &Operation) {}
+
+        fn on_aabb(&mut self, _aabb: &Aabb) {}
+    }
+
+    #[test]
+    fn clips_cull_children() {
+        let rect = |x: f32| NewNode::Operation {
+            operation: Operation::Translate {
+                offset: vec2(x, 0.0),
+            },
+            child: NewNode::Shape(Shape::Rectangle {
+                min: Vec2::ZERO,
+                max: Vec2::ONE,
+            })
+            .into(),
+        };
+
+        // the group's bounds overlap the clip, but only one of its children
+        let mut tree = Tree::new();
+        tree.update_node(NodeUpdate {
+            target: 0,
+            content: NodeContent::Operation {
+                operation: Operation::Clip(Clip::Rectangle {
+                    min: Vec2::ZERO,
+                    max: vec2(2.0, 2.0),
+                }),
+                child: NewNode::Group {
+                    children: vec![rect(0.0), rect(10.0)],
+                }
+                .into(),
+            },
+        })
+        .unwrap();
+
+        let viewport = Aabb {
+            min: Vec2::ZERO,
+            max: vec2(100.0, 100.0),
+        };
+
+        let mut counter = ShapeCounter::default();
+        tree.walk(&mut counter, &viewport);
+        assert_eq!(counter.0, 1);
+    }
+
     #[test]
     fn failed_update_unsets_node_flags() {
         let mut tree = Tree::new();
@@ -0,0 +1,320 @@
+// Copyright (C) 2023 Marceline Cramer
+//
+// Willow is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// Willow is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with Willow.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::f32::consts::{FRAC_PI_2, TAU};
+
+use willow_protocol::{
+    glam::{Vec2, Vec4},
+    Clip, LineCap, LineJoin, OutlineStyle, PathCommand,
+};
+
+use crate::Aabb;
+
+/// Creates the outline of a [Shape::Arc][crate::Shape::Arc].
+pub fn arc_commands(
+    radius: f32,
+    inner_radius: f32,
+    start_angle: f32,
+    sweep_angle: f32,
+) -> Vec<PathCommand> {
+    let outer = PathCommand::Arc {
+        center: Vec2::ZERO,
+        radius,
+        start_angle,
+        sweep_angle,
+    };
+
+    let start = Vec2::from_angle(start_angle) * radius;
+    if inner_radius <= 0.0 {
+        let center = PathCommand::LineTo(Vec2::ZERO);
+        return vec![
+            PathCommand::MoveTo(start),
+            outer,
+            center,
+            PathCommand::Close,
+        ];
+    }
+
+    // the inner edge runs backwards, so that a full ring leaves a hole
+    let end_angle = start_angle + sweep_angle;
+    let inner = PathCommand::Arc {
+        center: Vec2::ZERO,
+        radius: inner_radius,
+        start_angle: end_angle,
+        sweep_angle: -sweep_angle,
+    };
+
+    if sweep_angle.abs() < TAU {
+        return vec![PathCommand::MoveTo(start), outer, inner, PathCommand::Close];
+    }
+
+    // full rings have separate edges, so their outlines have no seam
+    let inner_start = Vec2::from_angle(end_angle) * inner_radius;
+    vec![
+        PathCommand::MoveTo(start),
+        outer,
+        PathCommand::Close,
+        PathCommand::MoveTo(inner_start),
+        inner,
+        PathCommand::Close,
+    ]
+}
+
+/// Creates the outline of a [Shape::Polygon][crate::Shape::Polygon], or of a
+/// [Shape::Polyline][crate::Shape::Polyline] if `close` is false.
+pub fn polygon_commands(points: &[Vec2], close: bool) -> Vec<PathCommand> {
+    let mut commands = Vec::with_capacity(points.len() + 1);
+    let mut points = points.iter().copied();
+    commands.extend(points.next().map(PathCommand::MoveTo));
+    commands.extend(points.map(PathCommand::LineTo));
+
+    if close {
+        commands.push(PathCommand::Close);
+    }
+
+    commands
+}
+
+/// Creates the outline of a
+/// [Shape::RoundedRectangle][crate::Shape::RoundedRectangle].
+///
+/// The corners are approximated with cubic Bézier curves.
+pub fn rounded_rectangle_commands(min: Vec2, max: Vec2, radii: Vec4) -> Vec<PathCommand> {
+    let aabb = Aabb { min, max };
+
+    // the directions into each corner along its edges, clockwise
+    let get_offsets = |corner_idx| match corner_idx {
+        0 => (Vec2::Y, Vec2::X),
+        1 => (-Vec2::X, Vec2::Y),
+        2 => (-Vec2::Y, -Vec2::X),
+        3 => (Vec2::X, -Vec2::Y),
+        _ => unreachable!(),
+    };
+
+    // approximate quarter circle control point offset
+    let control_offset = 0.446;
+
+    let mut commands = vec![PathCommand::MoveTo(min + Vec2::Y * radii.x)];
+    for (idx, corner) in aabb.corners().into_iter().enumerate() {
+        let (loff, roff) = get_offsets(idx);
+        let radius = radii[idx];
+        let control_offset = control_offset * radius;
+
+        commands.push(PathCommand::LineTo(corner + loff * radius));
+        commands.push(PathCommand::CubicTo {
+            ctrl1: corner + loff * control_offset,
+            ctrl2: corner + roff * control_offset,
+            to: corner + roff * radius,
+        });
+    }
+
+    commands.push(PathCommand::Close);
+    commands
+}
+
+/// Creates the outline of the region of an [Operation::Clip][crate::Operation::Clip].
+pub fn clip_commands(clip: &Clip) -> Vec<PathCommand> {
+    match clip {
+        Clip::Rectangle { min, max } => {
+            let corners = Aabb {
+                min: *min,
+                max: *max,
+            }
+            .corners();
+            polygon_commands(&corners, true)
+        }
+        Clip::RoundedRectangle { min, max, radii } => {
+            rounded_rectangle_commands(*min, *max, *radii)
+        }
+        Clip::Path { commands, .. } => commands.clone(),
+    }
+}
+
+/// Computes the bounds of the region of an [Operation::Clip][crate::Operation::Clip].
+pub fn clip_aabb(clip: &Clip) -> Aabb {
+    match clip {
+        Clip::Rectangle { min, max } | Clip::RoundedRectangle { min, max, .. } => Aabb {
+            min: *min,
+            max: *max,
+        },
+        Clip::Path { commands, .. } => path_aabb(commands),
+    }
+}
+
+/// Computes the exact bounds of the outline of a [Shape::Path][crate::Shape::Path].
+///
+/// Curves are bounded by their endpoints and the points where they turn
+/// around horizontally or vertically, not by their control points.
+pub fn path_aabb(commands: &[PathCommand]) -> Aabb {
+    let mut bounds = Bounder::default();
+    let mut pen = Vec2::ZERO;
+    let mut start = Vec2::ZERO;
+
+    for command in commands.iter() {
+        use PathCommand::*;
+        match *command {
+            MoveTo(to) => {
+                pen = to;
+                start = to;
+            }
+            LineTo(to) => {
+                bounds.add(pen);
+                bounds.add(to);
+                pen = to;
+            }
+            QuadTo { ctrl, to } => {
+                bounds.add(pen);
+                bounds.add(to);
+                for t in quad_extrema(pen, ctrl, to) {
+                    let u = 1.0 - t;
+                    bounds.add(pen * u * u + ctrl * 2.0 * u * t + to * t * t);
+                }
+
+                pen = to;
+            }
+            CubicTo { ctrl1, ctrl2, to } => {
+                bounds.add(pen);
+                bounds.add(to);
+                for t in cubic_extrema(pen, ctrl1, ctrl2, to) {
+                    let u = 1.0 - t;
+                    bounds.add(
+                        pen * u * u * u
+                            + ctrl1 * 3.0 * u * u * t
+                            + ctrl2 * 3.0 * u * t * t
+                            + to * t * t * t,
+                    );
+                }
+
+                pen = to;
+            }
+            Arc {
+                center,
+                radius,
+                start_angle,
+                sweep_angle,
+            } => {
+                let point = |angle: f32| center + Vec2::from_angle(angle) * radius;
+                let end_angle = start_angle + sweep_angle;
+                bounds.add(pen);
+                bounds.add(point(start_angle));
+                bounds.add(point(end_angle));
+
+                // add each axis-aligned extreme of the circle the arc passes
+                let (low, high) = match sweep_angle < 0.0 {
+                    true => (end_angle, start_angle),
+                    false => (start_angle, end_angle),
+                };
+
+                // an arc passes at most four extremes, however far it sweeps
+                let span = (high - low).min(TAU);
+                let low = low.rem_euclid(TAU);
+                let first = (low / FRAC_PI_2).ceil() as i32;
+                for quarter in first..first + 4 {
+                    let angle = quarter as f32 * FRAC_PI_2;
+                    if angle > low + span {
+                        break;
+                    }
+
+                    bounds.add(point(angle));
+                }
+
+                pen = point(end_angle);
+            }
+            Close => pen = start,
+        }
+    }
+
+    bounds.aabb
+}
+
+/// Finds how far the lines drawn by an [Operation::Outline][crate::Operation::Outline]
+/// can reach past the edges they outline.
+///
+/// This is half the line width, or farther where miter joins and square caps
+/// stick out past it at an angle.
+pub fn outline_extent(style: &OutlineStyle) -> f32 {
+    let mut scale: f32 = 1.0;
+
+    if style.join == LineJoin::Miter {
+        scale = scale.max(style.miter_limit);
+    }
+
+    if style.cap == LineCap::Square {
+        scale = scale.max(std::f32::consts::SQRT_2);
+    }
+
+    style.width.abs() / 2.0 * scale
+}
+
+/// Accumulates points into an [Aabb].
+struct Bounder {
+    aabb: Aabb,
+}
+
+impl Default for Bounder {
+    fn default() -> Self {
+        Self {
+            aabb: Aabb::INVALID,
+        }
+    }
+}
+
+impl Bounder {
+    fn add(&mut self, point: Vec2) {
+        self.aabb.min = self.aabb.min.min(point);
+        self.aabb.max = self.aabb.max.max(point);
+    }
+}
+
+/// Finds the parameters between 0 and 1 where a quadratic Bézier curve turns
+/// around on either axis.
+fn quad_extrema(from: Vec2, ctrl: Vec2, to: Vec2) -> impl Iterator<Item = f32> {
+    let denominator = from - ctrl * 2.0 + to;
+    let t = (from - ctrl) / denominator;
+    [(t.x, denominator.x), (t.y, denominator.y)]
+        .into_iter()
+        .filter(|(t, denominator)| *denominator != 0.0 && *t > 0.0 && *t < 1.0)
+        .map(|(t, _)| t)
+}
+
+/// Finds the parameters between 0 and 1 where a cubic Bézier curve turns
+/// around on either axis.
+fn cubic_extrema(from: Vec2, ctrl1: Vec2, ctrl2: Vec2, to: Vec2) -> Vec<f32> {
+    // the coefficients of the curve's derivative, divided by 3
+    let a = (ctrl1 - ctrl2) * 3.0 + to - from;
+    let b = (from - ctrl1 * 2.0 + ctrl2) * 2.0;
+    let c = ctrl1 - from;
+
+    let mut roots = Vec::with_capacity(4);
+    for (a, b, c) in [(a.x, b.x, c.x), (a.y, b.y, c.y)] {
+        if a.abs() < f32::EPSILON {
+            if b != 0.0 {
+                roots.push(-c / b);
+            }
+
+            continue;
+        }
+
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant >= 0.0 {
+            let root = discriminant.sqrt();
+            roots.push((-b + root) / (2.0 * a));
+            roots.push((-b - root) / (2.0 * a));
+        }
+    }
+
+    roots.retain(|t| *t > 0.0 && *t < 1.0);
+    roots
+}
@@ -0,0 +1,348 @@
+// Copyright (C) 2023 Marceline Cramer
+//
+// Willow is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// Willow is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with Willow.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Formatter;
+use std::rc::Rc;
+
+use willow_protocol::{
+    glam::Vec2, ImageFit, ResourceData, ResourceHash, ResourceQuery, ResourceQueryResponse,
+    ResourceUpload, ResourceUploadResponse, Shape,
+};
+use willow_text::color::pack_argb;
+use willow_text::{FaceId, FontError, FontRegistry};
+
+use crate::Aabb;
+
+#[derive(Debug)]
+pub enum ResourceError {
+    /// The pixels of a [ResourceData::Rgba] didn't match its size.
+    InvalidPixelCount { expected: usize, found: usize },
+
+    /// An encoded image couldn't be decoded.
+    InvalidImage,
+
+    /// An encoded image was decoded, but its pixel format isn't supported.
+    UnsupportedFormat,
+
+    /// A font file couldn't be loaded.
+    InvalidFont(FontError),
+}
+
+impl std::fmt::Display for ResourceError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> std::fmt::Result {
+        use ResourceError::*;
+        match self {
+            InvalidPixelCount { expected, found } => write!(
+                fmt,
+                "expected {} bytes of pixels, found {}",
+                expected, found
+            ),
+            InvalidImage => write!(fmt, "invalid image data"),
+            UnsupportedFormat => write!(fmt, "unsupported image pixel format"),
+            InvalidFont(err) => write!(fmt, "invalid font: {}", err),
+        }
+    }
+}
+
+pub type ResourceResult<T> = Result<T, ResourceError>;
+
+/// A decoded image resource.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+
+    /// Rows of sRGB pixels from top to bottom, packed into words as
+    /// `0xAARRGGBB` with the color components premultiplied by alpha.
+    ///
+    /// This is the format that raqote draws images from, so images are
+    /// converted once when they're uploaded instead of every time they're
+    /// drawn.
+    pub pixels: Vec<u32>,
+}
+
+impl Image {
+    /// Creates an image from the content of a [ResourceData::Rgba].
+    pub fn from_rgba(width: u32, height: u32, mut pixels: Vec<u8>) -> ResourceResult<Self> {
+        let expected = width as usize * height as usize * 4;
+        if pixels.len() != expected {
+            return Err(ResourceError::InvalidPixelCount {
+                expected,
+                found: pixels.len(),
+            });
+        }
+
+        for pixel in pixels.chunks_exact_mut(4) {
+            let alpha = pixel[3] as u32;
+            for channel in pixel[..3].iter_mut() {
+                *channel = ((*channel as u32 * alpha + 127) / 255) as u8;
+            }
+        }
+
+        Ok(Self {
+            width,
+            height,
+            pixels: pack_argb(&pixels),
+        })
+    }
+
+    /// Decodes a PNG image.
+    pub fn decode_png(data: &[u8]) -> ResourceResult<Self> {
+        let (width, height, pixels) =
+            willow_text::color::decode_png(data).ok_or(ResourceError::InvalidImage)?;
+
+        Ok(Self {
+            width,
+            height,
+            pixels: pack_argb(&pixels),
+        })
+    }
+
+    /// Decodes a JPEG image.
+    pub fn decode_jpeg(data: &[u8]) -> ResourceResult<Self> {
+        use jpeg_decoder::{Decoder, PixelFormat};
+
+        let mut decoder = Decoder::new(data);
+        let buffer = decoder.decode().map_err(|_| ResourceError::InvalidImage)?;
+        let info = decoder.info().ok_or(ResourceError::InvalidImage)?;
+
+        // JPEG images are always opaque
+        let pixels = match info.pixel_format {
+            PixelFormat::RGB24 => buffer
+                .chunks_exact(3)
+                .map(|rgb| u32::from_be_bytes([255, rgb[0], rgb[1], rgb[2]]))
+                .collect(),
+            PixelFormat::L8 => buffer
+                .iter()
+                .map(|l| u32::from_be_bytes([255, *l, *l, *l]))
+                .collect(),
+            PixelFormat::L16 | PixelFormat::CMYK32 => return Err(ResourceError::UnsupportedFormat),
+        };
+
+        Ok(Self {
+            width: info.width as u32,
+            height: info.height as u32,
+            pixels,
+        })
+    }
+
+    /// Gets the size of this image in pixels.
+    pub fn size(&self) -> Vec2 {
+        Vec2::new(self.width as f32, self.height as f32)
+    }
+}
+
+/// A stored resource.
+#[derive(Debug)]
+pub enum Resource {
+    Image(Image),
+
+    /// A font file, loaded into a [FontRegistry] as the faces with these IDs.
+    Font(Vec<FaceId>),
+}
+
+/// Finds where a [Shape::Image][crate::Shape::Image] of a given size is
+/// drawn for its fit within its rectangle.
+///
+/// The result can extend past the rectangle for [ImageFit::Cover] and
+/// [ImageFit::None], and the image is clipped to the rectangle afterwards.
+pub fn fit_image(fit: ImageFit, size: Vec2, min: Vec2, max: Vec2) -> Aabb {
+    let extent = max - min;
+    let scaled = match fit {
+        ImageFit::Fill => return Aabb { min, max },
+        ImageFit::Contain => size * (extent / size).min_element(),
+        ImageFit::Cover => size * (extent / size).max_element(),
+        ImageFit::None => size,
+    };
+
+    let min = min + (extent - scaled) / 2.0;
+    Aabb {
+        min,
+        max: min + scaled,
+    }
+}
+
+/// Finds the hashes of the resources that a shape refers to.
+pub fn shape_resources(shape: &Shape) -> Vec<ResourceHash> {
+    match shape {
+        Shape::Image { resource, .. } => vec![*resource],
+        Shape::Text { font, .. } | Shape::Paragraph { font, .. } => {
+            font.resource.into_iter().collect()
+        }
+        Shape::RichText { spans, .. } => {
+            let mut resources: Vec<_> =
+                spans.iter().filter_map(|span| span.font.resource).collect();
+            resources.sort();
+            resources.dedup();
+            resources
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// The resources uploaded to the server, stored by their hashes.
+///
+/// Resources are counted by the nodes that refer to them, and each resource
+/// is evicted once the last node referring to it is removed. A resource that
+/// no node refers to when it's uploaded is kept until the next call to
+/// [Resources::evict_unclaimed], so that it can be uploaded before the nodes
+/// that use it.
+#[derive(Debug, Default)]
+pub struct Resources {
+    resources: HashMap<ResourceHash, Resource>,
+
+    /// The number of nodes that refer to each hash, including hashes that
+    /// haven't been uploaded yet.
+    refs: HashMap<ResourceHash, usize>,
+
+    /// Stored resources that no node referred to when they were uploaded.
+    unclaimed: Vec<ResourceHash>,
+}
+
+impl Resources {
+    /// Decodes and stores an uploaded resource, if it isn't already stored.
+    ///
+    /// Font files are loaded into `fonts`.
+    pub fn upload(
+        &mut self,
+        upload: ResourceUpload,
+        fonts: &mut FontRegistry,
+    ) -> ResourceResult<ResourceUploadResponse> {
+        let hash = upload.data.hash();
+        self.store(hash, upload.data, fonts)?;
+        Ok(ResourceUploadResponse { hash })
+    }
+
+    /// Decodes and stores a resource by its hash, returning whether it wasn't
+    /// already stored.
+    pub(crate) fn store(
+        &mut self,
+        hash: ResourceHash,
+        data: ResourceData,
+        fonts: &mut FontRegistry,
+    ) -> ResourceResult<bool> {
+        if self.contains(&hash) {
+            return Ok(false);
+        }
+
+        let resource = match data {
+            ResourceData::Rgba {
+                width,
+                height,
+                pixels,
+            } => Resource::Image(Image::from_rgba(width, height, pixels)?),
+            ResourceData::Png(data) => Resource::Image(Image::decode_png(&data)?),
+            ResourceData::Jpeg(data) => Resource::Image(Image::decode_jpeg(&data)?),
+            ResourceData::Font(data) => {
+                let faces = fonts
+                    .load_resource(hash, data)
+                    .map_err(ResourceError::InvalidFont)?;
+                Resource::Font(faces)
+            }
+        };
+
+        if !self.refs.contains_key(&hash) {
+            self.unclaimed.push(hash);
+        }
+
+        self.resources.insert(hash, resource);
+        Ok(true)
+    }
+
+    /// Finds which of the queried resources aren't stored.
+    pub fn query(&self, query: &ResourceQuery) -> ResourceQueryResponse {
+        ResourceQueryResponse {
+            missing: query
+                .hashes
+                .iter()
+                .filter(|hash| !self.contains(hash))
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// Tests whether a resource is stored.
+    pub fn contains(&self, hash: &ResourceHash) -> bool {
+        self.resources.contains_key(hash)
+    }
+
+    /// Gets a stored resource by its hash.
+    pub fn get(&self, hash: &ResourceHash) -> Option<&Resource> {
+        self.resources.get(hash)
+    }
+
+    /// Gets an image resource by its hash.
+    pub fn get_image(&self, hash: &ResourceHash) -> Option<&Image> {
+        match self.resources.get(hash)? {
+            Resource::Image(image) => Some(image),
+            _ => None,
+        }
+    }
+
+    /// Counts a new reference to a resource, which doesn't need to have been
+    /// uploaded yet.
+    pub fn acquire(&mut self, hash: ResourceHash) {
+        *self.refs.entry(hash).or_default() += 1;
+    }
+
+    /// Removes a reference to a resource, evicting the resource if it was
+    /// the last one.
+    ///
+    /// The faces of evicted font files are removed from `fonts`.
+    pub fn release(&mut self, hash: ResourceHash, fonts: &mut FontRegistry) {
+        let Some(refs) = self.refs.get_mut(&hash) else {
+            return;
+        };
+
+        *refs -= 1;
+        if *refs > 0 {
+            return;
+        }
+
+        self.refs.remove(&hash);
+        self.evict(hash, fonts);
+    }
+
+    /// Evicts the resources uploaded since the last call that no node has
+    /// referred to since.
+    ///
+    /// Trees don't call this themselves, because several trees can share
+    /// resources, and a resource uploaded for one of them shouldn't be
+    /// evicted by an update to another. Instead, whoever owns the resources
+    /// should call this at the end of each frame, once every tree using
+    /// them has been updated.
+    ///
+    /// The faces of evicted font files are removed from `fonts`.
+    pub fn evict_unclaimed(&mut self, fonts: &mut FontRegistry) {
+        for hash in std::mem::take(&mut self.unclaimed) {
+            if !self.refs.contains_key(&hash) {
+                self.evict(hash, fonts);
+            }
+        }
+    }
+
+    /// Removes a stored resource, and its faces from `fonts` if it's a font.
+    fn evict(&mut self, hash: ResourceHash, fonts: &mut FontRegistry) {
+        if let Some(Resource::Font(_)) = self.resources.remove(&hash) {
+            fonts.remove_resource(hash);
+        }
+    }
+}
+
+/// [Resources] shared between every shape tree, which are rebuilt often, and
+/// the renderer, which draws them.
+pub type SharedResources = Rc<RefCell<Resources>>;
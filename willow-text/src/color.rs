@@ -0,0 +1,308 @@
+// Copyright (C) 2023 Marceline Cramer
+//
+// Willow is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// Willow is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with Willow.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::rc::Rc;
+
+use allsorts::binary::read::ReadScope;
+use allsorts::bitmap::{BitDepth, Bitmap, BitmapGlyph, EncapsulatedFormat, Metrics};
+use allsorts::error::ParseError;
+use willow_protocol::glam::Vec2;
+
+/// The palette index of `COLR` layers that are filled with the text's color.
+const FOREGROUND: u16 = 0xffff;
+
+/// A glyph that is drawn in its own colors, like an emoji.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ColorGlyph {
+    /// The outlines of other glyphs, filled with solid colors and stacked
+    /// from bottom to top, from the font's `COLR` and `CPAL` tables.
+    Layers(Vec<ColorLayer>),
+
+    /// An image from the font's `CBDT` or `sbix` table.
+    Bitmap(Rc<GlyphBitmap>),
+}
+
+/// A layer of a [ColorGlyph].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColorLayer {
+    /// The glyph whose outline is filled.
+    pub index: u16,
+
+    /// The color to fill the outline with as RGBA without premultiplied
+    /// alpha, or `None` to fill it with the color of the text.
+    pub color: Option<[u8; 4]>,
+}
+
+/// An image that is drawn in place of a glyph's outline.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GlyphBitmap {
+    pub width: u32,
+    pub height: u32,
+
+    /// The pixels in rows from top to bottom, packed into words as
+    /// `0xAARRGGBB` with the color components premultiplied by alpha.
+    ///
+    /// This is the format that images are drawn from, so glyphs are
+    /// converted once when they're decoded instead of every time they're
+    /// drawn.
+    pub pixels: Vec<u32>,
+
+    /// The bottom-left corner of the image in font units, with the Y axis
+    /// pointing up.
+    pub min: Vec2,
+
+    /// The top-right corner of the image in font units.
+    pub max: Vec2,
+}
+
+impl GlyphBitmap {
+    /// Decodes a glyph image found by allsorts, returning `None` for images
+    /// that aren't in color or aren't in a supported format.
+    pub fn decode(glyph: BitmapGlyph, units_per_em: f32) -> Option<Self> {
+        let ppem = glyph.ppem_x? as f32;
+        let (width, height, pixels) = match glyph.bitmap {
+            Bitmap::Encapsulated(image) => match image.format {
+                EncapsulatedFormat::Png => {
+                    let (width, height, pixels) = decode_png(&image.data)?;
+                    (width, height, pack_argb(&pixels))
+                }
+                _ => return None,
+            },
+            Bitmap::Embedded(image) => match image.format {
+                BitDepth::ThirtyTwo => {
+                    // embedded color bitmaps are premultiplied BGRA
+                    let pixels = image
+                        .data
+                        .chunks_exact(4)
+                        .map(|bgra| u32::from_be_bytes([bgra[3], bgra[2], bgra[1], bgra[0]]))
+                        .collect();
+
+                    (image.width as u32, image.height as u32, pixels)
+                }
+                _ => return None,
+            },
+        };
+
+        let offset = match glyph.metrics {
+            Metrics::Embedded(metrics) => {
+                let metrics = metrics.hori().or(metrics.vert())?;
+                Vec2::new(
+                    metrics.origin_offset_x as f32,
+                    metrics.origin_offset_y as f32,
+                )
+            }
+            Metrics::HmtxVmtx(offset) => Vec2::new(offset.x as f32, offset.y as f32),
+        };
+
+        // the image's metrics are in pixels at the size it was drawn for
+        let scale = units_per_em / ppem;
+        let min = offset * scale;
+        let max = min + Vec2::new(width as f32, height as f32) * scale;
+
+        Some(Self {
+            width,
+            height,
+            pixels,
+            min,
+            max,
+        })
+    }
+}
+
+/// Decodes a PNG image into its size and premultiplied RGBA pixels.
+pub fn decode_png(data: &[u8]) -> Option<(u32, u32, Vec<u8>)> {
+    use png::{ColorType, Decoder, Transformations};
+
+    let mut decoder = Decoder::new(data);
+    decoder.set_transformations(Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().ok()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).ok()?;
+    let buffer = &buffer[..info.buffer_size()];
+
+    let mut pixels: Vec<u8> = match info.color_type {
+        ColorType::Rgba => buffer.to_vec(),
+        ColorType::Rgb => buffer
+            .chunks_exact(3)
+            .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+            .collect(),
+        ColorType::GrayscaleAlpha => buffer
+            .chunks_exact(2)
+            .flat_map(|ga| [ga[0], ga[0], ga[0], ga[1]])
+            .collect(),
+        ColorType::Grayscale => buffer.iter().flat_map(|g| [*g, *g, *g, 255]).collect(),
+        ColorType::Indexed => return None,
+    };
+
+    for pixel in pixels.chunks_exact_mut(4) {
+        let alpha = pixel[3] as u32;
+        for channel in pixel[..3].iter_mut() {
+            *channel = ((*channel as u32 * alpha + 127) / 255) as u8;
+        }
+    }
+
+    Some((info.width, info.height, pixels))
+}
+
+/// Packs premultiplied red, green, blue, and alpha bytes into words as
+/// `0xAARRGGBB`, the format that images are drawn from.
+pub fn pack_argb(rgba: &[u8]) -> Vec<u32> {
+    rgba.chunks_exact(4)
+        .map(|rgba| u32::from_be_bytes([rgba[3], rgba[0], rgba[1], rgba[2]]))
+        .collect()
+}
+
+/// The layers of every color glyph in a font, read from version 0 of its
+/// `COLR` table and the first palette of its `CPAL` table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ColorLayers {
+    /// Each color glyph's index, first layer, and number of layers, sorted
+    /// by glyph index.
+    base_glyphs: Vec<(u16, u16, u16)>,
+
+    /// Each layer's glyph index and palette index.
+    layers: Vec<(u16, u16)>,
+
+    /// The colors of the first palette as RGBA.
+    palette: Vec<[u8; 4]>,
+}
+
+impl ColorLayers {
+    /// Reads the contents of a font's `COLR` and `CPAL` tables.
+    ///
+    /// Fonts with later versions of `COLR` keep their version 0 glyphs for
+    /// compatibility, so those are read, and the rest are ignored.
+    pub fn read(colr: &[u8], cpal: &[u8]) -> Result<Self, ParseError> {
+        let colr = ReadScope::new(colr);
+        let mut header = colr.ctxt();
+        let _version = header.read_u16be()?;
+        let num_base_glyphs = header.read_u16be()?;
+        let base_glyphs_offset = header.read_u32be()? as usize;
+        let layers_offset = header.read_u32be()? as usize;
+        let num_layers = header.read_u16be()?;
+
+        let mut records = colr.offset(base_glyphs_offset).ctxt();
+        let mut base_glyphs = Vec::with_capacity(num_base_glyphs as usize);
+        for _ in 0..num_base_glyphs {
+            let glyph = records.read_u16be()?;
+            let first_layer = records.read_u16be()?;
+            let num_layers = records.read_u16be()?;
+            base_glyphs.push((glyph, first_layer, num_layers));
+        }
+
+        let mut records = colr.offset(layers_offset).ctxt();
+        let mut layers = Vec::with_capacity(num_layers as usize);
+        for _ in 0..num_layers {
+            let glyph = records.read_u16be()?;
+            let palette_index = records.read_u16be()?;
+            layers.push((glyph, palette_index));
+        }
+
+        let cpal = ReadScope::new(cpal);
+        let mut header = cpal.ctxt();
+        let _version = header.read_u16be()?;
+        let num_entries = header.read_u16be()?;
+        let _num_palettes = header.read_u16be()?;
+        let _num_colors = header.read_u16be()?;
+        let colors_offset = header.read_u32be()? as usize;
+        let first_color = header.read_u16be()? as usize;
+
+        let mut records = cpal.offset(colors_offset + first_color * 4).ctxt();
+        let mut palette = Vec::with_capacity(num_entries as usize);
+        for _ in 0..num_entries {
+            let bgra = records.read_slice(4)?;
+            palette.push([bgra[2], bgra[1], bgra[0], bgra[3]]);
+        }
+
+        base_glyphs.sort_unstable_by_key(|(glyph, _, _)| *glyph);
+
+        Ok(Self {
+            base_glyphs,
+            layers,
+            palette,
+        })
+    }
+
+    /// Looks up the layers of a color glyph.
+    pub fn get(&self, index: u16) -> Option<Vec<ColorLayer>> {
+        let base = self
+            .base_glyphs
+            .binary_search_by_key(&index, |(glyph, _, _)| *glyph)
+            .ok()?;
+
+        let (_, first, count) = self.base_glyphs[base];
+        let first = first as usize;
+        let layers = self.layers.get(first..first + count as usize)?;
+
+        let layers = layers
+            .iter()
+            .map(|(index, palette_index)| ColorLayer {
+                index: *index,
+                color: match *palette_index {
+                    FOREGROUND => None,
+                    // out-of-range colors fall back to the text's color
+                    index => self.palette.get(index as usize).copied(),
+                },
+            })
+            .collect();
+
+        Some(layers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16s(values: &[u16]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn reads_layers_from_colr_and_cpal() {
+        // one base glyph with two layers, the second in the text's color
+        let mut colr = u16s(&[0, 1, 0, 14, 0, 20, 2]);
+        colr.extend(u16s(&[7, 0, 2]));
+        colr.extend(u16s(&[3, 1, 4, FOREGROUND]));
+
+        let mut cpal = u16s(&[0, 2, 1, 2, 0, 14, 0]);
+        cpal.extend([0, 0, 255, 255, 255, 0, 0, 128]);
+
+        let layers = ColorLayers::read(&colr, &cpal).unwrap();
+        assert_eq!(layers.get(6), None);
+        assert_eq!(
+            layers.get(7).unwrap(),
+            [
+                ColorLayer {
+                    index: 3,
+                    color: Some([0, 0, 255, 128]),
+                },
+                ColorLayer {
+                    index: 4,
+                    color: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pixels_are_packed_as_argb() {
+        let rgba = [0x11, 0x22, 0x33, 0x44, 0xff, 0x00, 0x80, 0xff];
+        assert_eq!(pack_argb(&rgba), vec![0x44112233, 0xffff0080]);
+    }
+}
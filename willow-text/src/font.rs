@@ -0,0 +1,972 @@
+// Copyright (C) 2023 Marceline Cramer
+//
+// Willow is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// Willow is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with Willow.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt::Formatter;
+use std::num::NonZeroUsize;
+use std::rc::Rc;
+
+use allsorts::binary::read::ReadScope;
+use allsorts::bitmap::BitDepth;
+use allsorts::error::{ParseError, ReadWriteError, ShapingError};
+use allsorts::font::{GlyphTableFlags, MatchingPresentation};
+use allsorts::font_data::{DynamicFontTableProvider, FontData as AllsortsFontData};
+use allsorts::glyph_position::{GlyphLayout, TextDirection};
+use allsorts::gpos::Info;
+use allsorts::gsub::{FeatureInfo, FeatureMask, Features};
+use allsorts::outline::OutlineBuilder;
+use allsorts::pathfinder_geometry::{line_segment::LineSegment2F, vector::Vector2F};
+use allsorts::scripts::ScriptType;
+use allsorts::tables::{FontTableProvider, OpenTypeData};
+use allsorts::unicode::codepoint::is_upright_char;
+use allsorts::Font as AllsortsFont;
+use lru::LruCache;
+use skrifa::outline::{DrawSettings, Engine, HintingInstance, HintingOptions};
+use skrifa::prelude::{LocationRef, Size};
+use skrifa::{FontRef, GlyphId, MetadataProvider};
+use willow_protocol::{glam::Vec2, FontFeature, FontStyle, FontWeight};
+
+use crate::color::{ColorGlyph, ColorLayers, GlyphBitmap};
+use crate::hinting::HintedPen;
+
+/// The maximum number of glyph outlines cached by each [FontData].
+pub const GLYPH_CACHE_CAPACITY: usize = 2048;
+
+/// The maximum number of shaped strings cached by each [FontData].
+pub const SHAPE_CACHE_CAPACITY: usize = 1024;
+
+/// The maximum number of sizes that each [FontData] keeps the state of its
+/// hinting instructions for.
+pub const HINTING_CACHE_CAPACITY: usize = 8;
+
+/// An error encountered while loading a font.
+#[derive(Debug)]
+pub enum FontError {
+    /// The font file could not be read.
+    Io(std::io::Error),
+
+    /// The font data is malformed or in an unsupported format.
+    Parse(ParseError),
+
+    /// The font has no character map that text can be mapped with.
+    NoCharacterMap,
+
+    /// Text could not be shaped with the font's shaping tables.
+    Shaping(ShapingError),
+
+    /// The font has no `glyf` or `CFF` table to read glyph outlines from.
+    NoOutlines,
+
+    /// A glyph's outline could not be read.
+    BadOutline {
+        /// The index of the glyph.
+        index: u16,
+
+        /// Why the outline could not be read.
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for FontError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> std::fmt::Result {
+        use FontError::*;
+        match self {
+            Io(err) => write!(fmt, "failed to read font: {}", err),
+            Parse(err) => write!(fmt, "failed to parse font: {}", err),
+            NoCharacterMap => write!(fmt, "font has no supported character map"),
+            Shaping(err) => write!(fmt, "failed to shape text: {}", err),
+            NoOutlines => write!(fmt, "font has no glyf or CFF table"),
+            BadOutline { index, reason } => {
+                write!(fmt, "failed to read outline of glyph {}: {}", index, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FontError {}
+
+impl From<std::io::Error> for FontError {
+    fn from(err: std::io::Error) -> Self {
+        FontError::Io(err)
+    }
+}
+
+impl From<ParseError> for FontError {
+    fn from(err: ParseError) -> Self {
+        FontError::Parse(err)
+    }
+}
+
+impl From<ShapingError> for FontError {
+    fn from(err: ShapingError) -> Self {
+        FontError::Shaping(err)
+    }
+}
+
+impl From<ReadWriteError> for FontError {
+    fn from(err: ReadWriteError) -> Self {
+        match err {
+            ReadWriteError::Read(err) => FontError::Parse(err),
+            // font table providers only read, so this shouldn't happen
+            ReadWriteError::Write(_) => FontError::Parse(ParseError::BadValue),
+        }
+    }
+}
+
+pub type FontResult<T> = Result<T, FontError>;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct GlyphPosition {
+    pub index: u16,
+    pub hori_advance: i32,
+    pub vert_advance: i32,
+    pub xoff: i32,
+    pub yoff: i32,
+
+    /// The byte offset in the shaped string of the first character that this
+    /// glyph was shaped from.
+    pub cluster: usize,
+
+    /// Whether this glyph stands upright in vertical text, like CJK
+    /// characters do, instead of lying sideways along the line. Always false
+    /// in horizontal text.
+    pub upright: bool,
+}
+
+/// A single drawing command in a glyph [Outline].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutlineCommand {
+    MoveTo(Vec2),
+    LineTo(Vec2),
+    QuadTo(Vec2, Vec2),
+    CubicTo(Vec2, Vec2, Vec2),
+    Close,
+}
+
+/// The outline of a glyph in font units, with the Y axis pointing up.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outline {
+    pub commands: Vec<OutlineCommand>,
+
+    /// The minimum corner of the bounds of every point in the outline.
+    pub min: Vec2,
+
+    /// The maximum corner of the bounds of every point in the outline.
+    pub max: Vec2,
+}
+
+impl Default for Outline {
+    fn default() -> Self {
+        Self {
+            commands: Vec::new(),
+            min: Vec2::INFINITY,
+            max: Vec2::NEG_INFINITY,
+        }
+    }
+}
+
+impl Outline {
+    /// Tests whether this outline draws nothing, like a space's.
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, command: OutlineCommand, points: &[Vec2]) {
+        for point in points {
+            self.min = self.min.min(*point);
+            self.max = self.max.max(*point);
+        }
+
+        self.commands.push(command);
+    }
+}
+
+/// The vertical metrics of a font in font units.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FontMetrics {
+    /// The size of the em square.
+    pub units_per_em: f32,
+
+    /// The distance from the baseline to the top of the tallest glyphs.
+    pub ascent: f32,
+
+    /// The distance from the baseline to the bottom of the lowest glyphs.
+    pub descent: f32,
+
+    /// The extra space to put between lines.
+    pub line_gap: f32,
+}
+
+/// The metrics of the lines a font draws under and through text, in font
+/// units with the Y axis pointing up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DecorationMetrics {
+    /// The height of the top of the underline above the baseline.
+    pub underline_position: f32,
+    pub underline_thickness: f32,
+
+    /// The height of the top of the strikethrough line above the baseline.
+    pub strikeout_position: f32,
+    pub strikeout_thickness: f32,
+}
+
+/// The settings a string is shaped with, used to look up shaped runs in
+/// [FontData]'s shaping cache.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ShapeKey {
+    pub text: String,
+    pub script: u32,
+    pub right_to_left: bool,
+    pub vertical: bool,
+    pub features: Vec<FontFeature>,
+}
+
+/// What is known about a face from when it is loaded.
+struct FaceInfo {
+    /// The index of this face in its font file.
+    index: usize,
+
+    /// The font's vertical metrics.
+    metrics: FontMetrics,
+
+    /// The layers of the font's color glyphs, if it has any.
+    color_layers: Option<ColorLayers>,
+}
+
+/// The data that each [FontData] caches after reading it from the font.
+struct FontCaches {
+    /// Glyph outlines, keyed by glyph index.
+    glyphs: LruCache<u16, Rc<Outline>>,
+
+    /// Glyph runs that have already been shaped.
+    shapes: LruCache<ShapeKey, Rc<[GlyphPosition]>>,
+
+    /// Color glyph images, keyed by glyph index and pixels per em.
+    bitmaps: LruCache<(u16, u16), Option<Rc<GlyphBitmap>>>,
+
+    /// The state of the font's hinting instructions after running its setup
+    /// programs, keyed by the bits of the size in pixels per em, or `None` if
+    /// the font couldn't be hinted at that size.
+    hinting: LruCache<u32, Option<HintingInstance>>,
+}
+
+impl FontCaches {
+    fn new() -> Self {
+        let capacity = |capacity| NonZeroUsize::new(capacity).unwrap();
+        Self {
+            glyphs: LruCache::new(capacity(GLYPH_CACHE_CAPACITY)),
+            shapes: LruCache::new(capacity(SHAPE_CACHE_CAPACITY)),
+            bitmaps: LruCache::new(capacity(GLYPH_CACHE_CAPACITY)),
+            hinting: LruCache::new(capacity(HINTING_CACHE_CAPACITY)),
+        }
+    }
+}
+
+#[ouroboros::self_referencing]
+pub struct FontData {
+    file_buffer: Vec<u8>,
+    face: FaceInfo,
+    caches: FontCaches,
+
+    #[borrows(file_buffer)]
+    #[covariant]
+    read_scope: ReadScope<'this>,
+
+    #[borrows(read_scope)]
+    #[not_covariant]
+    font_data: AllsortsFontData<'this>,
+
+    #[borrows(font_data)]
+    #[covariant]
+    inner: AllsortsFont<DynamicFontTableProvider<'this>>,
+}
+
+impl FontData {
+    /// Loads the face at `index` in a font file. Single-face font files only
+    /// contain the face at index 0.
+    pub fn load(file_buffer: Vec<u8>, index: usize) -> FontResult<Self> {
+        let mut font = FontDataTryBuilder {
+            file_buffer,
+            face: FaceInfo {
+                index,
+                // replaced with the font's metrics once it is parsed
+                metrics: FontMetrics {
+                    units_per_em: 0.0,
+                    ascent: 0.0,
+                    descent: 0.0,
+                    line_gap: 0.0,
+                },
+                color_layers: None,
+            },
+            caches: FontCaches::new(),
+            read_scope_builder: |buffer| Ok(ReadScope::new(buffer)),
+            font_data_builder: |scope| Ok(scope.read::<AllsortsFontData<'_>>()?),
+            inner_builder: |font_data| {
+                AllsortsFont::new(font_data.table_provider(index)?)?
+                    .ok_or(FontError::NoCharacterMap)
+            },
+        }
+        .try_build()?;
+
+        let metrics = font.with_inner(|font| {
+            let head = font.head_table()?.ok_or(ParseError::MissingValue)?;
+            Ok::<_, FontError>(FontMetrics {
+                units_per_em: head.units_per_em as f32,
+                ascent: font.hhea_table.ascender as f32,
+                descent: -font.hhea_table.descender as f32,
+                line_gap: font.hhea_table.line_gap as f32,
+            })
+        })?;
+
+        font.with_face_mut(|face| face.metrics = metrics);
+
+        // fonts with broken color tables still have their outlines
+        let color_layers = font.with_inner(|font| {
+            let provider = &font.font_table_provider;
+            let colr = provider.table_data(allsorts::tag::COLR).ok()??;
+            let cpal = provider.table_data(allsorts::tag::CPAL).ok()??;
+            ColorLayers::read(&colr, &cpal).ok()
+        });
+
+        font.with_face_mut(|face| face.color_layers = color_layers);
+        Ok(font)
+    }
+
+    /// Counts the faces in a font file, which is more than one for font
+    /// collections such as TTC files.
+    pub fn count_faces(file_buffer: &[u8]) -> FontResult<usize> {
+        let font_data = ReadScope::new(file_buffer).read::<AllsortsFontData<'_>>()?;
+        match font_data {
+            AllsortsFontData::OpenType(font) => match font.data {
+                OpenTypeData::Single(_) => Ok(1),
+                OpenTypeData::Collection(ttc) => Ok(ttc.offset_tables.len()),
+            },
+            _ => Ok(1),
+        }
+    }
+
+    /// Reads a string from the font's `name` table.
+    fn read_name(&self, name_id: u16) -> Option<String> {
+        self.with_inner(|font| {
+            let data = font
+                .font_table_provider
+                .read_table_data(allsorts::tag::NAME)
+                .ok()?;
+            let name = allsorts::get_name::fontcode_get_name(&data, name_id).ok()??;
+            name.into_string().ok()
+        })
+    }
+
+    /// Reads the name of the font's family.
+    pub fn family(&self) -> Option<String> {
+        const FONT_FAMILY: u16 = 1;
+        const TYPOGRAPHIC_FAMILY: u16 = 16;
+
+        self.read_name(TYPOGRAPHIC_FAMILY)
+            .or_else(|| self.read_name(FONT_FAMILY))
+    }
+
+    /// Reads the font's weight from its `OS/2` table.
+    pub fn weight(&self) -> FontWeight {
+        self.with_inner(|font| match font.os2_table() {
+            Ok(Some(os2)) if os2.us_weight_class > 0 => FontWeight(os2.us_weight_class),
+            _ => FontWeight::NORMAL,
+        })
+    }
+
+    /// Reads the font's style from its `OS/2` or `head` table.
+    pub fn style(&self) -> FontStyle {
+        const ITALIC: u16 = 1 << 0;
+        const OBLIQUE: u16 = 1 << 9;
+        const MAC_ITALIC: u16 = 1 << 1;
+
+        self.with_inner(|font| {
+            if let Ok(Some(os2)) = font.os2_table() {
+                if os2.fs_selection & OBLIQUE != 0 {
+                    return FontStyle::Oblique;
+                } else if os2.fs_selection & ITALIC != 0 {
+                    return FontStyle::Italic;
+                }
+            } else if let Ok(Some(head)) = font.head_table() {
+                if head.mac_style & MAC_ITALIC != 0 {
+                    return FontStyle::Italic;
+                }
+            }
+
+            FontStyle::Normal
+        })
+    }
+
+    /// Shapes a string of a single script and direction, reusing the glyph
+    /// run from a previous call if the same string has already been shaped
+    /// with the same settings.
+    ///
+    /// The returned glyphs are in visual order, so right-to-left runs are
+    /// reversed from the order of their characters.
+    ///
+    /// Vertical text is shaped with the font's vertical alternates, and its
+    /// upright glyphs advance by their vertical advance.
+    ///
+    /// If the font's shaping tables can't be read, the error is logged, and
+    /// each character is drawn with the glyph that it maps to instead.
+    pub fn shape(
+        &mut self,
+        text: &str,
+        script: u32,
+        direction: TextDirection,
+        vertical: bool,
+        features: &[FontFeature],
+    ) -> Rc<[GlyphPosition]> {
+        let key = ShapeKey {
+            text: text.to_string(),
+            script,
+            right_to_left: direction == TextDirection::RightToLeft,
+            vertical,
+            features: features.to_vec(),
+        };
+
+        if let Some(glyphs) = self.with_caches_mut(|caches| caches.shapes.get(&key).cloned()) {
+            return glyphs;
+        }
+
+        let glyphs: Rc<[GlyphPosition]> =
+            match self.try_shape(text, script, direction, vertical, features) {
+                Ok(glyphs) => glyphs.into(),
+                Err(err) => {
+                    let family = self.family().unwrap_or_default();
+                    log::warn!("failed to shape {:?} with {}: {}", text, family, err);
+                    self.shape_unmapped(text, direction, vertical).into()
+                }
+            };
+
+        self.with_caches_mut(|caches| caches.shapes.put(key, glyphs.clone()));
+        glyphs
+    }
+
+    /// Shapes a string like [Self::shape], without the cache, returning any
+    /// error from reading the font's shaping tables.
+    pub fn try_shape(
+        &mut self,
+        text: &str,
+        script: u32,
+        direction: TextDirection,
+        vertical: bool,
+        features: &[FontFeature],
+    ) -> FontResult<Vec<GlyphPosition>> {
+        let presentation = MatchingPresentation::Required;
+        let lang_tag = None;
+        let (features, kerning) = shaping_features(features, script, vertical);
+
+        self.with_inner_mut(|font| {
+            let mapped = font.map_glyphs(text, script, presentation);
+            let infos = font
+                .shape(mapped, script, lang_tag, &features, kerning)
+                .map_err(|(err, _)| err)?;
+            let mut layout = GlyphLayout::new(font, &infos, direction, vertical);
+            let positions = layout.glyph_positions()?;
+            let mut glyphs = Vec::with_capacity(positions.len());
+            let mut cluster = 0;
+            for (glyph, position) in infos.iter().zip(&positions) {
+                glyphs.push(GlyphPosition {
+                    index: glyph.glyph.glyph_index,
+                    hori_advance: position.hori_advance,
+                    vert_advance: position.vert_advance,
+                    xoff: position.x_offset,
+                    yoff: position.y_offset,
+                    cluster: cluster.min(text.len()),
+                    upright: vertical && is_upright(glyph),
+                });
+
+                // glyphs duplicated by multiple substitution share a cluster
+                if !glyph.glyph.multi_subst_dup {
+                    for _ in glyph.glyph.unicodes.iter() {
+                        cluster = next_cluster(text, cluster);
+                    }
+                }
+            }
+
+            if direction == TextDirection::RightToLeft {
+                glyphs.reverse();
+            }
+
+            Ok(glyphs)
+        })
+    }
+
+    /// Places the glyph that each character maps to one after another,
+    /// without any shaping.
+    fn shape_unmapped(
+        &mut self,
+        text: &str,
+        direction: TextDirection,
+        vertical: bool,
+    ) -> Vec<GlyphPosition> {
+        let units_per_em = self.metrics().units_per_em as i32;
+        let mut glyphs = Vec::with_capacity(text.len());
+
+        for (cluster, ch) in text.char_indices() {
+            let index = self.glyph_index(ch);
+            let advance = self.horizontal_advance(index) as i32;
+            let upright = vertical && is_upright_char(ch);
+            let (hori_advance, vert_advance) = match (vertical, upright) {
+                (false, _) => (advance, 0),
+                (true, false) => (0, advance),
+                (true, true) => (0, units_per_em),
+            };
+
+            glyphs.push(GlyphPosition {
+                index,
+                hori_advance,
+                vert_advance,
+                xoff: 0,
+                yoff: 0,
+                cluster,
+                upright,
+            });
+        }
+
+        if direction == TextDirection::RightToLeft {
+            glyphs.reverse();
+        }
+
+        glyphs
+    }
+
+    /// Tests whether the font maps a character to a glyph.
+    pub fn has_glyph(&mut self, ch: char) -> bool {
+        self.glyph_index(ch) != 0
+    }
+
+    /// Looks up the glyph that the font maps a character to, or the missing
+    /// glyph at index 0 if there isn't one.
+    pub fn glyph_index(&mut self, ch: char) -> u16 {
+        self.with_inner_mut(|font| {
+            let presentation = MatchingPresentation::Required;
+            font.lookup_glyph_index(ch, presentation, None).0
+        })
+    }
+
+    /// Looks up the colored version of a glyph, if the font has one.
+    ///
+    /// Glyphs made of colored layers are preferred over images, which are
+    /// taken from the font's set of images closest to `ppem` pixels per em.
+    pub fn color_glyph(&mut self, index: u16, ppem: f32) -> Option<ColorGlyph> {
+        if let Some(layers) = self.borrow_face().color_layers.as_ref() {
+            if let Some(layers) = layers.get(index) {
+                return Some(ColorGlyph::Layers(layers));
+            }
+        }
+
+        let ppem = ppem.round().clamp(1.0, u16::MAX as f32) as u16;
+        let key = (index, ppem);
+        if let Some(bitmap) = self.with_caches_mut(|caches| caches.bitmaps.get(&key).cloned()) {
+            return bitmap.map(ColorGlyph::Bitmap);
+        }
+
+        let units_per_em = self.metrics().units_per_em;
+        let bitmap = self.with_inner_mut(|font| {
+            let image = font.lookup_glyph_image(index, ppem, BitDepth::ThirtyTwo);
+            let image = image.ok()??;
+            GlyphBitmap::decode(image, units_per_em).map(Rc::new)
+        });
+
+        self.with_caches_mut(|caches| caches.bitmaps.put(key, bitmap.clone()));
+        bitmap.map(ColorGlyph::Bitmap)
+    }
+
+    /// Looks up how far a glyph advances in horizontal text, in font units.
+    pub fn horizontal_advance(&mut self, index: u16) -> f32 {
+        self.with_inner_mut(|font| font.horizontal_advance(index).unwrap_or(0) as f32)
+    }
+
+    /// The font's vertical metrics, from its `hhea` table.
+    pub fn metrics(&self) -> FontMetrics {
+        self.borrow_face().metrics
+    }
+
+    /// Reads the positions of the font's underline from its `post` table and
+    /// of its strikethrough line from its `OS/2` table, guessing them from
+    /// the em size if those tables are missing.
+    pub fn decoration_metrics(&self) -> DecorationMetrics {
+        use allsorts::post::PostTable;
+
+        let units_per_em = self.metrics().units_per_em;
+        let mut metrics = DecorationMetrics {
+            underline_position: units_per_em * -0.1,
+            underline_thickness: units_per_em * 0.05,
+            strikeout_position: units_per_em * 0.3,
+            strikeout_thickness: units_per_em * 0.05,
+        };
+
+        self.with_inner(|font| {
+            let post = font
+                .font_table_provider
+                .read_table_data(allsorts::tag::POST);
+            if let Ok(data) = post {
+                if let Ok(post) = ReadScope::new(&data).read::<PostTable<'_>>() {
+                    metrics.underline_position = post.header.underline_position as f32;
+                    metrics.underline_thickness = post.header.underline_thickness as f32;
+                }
+            }
+
+            if let Ok(Some(os2)) = font.os2_table() {
+                metrics.strikeout_position = os2.y_strikeout_position as f32;
+                metrics.strikeout_thickness = os2.y_strikeout_size as f32;
+            }
+        });
+
+        metrics
+    }
+
+    /// Retrieves the outline of a glyph, loading it from the font's `glyf` or
+    /// `CFF` table if it isn't already cached.
+    ///
+    /// If the outline can't be read, the error is logged, and a replacement
+    /// box is returned in its place.
+    pub fn glyph_outline(&mut self, index: u16) -> Rc<Outline> {
+        if let Some(outline) = self.with_caches_mut(|caches| caches.glyphs.get(&index).cloned()) {
+            return outline;
+        }
+
+        let outline = match self.read_glyph_outline(index) {
+            Ok(outline) => outline,
+            Err(err) => {
+                let family = self.family().unwrap_or_default();
+                log::warn!("failed to load glyph from {}: {}", family, err);
+                self.replacement_outline(index)
+            }
+        };
+
+        let outline = Rc::new(outline);
+        self.with_caches_mut(|caches| caches.glyphs.put(index, outline.clone()));
+        outline
+    }
+
+    /// Reads the outline of a glyph from the font's `glyf` or `CFF` table,
+    /// without the cache.
+    ///
+    /// Fonts made of color images, like some emoji fonts, have no outlines,
+    /// so every glyph in them has an empty outline.
+    pub fn read_glyph_outline(&mut self, index: u16) -> FontResult<Outline> {
+        use allsorts::cff::CFF;
+        use allsorts::tables::{glyf::GlyfTable, loca::LocaTable, FontTableProvider, SfntVersion};
+        use allsorts::tag;
+
+        self.with_inner_mut(|font| {
+            let provider = &font.font_table_provider;
+            if font.glyph_table_flags.contains(GlyphTableFlags::CFF)
+                && provider.sfnt_version() == tag::OTTO
+            {
+                let cff_data = provider.read_table_data(tag::CFF)?;
+                let mut cff = ReadScope::new(&cff_data).read::<CFF<'_>>()?;
+                OutlineRecorder::build(&mut cff, index)
+            } else if font.glyph_table_flags.contains(GlyphTableFlags::GLYF) {
+                let head = font.head_table()?.ok_or(ParseError::MissingValue)?;
+                let loca_data = provider.read_table_data(tag::LOCA)?;
+                let loca = ReadScope::new(&loca_data).read_dep::<LocaTable<'_>>((
+                    usize::from(font.maxp_table.num_glyphs),
+                    head.index_to_loc_format,
+                ))?;
+                let glyf_data = provider.read_table_data(tag::GLYF)?;
+                let mut glyf = ReadScope::new(&glyf_data).read_dep::<GlyfTable<'_>>(&loca)?;
+                OutlineRecorder::build(&mut glyf, index)
+            } else if font.glyph_table_flags.contains(GlyphTableFlags::CBDT)
+                || font.glyph_table_flags.contains(GlyphTableFlags::SBIX)
+            {
+                Ok(Outline::default())
+            } else {
+                Err(FontError::NoOutlines)
+            }
+        })
+    }
+
+    /// Hints a glyph's outline for a size in pixels per em by running the
+    /// font's TrueType hinting instructions, or its CFF hints, and returns it
+    /// in font units.
+    ///
+    /// Fonts without either are autohinted by [skrifa] instead, like FreeType
+    /// does. Returns `None` if the glyph can't be hinted, like glyphs in color
+    /// image fonts or in font files that aren't plain OpenType, so that the
+    /// unhinted outline can be drawn instead.
+    pub fn hinted_outline(&mut self, index: u16, ppem: f32) -> Option<Outline> {
+        let scale = ppem / self.metrics().units_per_em;
+        self.with_mut(|fields| {
+            let font = FontRef::from_index(fields.file_buffer, fields.face.index as u32).ok()?;
+            let outlines = font.outline_glyphs();
+            let glyph = outlines.get(GlyphId::new(index as u32))?;
+
+            let instance = fields.caches.hinting.get_or_insert(ppem.to_bits(), || {
+                let options = HintingOptions::from(Engine::AutoFallback);
+                HintingInstance::new(&outlines, Size::new(ppem), LocationRef::default(), options)
+                    .ok()
+            });
+
+            let mut pen = HintedPen::new(scale);
+            glyph
+                .draw(DrawSettings::hinted(instance.as_ref()?, false), &mut pen)
+                .ok()?;
+            Some(pen.finish())
+        })
+    }
+
+    /// Makes a hollow box as wide as a glyph, drawn in place of glyphs whose
+    /// outlines can't be read.
+    pub fn replacement_outline(&mut self, index: u16) -> Outline {
+        let metrics = self.metrics();
+        let advance = self.horizontal_advance(index);
+        let width = if advance > 0.0 {
+            advance
+        } else {
+            metrics.units_per_em / 2.0
+        };
+
+        let margin = width * 0.1;
+        let stroke = metrics.units_per_em * 0.05;
+        let min = Vec2::new(margin, 0.0);
+        let max = Vec2::new(width - margin, metrics.ascent * 0.7);
+
+        let mut outline = Outline::default();
+        for (min, max, clockwise) in [(min, max, true), (min + stroke, max - stroke, false)] {
+            let mut corners = [min, Vec2::new(min.x, max.y), max, Vec2::new(max.x, min.y)];
+            if !clockwise {
+                corners.reverse();
+            }
+
+            outline.push(OutlineCommand::MoveTo(corners[0]), &corners[..1]);
+            for corner in corners[1..].iter() {
+                outline.push(OutlineCommand::LineTo(*corner), &[*corner]);
+            }
+
+            outline.push(OutlineCommand::Close, &[]);
+        }
+
+        outline
+    }
+}
+
+/// Records an [Outline] from an allsorts glyph.
+pub struct OutlineRecorder {
+    outline: Outline,
+}
+
+impl allsorts::outline::OutlineSink for OutlineRecorder {
+    fn move_to(&mut self, to: Vector2F) {
+        let to = vec2f(to);
+        self.outline.push(OutlineCommand::MoveTo(to), &[to]);
+    }
+
+    fn line_to(&mut self, to: Vector2F) {
+        let to = vec2f(to);
+        self.outline.push(OutlineCommand::LineTo(to), &[to]);
+    }
+
+    fn quadratic_curve_to(&mut self, ctrl: Vector2F, to: Vector2F) {
+        let (ctrl, to) = (vec2f(ctrl), vec2f(to));
+        self.outline
+            .push(OutlineCommand::QuadTo(ctrl, to), &[ctrl, to]);
+    }
+
+    fn cubic_curve_to(&mut self, ctrl: LineSegment2F, to: Vector2F) {
+        let (c1, c2, to) = (vec2f(ctrl.from()), vec2f(ctrl.to()), vec2f(to));
+        self.outline
+            .push(OutlineCommand::CubicTo(c1, c2, to), &[c1, c2, to]);
+    }
+
+    fn close(&mut self) {
+        self.outline.push(OutlineCommand::Close, &[]);
+    }
+}
+
+impl OutlineRecorder {
+    pub fn build(builder: &mut impl OutlineBuilder, index: u16) -> FontResult<Outline> {
+        let mut sink = Self {
+            outline: Outline::default(),
+        };
+
+        match builder.visit(index, &mut sink) {
+            Ok(()) => Ok(sink.outline),
+            Err(err) => Err(FontError::BadOutline {
+                index,
+                reason: err.to_string(),
+            }),
+        }
+    }
+}
+
+/// Converts a list of features to turn on or off into the features that
+/// allsorts shapes with, and whether to apply kerning.
+///
+/// Vertical text turns on vertical alternates and turns off kerning, which
+/// only adjusts horizontal advances.
+///
+/// Most common features are toggled in allsorts' default feature mask, which
+/// keeps the script-specific shaping that the mask enables. Other features,
+/// like stylistic sets, can only be applied by replacing the mask with a
+/// custom list, which skips that shaping, so they are ignored in scripts that
+/// need it.
+fn shaping_features(features: &[FontFeature], script: u32, vertical: bool) -> (Features, bool) {
+    let mut mask = FeatureMask::default();
+    let mut custom = Vec::new();
+    let mut kerning = !vertical;
+
+    if vertical {
+        mask.insert(FeatureMask::VRT2_OR_VERT);
+    }
+
+    for feature in features.iter() {
+        let tag = u32::from_be_bytes(feature.tag);
+        let bit = FeatureMask::from_tag(tag);
+
+        if tag == allsorts::tag::KERN {
+            kerning = feature.enabled && !vertical;
+        } else if !bit.is_empty() {
+            mask.set(bit, feature.enabled);
+        } else if feature.enabled {
+            custom.push(tag);
+        }
+    }
+
+    if custom.is_empty() || ScriptType::from(script) != ScriptType::Default {
+        return (Features::Mask(mask), kerning);
+    }
+
+    let custom = custom.into_iter().map(|feature_tag| FeatureInfo {
+        feature_tag,
+        alternate: None,
+    });
+
+    (
+        Features::Custom(mask.iter().chain(custom).collect()),
+        kerning,
+    )
+}
+
+/// Tests whether a shaped glyph stands upright in vertical text, which is
+/// the case for vertical alternates and CJK characters.
+fn is_upright(info: &Info) -> bool {
+    info.glyph.is_vert_alt
+        || info
+            .glyph
+            .unicodes
+            .first()
+            .is_some_and(|ch| is_upright_char(*ch))
+}
+
+/// Finds the byte offset of the character after the one at `offset`, skipping
+/// variation selectors, which are shaped together with the character before
+/// them.
+fn next_cluster(text: &str, offset: usize) -> usize {
+    let is_variation_selector =
+        |ch: char| matches!(ch, '\u{fe00}'..='\u{fe0f}' | '\u{e0100}'..='\u{e01ef}');
+
+    let mut chars = text.get(offset..).unwrap_or_default().char_indices();
+    let mut next = match chars.next() {
+        Some((_, ch)) => offset + ch.len_utf8(),
+        None => return text.len(),
+    };
+
+    for (_, ch) in chars {
+        if !is_variation_selector(ch) {
+            break;
+        }
+
+        next += ch.len_utf8();
+    }
+
+    next
+}
+
+fn vec2f(v: Vector2F) -> Vec2 {
+    Vec2::new(v.x(), v.y())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noto_sans() -> FontData {
+        FontData::load(notosans::REGULAR_TTF.to_vec(), 0).unwrap()
+    }
+
+    fn shape(font: &mut FontData, text: &str) -> Rc<[GlyphPosition]> {
+        font.shape(
+            text,
+            allsorts::tag::LATN,
+            TextDirection::LeftToRight,
+            false,
+            &[],
+        )
+    }
+
+    #[test]
+    fn glyph_outlines_are_cached() {
+        let mut font = noto_sans();
+        let index = font.glyph_index('a');
+        let outline = font.glyph_outline(index);
+        assert!(Rc::ptr_eq(&outline, &font.glyph_outline(index)));
+
+        // filling the cache with other glyphs evicts the first
+        for other in (0..=GLYPH_CACHE_CAPACITY as u16).filter(|other| *other != index) {
+            font.glyph_outline(other);
+        }
+
+        assert!(!font.borrow_caches().glyphs.contains(&index));
+        assert!(!Rc::ptr_eq(&outline, &font.glyph_outline(index)));
+        assert_eq!(font.borrow_caches().glyphs.len(), GLYPH_CACHE_CAPACITY);
+    }
+
+    #[test]
+    fn shaped_runs_are_cached() {
+        let mut font = noto_sans();
+        let glyphs = shape(&mut font, "willow");
+        assert!(Rc::ptr_eq(&glyphs, &shape(&mut font, "willow")));
+
+        // the same text with other settings is shaped again
+        let vertical = font.shape(
+            "willow",
+            allsorts::tag::LATN,
+            TextDirection::LeftToRight,
+            true,
+            &[],
+        );
+
+        assert!(!Rc::ptr_eq(&glyphs, &vertical));
+
+        for other in 0..SHAPE_CACHE_CAPACITY {
+            shape(&mut font, &other.to_string());
+        }
+
+        assert!(!Rc::ptr_eq(&glyphs, &shape(&mut font, "willow")));
+        assert_eq!(font.borrow_caches().shapes.len(), SHAPE_CACHE_CAPACITY);
+    }
+
+    #[test]
+    fn color_glyph_lookups_are_cached() {
+        let mut font = noto_sans();
+        let index = font.glyph_index('a');
+
+        // glyphs without images are cached as missing
+        assert!(font.color_glyph(index, 16.0).is_none());
+        assert!(font.color_glyph(index, 16.2).is_none());
+        assert_eq!(font.borrow_caches().bitmaps.len(), 1);
+
+        for ppem in 17..17 + GLYPH_CACHE_CAPACITY {
+            font.color_glyph(index, ppem as f32);
+        }
+
+        assert!(!font.borrow_caches().bitmaps.contains(&(index, 16)));
+        assert_eq!(font.borrow_caches().bitmaps.len(), GLYPH_CACHE_CAPACITY);
+    }
+}
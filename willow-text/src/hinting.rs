@@ -0,0 +1,260 @@
+// Copyright (C) 2023 Marceline Cramer
+//
+// Willow is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// Willow is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with Willow.  If not, see <https://www.gnu.org/licenses/>.
+
+use willow_protocol::glam::Vec2;
+
+use crate::font::{Outline, OutlineCommand};
+
+/// The distance in pixels between two edges above which they're kept at
+/// least a pixel apart, so that thin horizontal strokes don't disappear.
+const MIN_STEM: f32 = 0.5;
+
+/// Autohints an outline vertically, moving its points so that its horizontal
+/// edges and the tops and bottoms of its curves land on the pixel grid.
+///
+/// This finds the edges from the outline alone. It doesn't run the hinting
+/// instructions in TrueType fonts, so it works the same for every font.
+///
+/// `scale` is the number of pixels per font unit the outline is drawn at,
+/// with its origin on a pixel boundary. Points between edges are moved in
+/// proportion to their distance from them, and glyphs aren't changed
+/// horizontally, so that they can still be positioned between pixels.
+pub fn autohint(outline: &Outline, scale: f32) -> Outline {
+    let mut edges = find_edges(outline);
+    edges.sort_by(f32::total_cmp);
+    edges.dedup();
+
+    // each edge's height in font units, and its height after fitting
+    let mut fitted: Vec<(f32, f32)> = Vec::with_capacity(edges.len());
+    for y in edges {
+        let mut pixel = (y * scale).round();
+        if let Some((below, below_pixel)) = fitted.last() {
+            if (y - below) * scale >= MIN_STEM {
+                pixel = pixel.max(below_pixel * scale + 1.0);
+            }
+        }
+
+        fitted.push((y, pixel / scale));
+    }
+
+    let fit = |point: Vec2| Vec2::new(point.x, fit_height(&fitted, point.y));
+
+    let mut hinted = Outline::default();
+    for command in outline.commands.iter() {
+        use OutlineCommand::*;
+        match *command {
+            MoveTo(to) => {
+                let to = fit(to);
+                hinted.push(MoveTo(to), &[to]);
+            }
+            LineTo(to) => {
+                let to = fit(to);
+                hinted.push(LineTo(to), &[to]);
+            }
+            QuadTo(ctrl, to) => {
+                let (ctrl, to) = (fit(ctrl), fit(to));
+                hinted.push(QuadTo(ctrl, to), &[ctrl, to]);
+            }
+            CubicTo(ctrl1, ctrl2, to) => {
+                let (ctrl1, ctrl2, to) = (fit(ctrl1), fit(ctrl2), fit(to));
+                hinted.push(CubicTo(ctrl1, ctrl2, to), &[ctrl1, ctrl2, to]);
+            }
+            Close => hinted.push(Close, &[]),
+        }
+    }
+
+    hinted
+}
+
+/// Moves a height between the nearest fitted edges above and below it.
+fn fit_height(fitted: &[(f32, f32)], y: f32) -> f32 {
+    let above = fitted.partition_point(|(edge, _)| *edge <= y);
+    let below = above.checked_sub(1).map(|below| fitted[below]);
+    match (below, fitted.get(above)) {
+        (Some((y0, fit0)), Some((y1, fit1))) => fit0 + (y - y0) / (y1 - y0) * (fit1 - fit0),
+        (Some((y0, fit0)), None) => fit0 + (y - y0),
+        (None, Some((y1, fit1))) => fit1 + (y - y1),
+        (None, None) => y,
+    }
+}
+
+/// Finds the heights of an outline's edges: the on-curve points that are at
+/// the top or bottom of their contour locally, including the ends of
+/// horizontal lines.
+fn find_edges(outline: &Outline) -> Vec<f32> {
+    let mut edges = Vec::new();
+    let mut contour = Vec::new();
+
+    for command in outline.commands.iter() {
+        use OutlineCommand::*;
+        match *command {
+            MoveTo(to) => {
+                contour_edges(&contour, &mut edges);
+                contour.clear();
+                contour.push((to, true));
+            }
+            LineTo(to) => contour.push((to, true)),
+            QuadTo(ctrl, to) => contour.extend([(ctrl, false), (to, true)]),
+            CubicTo(ctrl1, ctrl2, to) => {
+                contour.extend([(ctrl1, false), (ctrl2, false), (to, true)])
+            }
+            Close => {
+                contour_edges(&contour, &mut edges);
+                contour.clear();
+            }
+        }
+    }
+
+    contour_edges(&contour, &mut edges);
+    edges
+}
+
+/// Adds the heights of the edges of a closed contour, given as its points
+/// and whether they're on the curve.
+fn contour_edges(contour: &[(Vec2, bool)], edges: &mut Vec<f32>) {
+    // contours often end with their first point again
+    let points = match contour {
+        [(first, _), rest @ .., (last, _)] if first == last => &contour[..rest.len() + 1],
+        _ => contour,
+    };
+
+    let len = points.len();
+    if len < 2 {
+        return;
+    }
+
+    for (idx, (point, on_curve)) in points.iter().enumerate() {
+        if !on_curve {
+            continue;
+        }
+
+        let prev = points[(idx + len - 1) % len].0.y;
+        let next = points[(idx + 1) % len].0.y;
+        let y = point.y;
+        if (prev <= y && next <= y) || (prev >= y && next >= y) {
+            edges.push(y);
+        }
+    }
+}
+
+/// Records a hinted [Outline] from [skrifa], converting its points from
+/// pixels back into font units.
+pub(crate) struct HintedPen {
+    outline: Outline,
+
+    /// The number of font units per pixel.
+    units: f32,
+}
+
+impl HintedPen {
+    /// Creates a pen for outlines hinted at `scale` pixels per font unit.
+    pub fn new(scale: f32) -> Self {
+        Self {
+            outline: Outline::default(),
+            units: scale.recip(),
+        }
+    }
+
+    pub fn finish(self) -> Outline {
+        self.outline
+    }
+
+    fn point(&self, x: f32, y: f32) -> Vec2 {
+        Vec2::new(x, y) * self.units
+    }
+}
+
+impl skrifa::outline::OutlinePen for HintedPen {
+    fn move_to(&mut self, x: f32, y: f32) {
+        let to = self.point(x, y);
+        self.outline.push(OutlineCommand::MoveTo(to), &[to]);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let to = self.point(x, y);
+        self.outline.push(OutlineCommand::LineTo(to), &[to]);
+    }
+
+    fn quad_to(&mut self, cx0: f32, cy0: f32, x: f32, y: f32) {
+        let (ctrl, to) = (self.point(cx0, cy0), self.point(x, y));
+        self.outline
+            .push(OutlineCommand::QuadTo(ctrl, to), &[ctrl, to]);
+    }
+
+    fn curve_to(&mut self, cx0: f32, cy0: f32, cx1: f32, cy1: f32, x: f32, y: f32) {
+        let (c1, c2, to) = (self.point(cx0, cy0), self.point(cx1, cy1), self.point(x, y));
+        self.outline
+            .push(OutlineCommand::CubicTo(c1, c2, to), &[c1, c2, to]);
+    }
+
+    fn close(&mut self) {
+        self.outline.push(OutlineCommand::Close, &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rectangle(outline: &mut Outline, min: Vec2, max: Vec2) {
+        use OutlineCommand::*;
+        let corners = [min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y)];
+        outline.push(MoveTo(corners[0]), &corners[..1]);
+        for corner in corners[1..].iter() {
+            outline.push(LineTo(*corner), &[*corner]);
+        }
+
+        outline.push(Close, &[]);
+    }
+
+    #[test]
+    fn edges_snap_to_pixels() {
+        let mut outline = Outline::default();
+        rectangle(&mut outline, Vec2::new(0.0, 0.3), Vec2::new(5.0, 7.6));
+        let hinted = autohint(&outline, 1.0);
+        assert_eq!(hinted.min, Vec2::new(0.0, 0.0));
+        assert_eq!(hinted.max, Vec2::new(5.0, 8.0));
+    }
+
+    #[test]
+    fn thin_strokes_keep_a_pixel() {
+        let mut outline = Outline::default();
+        rectangle(&mut outline, Vec2::new(0.0, 10.6), Vec2::new(5.0, 11.2));
+        let hinted = autohint(&outline, 1.0);
+        assert_eq!(hinted.min.y, 11.0);
+        assert_eq!(hinted.max.y, 12.0);
+    }
+
+    #[test]
+    fn font_hinting_fits_edges_to_pixels() {
+        use crate::font::FontData;
+
+        // Noto Sans has no instructions, so this is hinted by the fallback
+        let mut font = FontData::load(notosans::REGULAR_TTF.to_vec(), 0).unwrap();
+        let index = font.glyph_index('H');
+        let ppem = 11.0;
+        let scale = ppem / font.metrics().units_per_em;
+
+        let unhinted = font.glyph_outline(index);
+        let hinted = font.hinted_outline(index, ppem).unwrap();
+        assert_ne!(hinted, *unhinted);
+
+        // the baseline and cap height land on whole pixels
+        for height in [hinted.min.y, hinted.max.y] {
+            let pixels = height * scale;
+            assert!((pixels - pixels.round()).abs() < 0.01, "{pixels}");
+        }
+    }
+}
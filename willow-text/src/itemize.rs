@@ -0,0 +1,157 @@
+// Copyright (C) 2023 Marceline Cramer
+//
+// Willow is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// Willow is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with Willow.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::ops::Range;
+
+use allsorts::glyph_position::TextDirection;
+use allsorts::tag;
+use unicode_bidi::BidiInfo;
+use unicode_script::{Script, UnicodeScript};
+
+/// A range of text with a single script and direction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextRun {
+    /// The byte range of this run in the itemized text.
+    pub range: Range<usize>,
+
+    /// The OpenType script tag to shape this run with.
+    pub script: u32,
+
+    /// The direction to shape this run in.
+    pub direction: TextDirection,
+}
+
+/// Splits a string into runs of a single script and direction, applying the
+/// Unicode bidirectional algorithm to place the runs in visual order from
+/// left to right.
+///
+/// The paragraph direction is detected from the first strong character.
+pub fn visual_runs(text: &str) -> Vec<TextRun> {
+    let bidi = BidiInfo::new(text, None);
+    let mut runs = Vec::new();
+
+    for para in bidi.paragraphs.iter() {
+        let (levels, level_runs) = bidi.visual_runs(para, para.range.clone());
+
+        for level_run in level_runs {
+            let direction = match levels[level_run.start].is_rtl() {
+                true => TextDirection::RightToLeft,
+                false => TextDirection::LeftToRight,
+            };
+
+            let mut script_runs = script_runs(&text[level_run.clone()]);
+
+            // script runs are in logical order, which is reversed visually
+            if direction == TextDirection::RightToLeft {
+                script_runs.reverse();
+            }
+
+            for (range, script) in script_runs {
+                runs.push(TextRun {
+                    range: (range.start + level_run.start)..(range.end + level_run.start),
+                    script,
+                    direction,
+                });
+            }
+        }
+    }
+
+    runs
+}
+
+/// Splits a string into runs of a single script in logical order.
+///
+/// Characters that are shared between scripts, like punctuation and digits,
+/// join the run before them, or the run after them at the start of the text.
+pub fn script_runs(text: &str) -> Vec<(Range<usize>, u32)> {
+    let mut runs: Vec<(Range<usize>, Option<Script>)> = Vec::new();
+
+    for (idx, ch) in text.char_indices() {
+        let end = idx + ch.len_utf8();
+        let script = match ch.script() {
+            Script::Common | Script::Inherited | Script::Unknown => None,
+            script => Some(script),
+        };
+
+        match runs.last_mut() {
+            Some((range, last)) if script.is_none() || *last == script => range.end = end,
+            Some((range, last @ None)) => {
+                range.end = end;
+                *last = script;
+            }
+            _ => runs.push((idx..end, script)),
+        }
+    }
+
+    runs.into_iter()
+        .map(|(range, script)| (range, script.map(script_tag).unwrap_or(tag::LATN)))
+        .collect()
+}
+
+/// Converts a Unicode script into an OpenType script tag.
+pub fn script_tag(script: Script) -> u32 {
+    use Script::*;
+    match script {
+        Common | Inherited | Unknown => tag::DFLT,
+        Han => u32::from_be_bytes(*b"hani"),
+        Hiragana | Katakana => u32::from_be_bytes(*b"kana"),
+        Lao => tag::LAO,
+        Nko => u32::from_be_bytes(*b"nko "),
+        Vai => u32::from_be_bytes(*b"vai "),
+        Yi => u32::from_be_bytes(*b"yi  "),
+        // other script tags are lowercase ISO 15924 codes
+        script => script.as_iso15924_tag() | 0x2000_0000,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn common_characters_join_runs() {
+        let text = "12 abc, \u{5e9}\u{5dc}\u{5d5}\u{5dd}!";
+        let runs = script_runs(text);
+        let hebrew = u32::from_be_bytes(*b"hebr");
+        assert_eq!(runs, vec![(0..8, tag::LATN), (8..text.len(), hebrew)]);
+    }
+
+    #[test]
+    fn right_to_left_runs_are_reversed() {
+        let text = "abc \u{5e9}\u{5dc}\u{5d5}\u{5dd} def";
+        let runs = visual_runs(text);
+        let directions: Vec<_> = runs.iter().map(|run| run.direction).collect();
+        let ranges: Vec<_> = runs.iter().map(|run| run.range.clone()).collect();
+
+        assert_eq!(
+            directions,
+            vec![
+                TextDirection::LeftToRight,
+                TextDirection::RightToLeft,
+                TextDirection::LeftToRight
+            ]
+        );
+
+        assert_eq!(ranges, vec![0..4, 4..12, 12..16]);
+    }
+
+    #[test]
+    fn right_to_left_paragraph() {
+        let text = "\u{5e9}\u{5dc}\u{5d5}\u{5dd} abc";
+        let runs = visual_runs(text);
+        let ranges: Vec<_> = runs.iter().map(|run| run.range.clone()).collect();
+        assert_eq!(ranges, vec![9..12, 0..9]);
+    }
+}
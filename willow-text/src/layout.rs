@@ -0,0 +1,296 @@
+// Copyright (C) 2023 Marceline Cramer
+//
+// Willow is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// Willow is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with Willow.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::ops::Range;
+
+use willow_protocol::{
+    glam::{Affine2, Vec2},
+    Font, TextSpan,
+};
+
+use crate::registry::FaceId;
+
+/// An axis-aligned rectangle in pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl Bounds {
+    /// The empty bounds, which contain nothing and add nothing to a union.
+    pub const EMPTY: Self = Self {
+        min: Vec2::INFINITY,
+        max: Vec2::NEG_INFINITY,
+    };
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y
+    }
+
+    /// Turns these bounds a quarter turn clockwise around the origin.
+    pub fn turn_clockwise(&self) -> Self {
+        Self {
+            min: Vec2::new(-self.max.y, self.min.x),
+            max: Vec2::new(-self.min.y, self.max.x),
+        }
+    }
+}
+
+/// How a [PositionedGlyph] is turned from its usual orientation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum GlyphRotation {
+    #[default]
+    Upright,
+
+    /// A quarter turn clockwise, like sideways text in vertical lines.
+    Clockwise,
+
+    /// A quarter turn counter-clockwise.
+    CounterClockwise,
+}
+
+impl GlyphRotation {
+    /// Adds a quarter turn clockwise to this rotation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the glyph is already turned clockwise, since glyphs are
+    /// never drawn upside down.
+    pub fn turn_clockwise(self) -> Self {
+        match self {
+            GlyphRotation::Upright => GlyphRotation::Clockwise,
+            GlyphRotation::CounterClockwise => GlyphRotation::Upright,
+            GlyphRotation::Clockwise => panic!("glyph turned upside down"),
+        }
+    }
+}
+
+/// A glyph placed in a [TextLayout].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PositionedGlyph {
+    /// The face to draw this glyph with.
+    pub face: FaceId,
+
+    /// The index of the glyph in its face.
+    pub index: u16,
+
+    /// The index of the [StyledRange] that this glyph was shaped from. This is
+    /// always 0 for text with a single style.
+    pub span: usize,
+
+    /// The byte offset in the laid-out text of the first character that this
+    /// glyph was shaped from.
+    pub cluster: usize,
+
+    /// The position of the glyph's origin in pixels, relative to the start of
+    /// the baseline. The Y axis points down.
+    pub position: Vec2,
+
+    /// How far the pen moves after this glyph, in pixels.
+    pub advance: Vec2,
+
+    /// The number of pixels per font unit of this glyph's face.
+    pub scale: f32,
+
+    /// How the glyph is turned around its origin.
+    pub rotation: GlyphRotation,
+}
+
+impl PositionedGlyph {
+    /// The transform from the glyph's outline, in font units with the Y axis
+    /// pointing up, to pixels in its layout.
+    pub fn transform(&self) -> Affine2 {
+        let scale = self.scale;
+        let (x_axis, y_axis) = match self.rotation {
+            GlyphRotation::Upright => (Vec2::new(scale, 0.0), Vec2::new(0.0, -scale)),
+            GlyphRotation::Clockwise => (Vec2::new(0.0, scale), Vec2::new(scale, 0.0)),
+            GlyphRotation::CounterClockwise => (Vec2::new(0.0, -scale), Vec2::new(-scale, 0.0)),
+        };
+
+        Affine2::from_cols(x_axis, y_axis, self.position)
+    }
+
+    /// Turns this glyph's position, advance, and orientation a quarter turn
+    /// clockwise around the origin of its layout.
+    pub fn turn_clockwise(&mut self) {
+        self.position = turn_clockwise(self.position);
+        self.advance = turn_clockwise(self.advance);
+        self.rotation = self.rotation.turn_clockwise();
+    }
+}
+
+/// Turns a vector a quarter turn clockwise, with the Y axis pointing down.
+fn turn_clockwise(v: Vec2) -> Vec2 {
+    Vec2::new(-v.y, v.x)
+}
+
+/// A range of text laid out with a single style.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StyledRange<'a> {
+    /// The byte range of the styled text.
+    pub range: Range<usize>,
+
+    /// The font to lay the text out with.
+    pub font: &'a Font,
+
+    /// The size of the font's em square in pixels.
+    pub size: f32,
+
+    /// Whether to fill the box behind the text.
+    pub background: bool,
+
+    /// Whether to draw a line under the text.
+    pub underline: bool,
+
+    /// Whether to draw a line through the text.
+    pub strikethrough: bool,
+}
+
+impl<'a> StyledRange<'a> {
+    /// Styles a whole string with a single font and no decorations.
+    pub fn plain(text: &str, font: &'a Font, size: f32) -> Self {
+        Self {
+            range: 0..text.len(),
+            font,
+            size,
+            background: false,
+            underline: false,
+            strikethrough: false,
+        }
+    }
+
+    /// Joins the contents of a list of spans into one string, returning it
+    /// with the range of each span within it.
+    pub fn from_spans(spans: &'a [TextSpan]) -> (String, Vec<Self>) {
+        let mut text = String::new();
+        let mut styles = Vec::with_capacity(spans.len());
+
+        for span in spans.iter() {
+            let start = text.len();
+            text.push_str(&span.content);
+
+            styles.push(Self {
+                range: start..text.len(),
+                font: &span.font,
+                size: span.size,
+                background: span.background.is_some(),
+                underline: span.underline,
+                strikethrough: span.strikethrough,
+            });
+        }
+
+        (text, styles)
+    }
+
+    /// Tests whether this style draws any decorations.
+    pub fn is_decorated(&self) -> bool {
+        self.background || self.underline || self.strikethrough
+    }
+}
+
+/// A kind of [Decoration].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DecorationKind {
+    Background,
+    Underline,
+    Strikethrough,
+}
+
+/// A rectangle drawn behind, under, or through a span of laid-out text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decoration {
+    /// The index of the [StyledRange] that this decoration belongs to.
+    pub span: usize,
+
+    pub kind: DecorationKind,
+
+    /// The rectangle to fill, in the same space as the glyphs.
+    pub bounds: Bounds,
+}
+
+/// A string of text that has been shaped and positioned.
+///
+/// All measurements are in pixels with the Y axis pointing down, relative to
+/// the start of the baseline.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextLayout {
+    /// The glyphs in visual order.
+    pub glyphs: Vec<PositionedGlyph>,
+
+    /// How far the pen moved after drawing every glyph.
+    pub advance: Vec2,
+
+    /// The distance from the baseline to the top of the tallest face used.
+    pub ascent: f32,
+
+    /// The distance from the baseline to the bottom of the lowest face used.
+    pub descent: f32,
+
+    /// The recommended spacing between this text's baseline and the next.
+    pub line_height: f32,
+
+    /// The rectangles to fill for the decorations of styled text. Backgrounds
+    /// come first, so that they are drawn under the glyphs.
+    pub decorations: Vec<Decoration>,
+
+    /// The bounds of every glyph outline and decoration in the layout.
+    pub ink: Bounds,
+}
+
+/// The measurements of a string of text used to lay it out alongside other
+/// content, in pixels.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TextMetrics {
+    /// How far the text advances along its baseline.
+    pub advance: f32,
+
+    /// The distance from the baseline to the top of the tallest face used.
+    pub ascent: f32,
+
+    /// The distance from the baseline to the bottom of the lowest face used.
+    pub descent: f32,
+
+    /// The recommended spacing between this text's baseline and the next.
+    pub line_height: f32,
+}
+
+impl TextLayout {
+    /// Measures this layout.
+    pub fn metrics(&self) -> TextMetrics {
+        TextMetrics {
+            advance: self.advance.x,
+            ascent: self.ascent,
+            descent: self.descent,
+            line_height: self.line_height,
+        }
+    }
+
+    /// The box that the text occupies along its baseline, from the ascent to
+    /// the descent and across the advance.
+    pub fn advance_bounds(&self) -> Bounds {
+        Bounds {
+            min: Vec2::new(0.0, -self.ascent),
+            max: Vec2::new(self.advance.x, self.descent),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+// Copyright (C) 2023 Marceline Cramer
+//
+// Willow is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// Willow is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with Willow.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{cell::RefCell, rc::Rc};
+
+pub mod color;
+pub mod font;
+pub mod hinting;
+pub mod itemize;
+pub mod layout;
+pub mod paragraph;
+pub mod registry;
+
+pub use color::{ColorGlyph, ColorLayer, GlyphBitmap};
+pub use font::{FontData, FontError, FontResult};
+pub use layout::{
+    Bounds, Decoration, DecorationKind, GlyphRotation, PositionedGlyph, StyledRange, TextLayout,
+    TextMetrics,
+};
+pub use paragraph::{Line, ParagraphLayout};
+pub use registry::{FaceId, FontFace, FontRegistry};
+
+/// A [FontRegistry] shared between the shape tree, which lays text out to
+/// find its bounds, and the renderer, which draws it.
+pub type SharedFonts = Rc<RefCell<FontRegistry>>;
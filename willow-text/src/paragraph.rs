@@ -0,0 +1,589 @@
+// Copyright (C) 2023 Marceline Cramer
+//
+// Willow is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// Willow is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with Willow.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::ops::Range;
+
+use unicode_bidi::Direction;
+use unicode_linebreak::BreakOpportunity;
+use willow_protocol::{glam::Vec2, Font, ParagraphStyle, TextAlign, TextOverflow, WritingMode};
+
+use crate::layout::{Bounds, Decoration, DecorationKind, PositionedGlyph, StyledRange, TextLayout};
+use crate::registry::FontRegistry;
+
+/// The text appended to the last line of a paragraph truncated with
+/// [TextOverflow::Ellipsis].
+pub const ELLIPSIS: &str = "\u{2026}";
+
+/// A line in a [ParagraphLayout].
+///
+/// The lines of vertical paragraphs are measured as if the paragraph were
+/// turned a quarter turn counter-clockwise, so that their baselines are
+/// measured from its right edge and their X positions from its top edge.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    /// The byte range of the line's text in the paragraph, excluding trailing
+    /// whitespace and line breaks.
+    pub range: Range<usize>,
+
+    /// The distance from the top of the paragraph to this line's baseline.
+    pub baseline: f32,
+
+    /// The distance from the left edge of the paragraph to the start of
+    /// this line's first glyph.
+    pub x: f32,
+
+    /// How far this line advances, including the space added to justify it.
+    pub width: f32,
+}
+
+/// A paragraph of text that has been broken into lines and positioned.
+///
+/// All measurements are in pixels with the Y axis pointing down, relative to
+/// the top-left corner of the paragraph, or the top-right corner of vertical
+/// paragraphs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParagraphLayout {
+    /// The glyphs of every line.
+    pub glyphs: Vec<PositionedGlyph>,
+
+    /// The lines from top to bottom, or from right to left in vertical
+    /// paragraphs.
+    pub lines: Vec<Line>,
+
+    /// The size of the paragraph's box: its maximum width and the combined
+    /// height of its lines, or the combined width of its columns and their
+    /// maximum height in vertical paragraphs.
+    pub size: Vec2,
+
+    /// Whether the paragraph's lines run across or down it.
+    pub writing_mode: WritingMode,
+
+    /// The rectangles to fill for the decorations of styled text. Backgrounds
+    /// come first, so that they are drawn under the glyphs.
+    pub decorations: Vec<Decoration>,
+
+    /// The bounds of every glyph outline and decoration in the layout.
+    pub ink: Bounds,
+}
+
+impl ParagraphLayout {
+    /// The paragraph's box, which spans its size from its origin.
+    pub fn box_bounds(&self) -> Bounds {
+        match self.writing_mode {
+            WritingMode::Horizontal => Bounds {
+                min: Vec2::ZERO,
+                max: self.size,
+            },
+            WritingMode::Vertical => Bounds {
+                min: Vec2::new(-self.size.x, 0.0),
+                max: Vec2::new(0.0, self.size.y),
+            },
+        }
+    }
+}
+
+/// A line of text picked by [break_lines].
+struct LineBreak {
+    range: Range<usize>,
+
+    /// Whether this line ends in a mandatory break, or ends the paragraph.
+    hard: bool,
+}
+
+impl FontRegistry {
+    /// Breaks a string into lines at the opportunities given by the Unicode
+    /// line breaking algorithm, then aligns and positions the lines.
+    pub fn layout_paragraph(
+        &mut self,
+        font: &Font,
+        text: &str,
+        size: f32,
+        style: &ParagraphStyle,
+    ) -> ParagraphLayout {
+        let styles = [StyledRange::plain(text, font, size)];
+        self.layout_paragraph_styled(text, &styles, style)
+    }
+
+    /// Lays out a paragraph made of styled ranges, such as the spans of rich
+    /// text. The ranges must cover the string in order.
+    ///
+    /// Vertical paragraphs are laid out like horizontal ones turned a quarter
+    /// turn counter-clockwise, then turned back.
+    pub fn layout_paragraph_styled(
+        &mut self,
+        text: &str,
+        styles: &[StyledRange],
+        style: &ParagraphStyle,
+    ) -> ParagraphLayout {
+        let vertical = style.writing_mode == WritingMode::Vertical;
+        let advances = Advances::new(&self.layout_line(text, styles, vertical), text.len());
+        let mut breaks = break_lines(text, &advances, style.max_width);
+        let mut ellipsis = false;
+
+        if let Some(max_lines) = style.max_lines {
+            let max_lines = max_lines as usize;
+            if breaks.len() > max_lines {
+                breaks.truncate(max_lines);
+                ellipsis = style.overflow == TextOverflow::Ellipsis;
+            }
+        }
+
+        let last_line = breaks.len().saturating_sub(1);
+        let mut lines = Vec::with_capacity(breaks.len());
+        for (index, line) in breaks.into_iter().enumerate() {
+            let trimmed = text[line.range.clone()].trim_end();
+            let mut range = line.range.start..line.range.start + trimmed.len();
+            let mut hard = line.hard;
+            let mut suffix = "";
+
+            if ellipsis && index == last_line {
+                range.end = self.fit_ellipsis(text, styles, &advances, range.clone(), style);
+                range.end = range.start + text[range.clone()].trim_end().len();
+                suffix = ELLIPSIS;
+                hard = true;
+            }
+
+            let mut layout = self.layout_range(text, styles, range.clone(), suffix, vertical);
+            for glyph in layout.glyphs.iter_mut() {
+                glyph.cluster = (range.start + glyph.cluster).min(range.end);
+            }
+
+            lines.push((range, hard, layout));
+        }
+
+        // every line is spaced by the tallest line so that baselines are even
+        let mut ascent = 0.0f32;
+        let mut descent = 0.0f32;
+        let mut font_line_height = 0.0f32;
+        for (_, _, line) in lines.iter() {
+            ascent = ascent.max(line.ascent);
+            descent = descent.max(line.descent);
+            font_line_height = font_line_height.max(line.line_height);
+        }
+
+        let line_height = style.line_height.unwrap_or(font_line_height);
+        let half_leading = (line_height - ascent - descent) / 2.0;
+        let first_baseline = half_leading + ascent;
+        let right_to_left = unicode_bidi::get_base_direction_full(text) == Direction::Rtl;
+
+        let mut layout = ParagraphLayout {
+            glyphs: Vec::new(),
+            lines: Vec::with_capacity(lines.len()),
+            size: Vec2::new(style.max_width, line_height * lines.len() as f32),
+            writing_mode: style.writing_mode,
+            decorations: Vec::new(),
+            ink: Bounds::EMPTY,
+        };
+
+        for (index, (range, hard, line_layout)) in lines.into_iter().enumerate() {
+            let mut width = line_layout.advance.x;
+            let free = style.max_width - width;
+            let baseline = first_baseline + line_height * index as f32;
+
+            let mut x = match (style.align, right_to_left) {
+                (TextAlign::Center, _) => free / 2.0,
+                (TextAlign::Start | TextAlign::Justify, false) | (TextAlign::End, true) => 0.0,
+                (TextAlign::Start | TextAlign::Justify, true) | (TextAlign::End, false) => free,
+            };
+
+            let mut glyphs = line_layout.glyphs;
+            if style.align == TextAlign::Justify && !hard && self.justify(&mut glyphs, free) {
+                x = 0.0;
+                width = style.max_width;
+            }
+
+            for glyph in glyphs.iter_mut() {
+                glyph.position += Vec2::new(x, baseline);
+                layout.ink = layout.ink.union(&self.glyph_bounds(glyph));
+            }
+
+            for decoration in self.decorate(&glyphs, styles, baseline, vertical) {
+                layout.ink = layout.ink.union(&decoration.bounds);
+                layout.decorations.push(decoration);
+            }
+
+            layout.glyphs.extend(glyphs);
+            layout.lines.push(Line {
+                range,
+                baseline,
+                x,
+                width,
+            });
+        }
+
+        // draw every background under every line
+        layout
+            .decorations
+            .sort_by_key(|decoration| decoration.kind != DecorationKind::Background);
+
+        if vertical {
+            for glyph in layout.glyphs.iter_mut() {
+                glyph.turn_clockwise();
+            }
+
+            for decoration in layout.decorations.iter_mut() {
+                decoration.bounds = decoration.bounds.turn_clockwise();
+            }
+
+            layout.ink = layout.ink.turn_clockwise();
+            layout.size = Vec2::new(layout.size.y, layout.size.x);
+        }
+
+        layout
+    }
+
+    /// Lays out a range of styled text on one line, followed by `suffix` in
+    /// the style of the end of the range.
+    ///
+    /// The glyphs' spans index into `styles`, like those of the whole text.
+    fn layout_range(
+        &mut self,
+        text: &str,
+        styles: &[StyledRange],
+        range: Range<usize>,
+        suffix: &str,
+        vertical: bool,
+    ) -> TextLayout {
+        let mut line = text[range.clone()].to_string();
+        line.push_str(suffix);
+
+        let clamp = |offset: usize| offset.clamp(range.start, range.end) - range.start;
+        let mut line_styles: Vec<_> = styles
+            .iter()
+            .map(|style| StyledRange {
+                range: clamp(style.range.start)..clamp(style.range.end),
+                ..style.clone()
+            })
+            .collect();
+
+        if !suffix.is_empty() && !line_styles.is_empty() {
+            let last = suffix_style(styles, range);
+            line_styles[last].range.end = line.len();
+            for style in line_styles[last + 1..].iter_mut() {
+                style.range = line.len()..line.len();
+            }
+        }
+
+        self.layout_line(&line, &line_styles, vertical)
+    }
+
+    /// Shortens the last line of a truncated paragraph until it fits within
+    /// the style's maximum width with an ellipsis after it. Returns the new
+    /// end of the line.
+    fn fit_ellipsis(
+        &mut self,
+        text: &str,
+        styles: &[StyledRange],
+        advances: &Advances,
+        range: Range<usize>,
+        style: &ParagraphStyle,
+    ) -> usize {
+        let vertical = style.writing_mode == WritingMode::Vertical;
+        let mut ellipsis_widths = vec![None; styles.len()];
+        let mut end = range.end;
+
+        while end > range.start {
+            let trimmed = range.start + text[range.start..end].trim_end().len();
+            let mut width = advances.measure(range.start..trimmed);
+
+            if !styles.is_empty() {
+                // the ellipsis takes the style of the end of the line
+                let span = suffix_style(styles, range.start..trimmed);
+                width += *ellipsis_widths[span].get_or_insert_with(|| {
+                    let ellipsis = StyledRange {
+                        range: 0..ELLIPSIS.len(),
+                        ..styles[span].clone()
+                    };
+
+                    self.layout_line(ELLIPSIS, &[ellipsis], vertical).advance.x
+                });
+            }
+
+            if width <= style.max_width {
+                break;
+            }
+
+            end = text[..end]
+                .char_indices()
+                .last()
+                .map(|(offset, _)| offset)
+                .unwrap();
+            end = end.max(range.start);
+        }
+
+        end
+    }
+
+    /// Spreads `extra` pixels between the spaces in a line of glyphs.
+    /// Returns false if the line has no spaces to stretch.
+    fn justify(&mut self, glyphs: &mut [PositionedGlyph], extra: f32) -> bool {
+        let mut is_space = Vec::with_capacity(glyphs.len());
+        for glyph in glyphs.iter() {
+            let space = self
+                .get_mut(glyph.face)
+                .map(|face| face.data.glyph_index(' '))
+                .unwrap_or(0);
+
+            is_space.push(space != 0 && space == glyph.index);
+        }
+
+        let spaces = is_space.iter().filter(|space| **space).count();
+        if spaces == 0 || extra <= 0.0 {
+            return false;
+        }
+
+        let step = extra / spaces as f32;
+        let mut shift = 0.0;
+        for (glyph, space) in glyphs.iter_mut().zip(is_space) {
+            glyph.position.x += shift;
+
+            if space {
+                shift += step;
+            }
+        }
+
+        true
+    }
+}
+
+/// The advances of a paragraph's text shaped as a single line, which measure
+/// ranges of it without shaping them again.
+struct Advances {
+    /// The total advance of the glyphs shaped from before each byte offset.
+    prefix: Vec<f32>,
+}
+
+impl Advances {
+    fn new(layout: &TextLayout, len: usize) -> Self {
+        let mut prefix = vec![0.0; len + 1];
+        for glyph in layout.glyphs.iter() {
+            prefix[glyph.cluster + 1] += glyph.advance.x;
+        }
+
+        for offset in 1..prefix.len() {
+            prefix[offset] += prefix[offset - 1];
+        }
+
+        Self { prefix }
+    }
+
+    /// Measures how far a range of the text advances along a line.
+    fn measure(&self, range: Range<usize>) -> f32 {
+        self.prefix[range.end] - self.prefix[range.start]
+    }
+}
+
+/// Greedily fills lines with as much text as fits within a maximum width,
+/// breaking words that are wider than a line on their own between
+/// characters.
+fn break_lines(text: &str, advances: &Advances, max_width: f32) -> Vec<LineBreak> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    let mut end = 0;
+
+    for (pos, opportunity) in unicode_linebreak::linebreaks(text) {
+        let trimmed = start + text[start..pos].trim_end().len();
+        if end > start && advances.measure(start..trimmed) > max_width {
+            lines.push(LineBreak {
+                range: start..end,
+                hard: false,
+            });
+
+            start = end;
+        }
+
+        loop {
+            let word_end = start + text[start..pos].trim_end().len();
+            if advances.measure(start..word_end) <= max_width {
+                break;
+            }
+
+            let fit = fit_chars(text, advances, start..word_end, max_width);
+            if fit >= word_end {
+                break;
+            }
+
+            lines.push(LineBreak {
+                range: start..fit,
+                hard: false,
+            });
+
+            start = fit;
+        }
+
+        end = pos;
+
+        if opportunity == BreakOpportunity::Mandatory {
+            lines.push(LineBreak {
+                range: start..pos,
+                hard: true,
+            });
+
+            start = pos;
+        }
+    }
+
+    lines
+}
+
+/// Finds the end of the longest start of a range that fits within a maximum
+/// width, which is always at least one character long.
+fn fit_chars(text: &str, advances: &Advances, range: Range<usize>, max_width: f32) -> usize {
+    let word = &text[range.clone()];
+    let mut fit = range.start + word.chars().next().map_or(0, char::len_utf8);
+
+    for (offset, _) in word.char_indices().skip(2) {
+        let end = range.start + offset;
+        if advances.measure(range.start..end) > max_width {
+            break;
+        }
+
+        fit = end;
+    }
+
+    fit
+}
+
+/// Finds the index of the style that text appended after a range of styled
+/// text takes, which is the style of the end of the range.
+///
+/// `styles` must not be empty.
+fn suffix_style(styles: &[StyledRange], range: Range<usize>) -> usize {
+    styles
+        .iter()
+        .rposition(|style| {
+            style.range.start < style.range.end
+                && style.range.start < range.end
+                && style.range.end > range.start
+        })
+        .unwrap_or_else(|| {
+            let at = styles.partition_point(|style| style.range.end <= range.start);
+            at.min(styles.len() - 1)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::GlyphRotation;
+
+    fn lines(text: &str, style: &ParagraphStyle) -> Vec<String> {
+        let mut fonts = FontRegistry::default();
+        let layout = fonts.layout_paragraph(&Font::default(), text, 10.0, style);
+        layout
+            .lines
+            .iter()
+            .map(|line| text[line.range.clone()].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn wraps_between_words() {
+        let style = ParagraphStyle::new(60.0);
+        let text = "the quick brown fox jumps over the lazy dog";
+        let lines = lines(text, &style);
+        assert!(lines.len() > 1);
+        assert_eq!(lines.join(" "), text);
+    }
+
+    #[test]
+    fn breaks_at_newlines() {
+        let style = ParagraphStyle::new(1000.0);
+        assert_eq!(
+            lines("one\ntwo\n\nthree", &style),
+            ["one", "two", "", "three"]
+        );
+    }
+
+    #[test]
+    fn breaks_long_words() {
+        let style = ParagraphStyle::new(20.0);
+        let lines = lines("abcdefghijklmnop", &style);
+        assert!(lines.len() > 1);
+        assert_eq!(lines.concat(), "abcdefghijklmnop");
+    }
+
+    #[test]
+    fn glyph_clusters_lie_in_their_lines() {
+        let mut fonts = FontRegistry::default();
+        let style = ParagraphStyle::new(60.0);
+        let text = "the quick brown fox jumps over the lazy dog";
+        let layout = fonts.layout_paragraph(&Font::default(), text, 10.0, &style);
+        let mut glyphs = layout.glyphs.iter();
+
+        for line in layout.lines.iter() {
+            let count = text[line.range.clone()].chars().count();
+            for glyph in glyphs.by_ref().take(count) {
+                assert!(line.range.contains(&glyph.cluster));
+            }
+        }
+    }
+
+    #[test]
+    fn ellipsis_fits_width() {
+        let mut fonts = FontRegistry::default();
+        let style = ParagraphStyle::new(60.0).with_max_lines(1, TextOverflow::Ellipsis);
+        let text = "the quick brown fox jumps over the lazy dog";
+        let layout = fonts.layout_paragraph(&Font::default(), text, 10.0, &style);
+        assert_eq!(layout.lines.len(), 1);
+        assert!(layout.ink.max.x <= 60.0);
+        assert_eq!(
+            layout.size.y,
+            fonts.measure(&Font::default(), "", 10.0).line_height
+        );
+    }
+
+    #[test]
+    fn alignment_moves_lines() {
+        let mut fonts = FontRegistry::default();
+        let font = Font::default();
+        let style = ParagraphStyle::new(100.0);
+        let start = fonts.layout_paragraph(&font, "hi", 10.0, &style);
+        let style = style.with_align(TextAlign::End);
+        let end = fonts.layout_paragraph(&font, "hi", 10.0, &style);
+        assert_eq!(start.lines[0].x, 0.0);
+        assert!((end.lines[0].x + end.lines[0].width - 100.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn justify_fills_width() {
+        let mut fonts = FontRegistry::default();
+        let style = ParagraphStyle::new(60.0).with_align(TextAlign::Justify);
+        let text = "the quick brown fox jumps over the lazy dog";
+        let layout = fonts.layout_paragraph(&Font::default(), text, 10.0, &style);
+        let first = &layout.lines[0];
+        assert_eq!(first.width, 60.0);
+        assert!(layout.ink.max.x > 55.0 && layout.ink.max.x <= 60.0);
+    }
+
+    #[test]
+    fn vertical_columns_run_right_to_left() {
+        let mut fonts = FontRegistry::default();
+        let style = ParagraphStyle::new(60.0).with_writing_mode(WritingMode::Vertical);
+        let text = "the quick brown fox jumps over the lazy dog";
+        let layout = fonts.layout_paragraph(&Font::default(), text, 10.0, &style);
+        let bounds = layout.box_bounds();
+        assert!(layout.lines.len() > 1);
+        assert_eq!(bounds.max, Vec2::new(0.0, 60.0));
+        assert!(layout.ink.min.x >= bounds.min.x && layout.ink.max.x <= 0.0);
+        assert!(layout.ink.max.y <= 60.0);
+
+        // latin text lies sideways, and later columns are further left
+        let first = layout.glyphs.first().unwrap();
+        let last = layout.glyphs.last().unwrap();
+        assert_eq!(first.rotation, GlyphRotation::Clockwise);
+        assert!(last.position.x < first.position.x);
+    }
+}
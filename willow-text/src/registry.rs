@@ -0,0 +1,1035 @@
+// Copyright (C) 2023 Marceline Cramer
+//
+// Willow is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// Willow is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with Willow.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use allsorts::glyph_position::TextDirection;
+use willow_protocol::{glam::Vec2, Font, FontStyle, FontWeight, ResourceHash};
+
+use crate::color::ColorGlyph;
+use crate::font::{FontData, FontError, FontResult};
+use crate::itemize;
+use crate::layout::{
+    Bounds, Decoration, DecorationKind, GlyphRotation, PositionedGlyph, StyledRange, TextLayout,
+    TextMetrics,
+};
+
+/// The file extensions of font files loaded by [FontRegistry::load_dir].
+pub const FONT_EXTENSIONS: &[&str] = &["ttf", "otf", "ttc", "otc"];
+
+/// The index of a face in a [FontRegistry].
+pub type FaceId = usize;
+
+/// A loaded font face and the attributes it is matched by.
+pub struct FontFace {
+    pub family: String,
+    pub weight: FontWeight,
+    pub style: FontStyle,
+    pub data: FontData,
+
+    /// The uploaded font file that this face was loaded from, if any.
+    pub resource: Option<ResourceHash>,
+}
+
+/// A set of loaded font faces that [Font] descriptors are resolved against.
+pub struct FontRegistry {
+    /// Every face by its ID. Removed faces leave gaps, so that IDs are never
+    /// reused and caches keyed by them don't need to be cleared.
+    faces: Vec<Option<FontFace>>,
+
+    /// The faces to search, in order, for characters that are missing from
+    /// the resolved face.
+    fallbacks: Vec<FaceId>,
+}
+
+impl Default for FontRegistry {
+    /// Creates a registry containing the built-in Noto Sans faces.
+    fn default() -> Self {
+        let mut registry = Self::new();
+
+        let faces = [
+            notosans::REGULAR_TTF,
+            notosans::BOLD_TTF,
+            notosans::ITALIC_TTF,
+            notosans::BOLD_ITALIC_TTF,
+        ];
+
+        for data in faces {
+            registry
+                .load_data(data.to_vec())
+                .expect("built-in font is invalid");
+        }
+
+        if let Some(regular) = registry.resolve(&Font::default()) {
+            registry.add_fallback(regular);
+        }
+
+        registry
+    }
+}
+
+impl FontRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self {
+            faces: Vec::new(),
+            fallbacks: Vec::new(),
+        }
+    }
+
+    /// Returns the number of faces ever added to the registry, including
+    /// removed faces. Face IDs are allocated sequentially, so this is also
+    /// the ID of the next added face.
+    pub fn len(&self) -> usize {
+        self.faces.len()
+    }
+
+    /// Tests whether the registry has no faces.
+    pub fn is_empty(&self) -> bool {
+        self.faces.iter().all(Option::is_none)
+    }
+
+    /// Gets the fallback chain.
+    pub fn fallbacks(&self) -> &[FaceId] {
+        &self.fallbacks
+    }
+
+    /// Replaces the fallback chain.
+    pub fn set_fallbacks(&mut self, fallbacks: Vec<FaceId>) {
+        self.fallbacks = fallbacks;
+    }
+
+    /// Appends a face to the end of the fallback chain.
+    pub fn add_fallback(&mut self, id: FaceId) {
+        if !self.fallbacks.contains(&id) {
+            self.fallbacks.push(id);
+        }
+    }
+
+    /// Adds a face to the registry and returns its ID.
+    pub fn add(
+        &mut self,
+        family: &str,
+        weight: FontWeight,
+        style: FontStyle,
+        data: FontData,
+    ) -> FaceId {
+        let id = self.faces.len();
+
+        self.faces.push(Some(FontFace {
+            family: family.to_string(),
+            weight,
+            style,
+            data,
+            resource: None,
+        }));
+
+        id
+    }
+
+    /// Adds a face to the registry using the family, weight, and style that
+    /// the font describes itself with.
+    pub fn add_data(&mut self, data: FontData) -> FaceId {
+        let family = data.family().unwrap_or_default();
+        let weight = data.weight();
+        let style = data.style();
+        self.add(&family, weight, style, data)
+    }
+
+    /// Loads every face in a font file's contents.
+    ///
+    /// If any face fails to load, none are added.
+    pub fn load_data(&mut self, file_buffer: Vec<u8>) -> FontResult<Vec<FaceId>> {
+        let num = FontData::count_faces(&file_buffer)?;
+        let mut faces = Vec::with_capacity(num);
+        for index in 0..num {
+            faces.push(FontData::load(file_buffer.clone(), index)?);
+        }
+
+        Ok(faces.into_iter().map(|face| self.add_data(face)).collect())
+    }
+
+    /// Loads every face in an uploaded font file, so that fonts referring to
+    /// it with [Font::resource] are drawn with its faces.
+    ///
+    /// The faces aren't added to the fallback chain.
+    pub fn load_resource(
+        &mut self,
+        resource: ResourceHash,
+        file_buffer: Vec<u8>,
+    ) -> FontResult<Vec<FaceId>> {
+        let ids = self.load_data(file_buffer)?;
+        for id in ids.iter() {
+            if let Some(face) = self.get_mut(*id) {
+                face.resource = Some(resource);
+            }
+        }
+
+        Ok(ids)
+    }
+
+    /// Removes every face loaded from an uploaded font file. Their IDs are
+    /// not reused.
+    pub fn remove_resource(&mut self, resource: ResourceHash) {
+        for (id, slot) in self.faces.iter_mut().enumerate() {
+            if slot.as_ref().and_then(|face| face.resource) == Some(resource) {
+                *slot = None;
+                self.fallbacks.retain(|fallback| *fallback != id);
+            }
+        }
+    }
+
+    /// Loads every face in a font file.
+    pub fn load_file(&mut self, path: impl AsRef<Path>) -> FontResult<Vec<FaceId>> {
+        let file_buffer = std::fs::read(path)?;
+        self.load_data(file_buffer)
+    }
+
+    /// Loads every font file in a directory and its subdirectories.
+    ///
+    /// Files that fail to load are skipped so that one bad font doesn't
+    /// prevent the rest from loading. The paths of the skipped files and
+    /// directories are returned together with their errors.
+    pub fn load_dir(&mut self, path: impl AsRef<Path>) -> Vec<(PathBuf, FontError)> {
+        let mut errors = Vec::new();
+        let mut dirs = vec![path.as_ref().to_path_buf()];
+
+        while let Some(dir) = dirs.pop() {
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    errors.push((dir, err.into()));
+                    continue;
+                }
+            };
+
+            // sort entries so that faces are registered in a stable order
+            let mut paths: Vec<_> = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .collect();
+            paths.sort();
+
+            for path in paths {
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if is_font_file(&path) {
+                    if let Err(err) = self.load_file(&path) {
+                        errors.push((path, err));
+                    }
+                }
+            }
+        }
+
+        errors
+    }
+
+    /// Gets a face by its ID.
+    pub fn get(&self, id: FaceId) -> Option<&FontFace> {
+        self.faces.get(id)?.as_ref()
+    }
+
+    /// Mutably gets a face by its ID.
+    pub fn get_mut(&mut self, id: FaceId) -> Option<&mut FontFace> {
+        self.faces.get_mut(id)?.as_mut()
+    }
+
+    /// Iterates over the faces that haven't been removed.
+    fn iter(&self) -> impl Iterator<Item = (FaceId, &FontFace)> {
+        self.faces
+            .iter()
+            .enumerate()
+            .filter_map(|(id, face)| Some((id, face.as_ref()?)))
+    }
+
+    /// Gets a face that text was resolved or itemized with.
+    ///
+    /// Faces can be removed while text laid out with them is still around,
+    /// like when their font resource is evicted, so the face that the
+    /// default font resolves to is used in place of a removed face. Returns
+    /// `None` only if the registry is empty.
+    fn face(&self, id: FaceId) -> Option<&FontFace> {
+        self.get(self.live_face(id)?)
+    }
+
+    /// Mutably gets a face that text was resolved or itemized with, falling
+    /// back like [Self::face].
+    fn face_mut(&mut self, id: FaceId) -> Option<&mut FontFace> {
+        let id = self.live_face(id)?;
+        self.get_mut(id)
+    }
+
+    /// Finds the face to use in place of a face that may have been removed.
+    fn live_face(&self, id: FaceId) -> Option<FaceId> {
+        match self.get(id) {
+            Some(_) => Some(id),
+            None => self.resolve(&Font::default()),
+        }
+    }
+
+    /// Finds the loaded face that best matches a [Font].
+    ///
+    /// Faces loaded from the font's [resource][Font::resource] are preferred,
+    /// then faces in the requested family, then faces in the default family,
+    /// then any face. Within those, the closest style is chosen, and then the
+    /// closest weight following CSS's font matching rules. Returns `None` only
+    /// if the registry is empty.
+    ///
+    /// Faces loaded from uploaded font files are only matched by family for
+    /// the fonts that refer to them.
+    pub fn resolve(&self, font: &Font) -> Option<FaceId> {
+        let from_resource =
+            font.resource.is_some() && self.iter().any(|(_, face)| face.resource == font.resource);
+
+        let in_family = |face: &FontFace, family: &str| {
+            face.resource.is_none() && face.family.eq_ignore_ascii_case(family)
+        };
+
+        let has_family = |family: &str| self.iter().any(|(_, face)| in_family(face, family));
+
+        let family = if from_resource {
+            None
+        } else if has_family(&font.family) {
+            Some(font.family.as_str())
+        } else if has_family(Font::DEFAULT_FAMILY) {
+            Some(Font::DEFAULT_FAMILY)
+        } else {
+            None
+        };
+
+        self.iter()
+            .filter(|(_, face)| match family {
+                _ if from_resource => face.resource == font.resource,
+                Some(family) => in_family(face, family),
+                None => true,
+            })
+            .min_by_key(|(_, face)| {
+                (
+                    style_distance(font.style, face.style),
+                    weight_distance(font.weight, face.weight),
+                )
+            })
+            .map(|(id, _)| id)
+    }
+
+    /// Splits a string into runs that are each drawn with a single face.
+    ///
+    /// Each cluster of characters is assigned the first face out of `primary`
+    /// and then the fallback chain that has glyphs for it. Clusters that no
+    /// face supports fall back to `primary`.
+    pub fn itemize(&mut self, primary: FaceId, text: &str) -> Vec<(FaceId, Range<usize>)> {
+        let fallbacks = self.fallbacks.clone();
+        let mut runs: Vec<(FaceId, Range<usize>)> = Vec::new();
+        let mut chars = text.char_indices().peekable();
+
+        while let Some((start, ch)) = chars.next() {
+            // group marks, joiners, and selectors with their base character
+            let mut end = start + ch.len_utf8();
+            let mut joined = false;
+            while let Some((idx, next)) = chars.peek().copied() {
+                if !joined && !is_cluster_continuation(next) {
+                    break;
+                }
+
+                joined = next == ZERO_WIDTH_JOINER;
+                end = idx + next.len_utf8();
+                chars.next();
+            }
+
+            let face = std::iter::once(primary)
+                .chain(fallbacks.iter().copied())
+                .find(|id| match self.get_mut(*id) {
+                    Some(face) => face.data.has_glyph(ch),
+                    None => false,
+                })
+                .unwrap_or(primary);
+
+            match runs.last_mut() {
+                Some((last, range)) if *last == face => range.end = end,
+                _ => runs.push((face, start..end)),
+            }
+        }
+
+        runs
+    }
+
+    /// Finds the bounds of a positioned glyph's outline, or of its colored
+    /// version if it has one, in pixels.
+    pub fn glyph_bounds(&mut self, glyph: &PositionedGlyph) -> Bounds {
+        let Some(face) = self.get_mut(glyph.face) else {
+            return Bounds::EMPTY;
+        };
+
+        let data = &mut face.data;
+        let ppem = glyph.scale * data.metrics().units_per_em;
+        let (min, max) = match data.color_glyph(glyph.index, ppem) {
+            Some(ColorGlyph::Bitmap(bitmap)) => (bitmap.min, bitmap.max),
+            Some(ColorGlyph::Layers(layers)) => {
+                let mut min = Vec2::INFINITY;
+                let mut max = Vec2::NEG_INFINITY;
+                for layer in layers.iter() {
+                    let outline = data.glyph_outline(layer.index);
+                    min = min.min(outline.min);
+                    max = max.max(outline.max);
+                }
+
+                (min, max)
+            }
+            None => {
+                let outline = data.glyph_outline(glyph.index);
+                (outline.min, outline.max)
+            }
+        };
+
+        if min.x > max.x || min.y > max.y {
+            return Bounds::EMPTY;
+        }
+
+        let transform = glyph.transform();
+        let corners = [min, Vec2::new(min.x, max.y), Vec2::new(max.x, min.y), max];
+
+        corners.into_iter().fold(Bounds::EMPTY, |bounds, corner| {
+            let point = transform.transform_point2(corner);
+            bounds.union(&Bounds {
+                min: point,
+                max: point,
+            })
+        })
+    }
+
+    /// Measures a string as it would be laid out by [Self::layout].
+    pub fn measure(&mut self, font: &Font, text: &str, size: f32) -> TextMetrics {
+        self.layout(font, text, size).metrics()
+    }
+
+    /// Shapes and positions a string with an em square of `size` pixels,
+    /// using the face resolved from `font` and drawing characters missing
+    /// from that face with faces from the fallback chain.
+    ///
+    /// The string is split into runs by script and bidirectional level, and
+    /// each run is shaped with its own script and direction.
+    pub fn layout(&mut self, font: &Font, text: &str, size: f32) -> TextLayout {
+        self.layout_styled(text, &[StyledRange::plain(text, font, size)])
+    }
+
+    /// Lays out a string made of styled ranges, such as the spans of rich
+    /// text, on a single line. The ranges must cover the string in order.
+    ///
+    /// Adjacent ranges with the same font and size are shaped together, so
+    /// that kerning and ligatures work across them.
+    pub fn layout_styled(&mut self, text: &str, styles: &[StyledRange]) -> TextLayout {
+        self.layout_line(text, styles, false)
+    }
+
+    /// Lays out a line of styled text, either horizontally or vertically.
+    ///
+    /// Vertical lines are laid out turned a quarter turn counter-clockwise,
+    /// so that they run left to right along a baseline through their middle
+    /// like horizontal lines do. Their glyphs that stand upright are turned
+    /// counter-clockwise within the line, and the others lie along it. Turning
+    /// the layout clockwise afterwards stands it up.
+    pub(crate) fn layout_line(
+        &mut self,
+        text: &str,
+        styles: &[StyledRange],
+        vertical: bool,
+    ) -> TextLayout {
+        let mut layout = TextLayout {
+            glyphs: Vec::new(),
+            advance: Vec2::ZERO,
+            ascent: 0.0,
+            descent: 0.0,
+            line_height: 0.0,
+            decorations: Vec::new(),
+            ink: Bounds::EMPTY,
+        };
+
+        // the line is as tall as the primary faces of its styles, even where
+        // they aren't used
+        let mut used_faces = Vec::new();
+        let mut sized_styles: Vec<_> = styles.iter().filter(|s| !s.range.is_empty()).collect();
+        if sized_styles.is_empty() {
+            sized_styles.extend(styles.first());
+        }
+
+        for style in sized_styles {
+            if let Some(face) = self.resolve(style.font) {
+                used_faces.push((face, style.size));
+            }
+        }
+
+        let mut pen = Vec2::ZERO;
+        for run in itemize::visual_runs(text) {
+            let mut pieces = shaping_groups(styles, run.range.clone());
+
+            if run.direction == TextDirection::RightToLeft {
+                pieces.reverse();
+            }
+
+            for (range, font, size) in pieces {
+                let Some(primary) = self.resolve(font) else {
+                    continue;
+                };
+
+                let mut face_runs = self.itemize(primary, &text[range.clone()]);
+
+                if run.direction == TextDirection::RightToLeft {
+                    face_runs.reverse();
+                }
+
+                for (face, face_range) in face_runs {
+                    if !used_faces.contains(&(face, size)) {
+                        used_faces.push((face, size));
+                    }
+
+                    let start = range.start + face_range.start;
+                    let end = range.start + face_range.end;
+                    let Some(FontFace { data, .. }) = self.face_mut(face) else {
+                        continue;
+                    };
+
+                    let scale = size / data.metrics().units_per_em;
+                    let glyphs = data.shape(
+                        &text[start..end],
+                        run.script,
+                        run.direction,
+                        vertical,
+                        &font.features,
+                    );
+
+                    for glyph in glyphs.iter() {
+                        let cluster = start + glyph.cluster;
+                        let span = styles.partition_point(|style| style.range.end <= cluster);
+                        let mut offset = Vec2::new(glyph.xoff as f32, -glyph.yoff as f32);
+                        let mut rotation = GlyphRotation::Upright;
+
+                        // vertical advances run along the line like horizontal ones
+                        let advance = if vertical {
+                            Vec2::new(glyph.vert_advance as f32, 0.0)
+                        } else {
+                            Vec2::new(glyph.hori_advance as f32, 0.0)
+                        };
+
+                        if glyph.upright {
+                            let origin = self.upright_origin(face, glyph.index, advance.x);
+                            offset = origin + Vec2::new(offset.y, -offset.x);
+                            rotation = GlyphRotation::CounterClockwise;
+                        } else if vertical {
+                            offset.y += self.vertical_shift(face);
+                        }
+
+                        layout.glyphs.push(PositionedGlyph {
+                            face,
+                            index: glyph.index,
+                            span: span.min(styles.len() - 1),
+                            cluster,
+                            position: pen + offset * scale,
+                            advance: advance * scale,
+                            scale,
+                            rotation,
+                        });
+
+                        pen += advance * scale;
+                    }
+                }
+            }
+        }
+
+        for (face, size) in used_faces {
+            let Some(face) = self.face(face) else {
+                continue;
+            };
+
+            let metrics = face.data.metrics();
+            let scale = size / metrics.units_per_em;
+            let line_height = metrics.ascent + metrics.descent + metrics.line_gap;
+
+            // vertical lines are centered on their baseline
+            let (ascent, descent) = if vertical {
+                let half = (metrics.ascent + metrics.descent) / 2.0;
+                (half, half)
+            } else {
+                (metrics.ascent, metrics.descent)
+            };
+
+            layout.ascent = layout.ascent.max(ascent * scale);
+            layout.descent = layout.descent.max(descent * scale);
+            layout.line_height = layout.line_height.max(line_height * scale);
+        }
+
+        for index in 0..layout.glyphs.len() {
+            let bounds = self.glyph_bounds(&layout.glyphs[index]);
+            layout.ink = layout.ink.union(&bounds);
+        }
+
+        layout.advance = pen;
+        layout.decorations = self.decorate(&layout.glyphs, styles, 0.0, vertical);
+
+        for decoration in layout.decorations.iter() {
+            layout.ink = layout.ink.union(&decoration.bounds);
+        }
+
+        layout
+    }
+
+    /// Finds the decorations of a line of glyphs laid out from styled
+    /// ranges, with the line's baseline at `baseline`.
+    ///
+    /// Vertical lines must be laid out turned counter-clockwise, as they are
+    /// by [Self::layout_line].
+    pub fn decorate(
+        &mut self,
+        glyphs: &[PositionedGlyph],
+        styles: &[StyledRange],
+        baseline: f32,
+        vertical: bool,
+    ) -> Vec<Decoration> {
+        let mut backgrounds = Vec::new();
+        let mut lines = Vec::new();
+
+        for group in glyphs.chunk_by(|a, b| a.span == b.span) {
+            let span = group[0].span;
+            let style = &styles[span];
+            if !style.is_decorated() {
+                continue;
+            }
+
+            let Some(face) = self.resolve(style.font) else {
+                continue;
+            };
+
+            let mut min_x = f32::INFINITY;
+            let mut max_x = f32::NEG_INFINITY;
+            for glyph in group.iter() {
+                let mut pen = glyph.position.x;
+                if glyph.rotation == GlyphRotation::CounterClockwise {
+                    let advance = glyph.advance.x / glyph.scale;
+                    pen -= self.upright_origin(glyph.face, glyph.index, advance).x * glyph.scale;
+                }
+
+                min_x = min_x.min(pen);
+                max_x = max_x.max(pen + glyph.advance.x);
+            }
+
+            let mut baseline = baseline;
+            let shift = self.vertical_shift(face);
+            let Some(FontFace { data, .. }) = self.face(face) else {
+                continue;
+            };
+
+            let metrics = data.metrics();
+            let scale = style.size / metrics.units_per_em;
+            if vertical {
+                baseline += shift * scale;
+            }
+
+            let line = |kind, position: f32, thickness: f32| {
+                let top = baseline - position * scale;
+                Decoration {
+                    span,
+                    kind,
+                    bounds: Bounds {
+                        min: Vec2::new(min_x, top),
+                        max: Vec2::new(max_x, top + thickness.max(1.0 / scale) * scale),
+                    },
+                }
+            };
+
+            if style.background {
+                backgrounds.push(line(
+                    DecorationKind::Background,
+                    metrics.ascent,
+                    metrics.ascent + metrics.descent,
+                ));
+            }
+
+            if style.underline || style.strikethrough {
+                let decoration = data.decoration_metrics();
+
+                if style.underline {
+                    lines.push(line(
+                        DecorationKind::Underline,
+                        decoration.underline_position,
+                        decoration.underline_thickness,
+                    ));
+                }
+
+                if style.strikethrough {
+                    lines.push(line(
+                        DecorationKind::Strikethrough,
+                        decoration.strikeout_position,
+                        decoration.strikeout_thickness,
+                    ));
+                }
+            }
+        }
+
+        backgrounds.extend(lines);
+        backgrounds
+    }
+
+    /// Finds the origin of a glyph that stands upright in a vertical line,
+    /// relative to its pen position on the line as laid out by
+    /// [Self::layout_line], in font units.
+    ///
+    /// The glyph is centered across the line, and the face's ascent and
+    /// descent are centered in its vertical advance.
+    fn upright_origin(&mut self, face: FaceId, index: u16, vert_advance: f32) -> Vec2 {
+        let Some(FontFace { data, .. }) = self.face_mut(face) else {
+            return Vec2::ZERO;
+        };
+
+        let metrics = data.metrics();
+        let width = data.horizontal_advance(index);
+        let along = (vert_advance - metrics.ascent - metrics.descent) / 2.0 + metrics.ascent;
+        Vec2::new(along, width / 2.0)
+    }
+
+    /// Finds how far to move the baseline of a face's glyphs that lie along a
+    /// vertical line to center them on the line's baseline, in font units.
+    fn vertical_shift(&self, face: FaceId) -> f32 {
+        let Some(face) = self.face(face) else {
+            return 0.0;
+        };
+
+        let metrics = face.data.metrics();
+        (metrics.ascent - metrics.descent) / 2.0
+    }
+}
+
+/// Finds the pieces of a range of text that can be shaped together, by
+/// joining adjacent styled ranges with the same font and size.
+fn shaping_groups<'a>(
+    styles: &[StyledRange<'a>],
+    within: Range<usize>,
+) -> Vec<(Range<usize>, &'a Font, f32)> {
+    let mut groups: Vec<(Range<usize>, &'a Font, f32)> = Vec::new();
+
+    for style in styles.iter() {
+        let start = style.range.start.max(within.start);
+        let end = style.range.end.min(within.end);
+        if start >= end {
+            continue;
+        }
+
+        match groups.last_mut() {
+            Some((range, font, size))
+                if range.end == start && *font == style.font && *size == style.size =>
+            {
+                range.end = end;
+            }
+            _ => groups.push((start..end, style.font, style.size)),
+        }
+    }
+
+    groups
+}
+
+const ZERO_WIDTH_JOINER: char = '\u{200d}';
+
+/// Tests whether a character continues the cluster of the character before
+/// it, and so must be drawn with the same face.
+fn is_cluster_continuation(ch: char) -> bool {
+    matches!(ch,
+        // combining marks
+        '\u{0300}'..='\u{036f}'
+        | '\u{0483}'..='\u{0489}'
+        | '\u{0591}'..='\u{05c7}'
+        | '\u{0610}'..='\u{061a}'
+        | '\u{064b}'..='\u{065f}'
+        | '\u{0670}'
+        | '\u{06d6}'..='\u{06ed}'
+        | '\u{0900}'..='\u{0903}'
+        | '\u{093a}'..='\u{094f}'
+        | '\u{1ab0}'..='\u{1aff}'
+        | '\u{1dc0}'..='\u{1dff}'
+        | '\u{20d0}'..='\u{20ff}'
+        | '\u{302a}'..='\u{302f}'
+        | '\u{3099}'..='\u{309a}'
+        | '\u{fe20}'..='\u{fe2f}'
+        // joiners
+        | '\u{200c}'..='\u{200d}'
+        // variation selectors
+        | '\u{fe00}'..='\u{fe0f}'
+        | '\u{e0100}'..='\u{e01ef}'
+        // emoji skin tone modifiers and tags
+        | '\u{1f3fb}'..='\u{1f3ff}'
+        | '\u{e0020}'..='\u{e007f}'
+    )
+}
+
+/// Tests whether a path has the extension of a font file.
+fn is_font_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| {
+            FONT_EXTENSIONS
+                .iter()
+                .any(|font_ext| ext.eq_ignore_ascii_case(font_ext))
+        })
+        .unwrap_or(false)
+}
+
+/// Ranks how well an available style substitutes for a desired one.
+fn style_distance(desired: FontStyle, available: FontStyle) -> u8 {
+    use FontStyle::*;
+    match (desired, available) {
+        (desired, available) if desired == available => 0,
+        (Italic, Oblique) | (Oblique, Italic) | (Normal, Oblique) => 1,
+        _ => 2,
+    }
+}
+
+/// Ranks how well an available weight substitutes for a desired one.
+///
+/// Follows the CSS font matching algorithm: weights between 400 and 500
+/// first look up to 500, light weights prefer lighter faces, and bold weights
+/// prefer bolder faces.
+fn weight_distance(desired: FontWeight, available: FontWeight) -> (u8, u16) {
+    let (desired, available) = (desired.0, available.0);
+    let lighter = (1, desired.saturating_sub(available));
+    let heavier = (2, available.saturating_sub(desired));
+
+    if desired == available {
+        (0, 0)
+    } else if (400..=500).contains(&desired) {
+        if available > desired && available <= 500 {
+            (0, available - desired)
+        } else if available < desired {
+            lighter
+        } else {
+            heavier
+        }
+    } else if desired < 400 {
+        if available < desired {
+            lighter
+        } else {
+            heavier
+        }
+    } else if available > desired {
+        (1, available - desired)
+    } else {
+        (2, desired - available)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::font::OutlineCommand;
+    use willow_protocol::{FontFeature, TextSpan};
+
+    /// A directory only used by one test, which is removed when dropped,
+    /// even if the test fails.
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "willow-text-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+
+            let path = std::env::temp_dir().join(name);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn bold_prefers_heavier() {
+        let weights = [FontWeight::LIGHT, FontWeight::NORMAL, FontWeight::BLACK];
+        let best = weights
+            .iter()
+            .min_by_key(|weight| weight_distance(FontWeight::BOLD, **weight));
+        assert_eq!(best, Some(&FontWeight::BLACK));
+    }
+
+    #[test]
+    fn normal_prefers_medium_then_lighter() {
+        let weights = [FontWeight::LIGHT, FontWeight::MEDIUM, FontWeight::BOLD];
+        let best = weights
+            .iter()
+            .min_by_key(|weight| weight_distance(FontWeight::NORMAL, **weight));
+        assert_eq!(best, Some(&FontWeight::MEDIUM));
+
+        let weights = [FontWeight::LIGHT, FontWeight::BOLD];
+        let best = weights
+            .iter()
+            .min_by_key(|weight| weight_distance(FontWeight::NORMAL, **weight));
+        assert_eq!(best, Some(&FontWeight::LIGHT));
+    }
+
+    #[test]
+    fn resolve_default_faces() {
+        let registry = FontRegistry::default();
+        let font = Font::default().with_weight(FontWeight::BLACK);
+        let face = registry.get(registry.resolve(&font).unwrap()).unwrap();
+        assert_eq!(face.weight, FontWeight::BOLD);
+        assert_eq!(face.style, FontStyle::Normal);
+
+        let font = Font {
+            family: "Missing Family".to_string(),
+            weight: FontWeight::NORMAL,
+            style: FontStyle::Oblique,
+            features: Vec::new(),
+            resource: None,
+        };
+
+        let face = registry.get(registry.resolve(&font).unwrap()).unwrap();
+        assert_eq!(face.family, Font::DEFAULT_FAMILY);
+        assert_eq!(face.weight, FontWeight::NORMAL);
+        assert_eq!(face.style, FontStyle::Italic);
+    }
+
+    #[test]
+    fn resource_faces_are_only_matched_by_their_fonts() {
+        let mut registry = FontRegistry::default();
+        let resource = ResourceHash([1; 32]);
+        let ids = registry
+            .load_resource(resource, notosans::BOLD_TTF.to_vec())
+            .unwrap();
+
+        // the uploaded face doesn't compete with the built-in regular face
+        let regular = registry.resolve(&Font::default()).unwrap();
+        assert_ne!(Some(&regular), ids.first());
+
+        let font = Font::default().with_resource(resource);
+        assert_eq!(registry.resolve(&font), ids.first().copied());
+
+        registry.remove_resource(resource);
+        assert!(registry.get(ids[0]).is_none());
+        assert_eq!(registry.resolve(&font), Some(regular));
+    }
+
+    #[test]
+    fn removed_faces_fall_back_to_the_default_font() {
+        let mut registry = FontRegistry::default();
+        let resource = ResourceHash([1; 32]);
+        let ids = registry
+            .load_resource(resource, notosans::BOLD_TTF.to_vec())
+            .unwrap();
+
+        let font = Font::default().with_resource(resource);
+        let mut layout = registry.layout(&font, "Willow", 16.0);
+        assert!(layout.glyphs.iter().all(|glyph| glyph.face == ids[0]));
+        registry.remove_resource(resource);
+
+        let regular = registry.resolve(&Font::default()).unwrap();
+        assert!(std::ptr::eq(
+            registry.face(ids[0]).unwrap(),
+            registry.get(regular).unwrap()
+        ));
+
+        // upright glyphs look their face up again to be decorated
+        for glyph in layout.glyphs.iter_mut() {
+            glyph.rotation = GlyphRotation::CounterClockwise;
+        }
+
+        let mut style = StyledRange::plain("Willow", &font, 16.0);
+        style.underline = true;
+        let decorations = registry.decorate(&layout.glyphs, &[style], 0.0, true);
+        assert_eq!(decorations.len(), 1);
+    }
+
+    #[test]
+    fn load_dir_skips_bad_files() {
+        let dir = TempDir::new();
+        std::fs::write(dir.0.join("bad.ttf"), b"not a font").unwrap();
+        std::fs::write(dir.0.join("good.ttf"), notosans::BOLD_TTF).unwrap();
+        std::fs::write(dir.0.join("readme.txt"), b"not a font either").unwrap();
+
+        let mut registry = FontRegistry::new();
+        let errors = registry.load_dir(&dir.0);
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, dir.0.join("bad.ttf"));
+
+        let face = registry.get(0).unwrap();
+        assert_eq!(face.family, Font::DEFAULT_FAMILY);
+        assert_eq!(face.weight, FontWeight::BOLD);
+        assert!(registry.get(1).is_none());
+    }
+
+    #[test]
+    fn itemize_keeps_marks_with_base() {
+        let mut registry = FontRegistry::default();
+        let primary = registry.resolve(&Font::default()).unwrap();
+        let text = "cafe\u{301}";
+        let runs = registry.itemize(primary, text);
+        assert_eq!(runs, vec![(primary, 0..text.len())]);
+    }
+
+    #[test]
+    fn spans_with_one_font_shape_together() {
+        let mut registry = FontRegistry::default();
+        let font = Font::default();
+        let whole = registry.layout(&font, "AVAV", 20.0);
+
+        let spans = [
+            TextSpan::new("AV", font.clone(), 20.0),
+            TextSpan::new("AV", font.clone(), 20.0).with_underline(),
+        ];
+
+        let (text, styles) = StyledRange::from_spans(&spans);
+        let split = registry.layout_styled(&text, &styles);
+        assert_eq!(split.advance, whole.advance);
+
+        let spans: Vec<_> = split.glyphs.iter().map(|glyph| glyph.span).collect();
+        assert_eq!(spans, [0, 0, 1, 1]);
+        assert_eq!(split.decorations.len(), 1);
+        assert_eq!(split.decorations[0].span, 1);
+        assert_eq!(
+            split.decorations[0].bounds.min.x,
+            split.glyphs[2].position.x
+        );
+    }
+
+    #[test]
+    fn kerning_can_be_turned_off() {
+        let mut registry = FontRegistry::default();
+        let font = Font::default();
+        let unkerned = font
+            .clone()
+            .with_feature(FontFeature::off(FontFeature::KERNING));
+
+        let kerned = registry.layout(&font, "AV", 10.0).advance;
+        let loose = registry.layout(&unkerned, "AV", 10.0).advance;
+        assert!(kerned.x < loose.x);
+    }
+
+    #[test]
+    fn replacement_box_fits_glyph_advance() {
+        let mut registry = FontRegistry::default();
+        let face = registry.resolve(&Font::default()).unwrap();
+        let data = &mut registry.get_mut(face).unwrap().data;
+        let index = data.glyph_index('m');
+        let outline = data.replacement_outline(index);
+        let closes = outline.commands.iter();
+        let closes = closes.filter(|command| **command == OutlineCommand::Close);
+        assert_eq!(closes.count(), 2);
+        assert!(outline.min.x > 0.0 && outline.max.x < data.horizontal_advance(index));
+    }
+}